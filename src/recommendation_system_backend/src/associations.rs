@@ -0,0 +1,477 @@
+use crate::recommendations::{load_ratings, TOP_N};
+use crate::settings;
+use crate::{Error, Item, Memory, ITEM_STORAGE, MEMORY_MANAGER, RECOMMENDATION_SYSTEM_STORAGE};
+use candid::{CandidType, Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+
+// every recommendation system is mined again every hour
+const MINING_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// candidate checks against a transaction per timer call, so a mining run is spread over many messages
+const CHECKS_PER_CHUNK: usize = 100_000;
+
+// largest itemset mined
+const MAX_ITEMSET_SIZE: usize = 3;
+
+const DEFAULT_MIN_SUPPORT: f64 = 0.01;
+const DEFAULT_MIN_CONFIDENCE: f64 = 0.2;
+
+// itemsets seen by a single user are noise whatever the support threshold
+const MIN_SUPPORT_COUNT: u64 = 2;
+
+// rules kept per item and recommendation system, best confidence first
+const MAX_RULES_PER_ITEM: usize = 20;
+
+// thresholds of the association rule miner of a recommendation system
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug, Default)]
+pub(crate) struct AssociationSettings {
+    // share of the users whose liked items contain the itemset, 0.01 by default
+    pub(crate) min_support: Option<f64>,
+    // share of the users liking the antecedent who also like the consequent, 0.2 by default
+    pub(crate) min_confidence: Option<f64>,
+}
+
+impl AssociationSettings {
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        if self.min_support.is_some_and(|support| !(support > 0.0 && support <= 1.0)) {
+            return Err(Error::InvalidInput { msg: "min_support must be above 0 and at most 1".to_string() });
+        }
+        if self.min_confidence.is_some_and(|confidence| !(0.0..=1.0).contains(&confidence)) {
+            return Err(Error::InvalidInput { msg: "min_confidence must be between 0 and 1".to_string() });
+        }
+        Ok(())
+    }
+}
+
+// users who like every item of the antecedent tend to like the consequent too
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub(crate) struct AssociationRule {
+    antecedent: Vec<u64>,
+    consequent: u64,
+    support: f64,
+    confidence: f64,
+    // confidence over the support of the consequent, above 1 means positively associated
+    lift: f64,
+}
+
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug, Default)]
+struct ItemRules {
+    rules: Vec<AssociationRule>,
+}
+
+impl Storable for ItemRules {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ItemRules {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// key of the rules whose antecedent contains an item, item first so an item's rules of every system are adjacent
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+struct RuleKey {
+    item_id: u64,
+    recommendation_system_id: u64,
+}
+
+impl Storable for RuleKey {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        let mut bytes = self.item_id.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&self.recommendation_system_id.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let (item_id, recommendation_system_id) = bytes.split_at(8);
+        RuleKey {
+            item_id: u64::from_be_bytes(item_id.try_into().unwrap()),
+            recommendation_system_id: u64::from_be_bytes(recommendation_system_id.try_into().unwrap()),
+        }
+    }
+}
+
+impl BoundedStorable for RuleKey {
+    const MAX_SIZE: u32 = 16;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+// an item frequently liked together with another one
+#[derive(CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct FrequentlyBoughtTogether {
+    recommendation_system_id: u64,
+    item: Item,
+    support: f64,
+    confidence: f64,
+    lift: f64,
+}
+
+// Apriori over the sets of items each user liked, one level of itemsets at a time and about
+// CHECKS_PER_CHUNK candidate checks per step
+struct Miner {
+    transactions: Vec<HashSet<u64>>,
+    min_count: u64,
+    // every frequent itemset found so far, sorted, with the number of transactions containing it
+    frequent: HashMap<Vec<u64>, u64>,
+    // candidates of the level being counted
+    candidates: Vec<Vec<u64>>,
+    counts: Vec<u64>,
+    offset: usize,
+}
+
+impl Miner {
+    fn new(transactions: Vec<HashSet<u64>>, min_support: f64) -> Self {
+        let min_count = ((min_support * transactions.len() as f64).ceil() as u64).max(MIN_SUPPORT_COUNT);
+        let mut items: Vec<u64> = transactions.iter().flatten().copied().collect::<HashSet<u64>>().into_iter().collect();
+        items.sort();
+        let candidates: Vec<Vec<u64>> = items.into_iter().map(|item_id| vec![item_id]).collect();
+        let counts = vec![0; candidates.len()];
+        Miner { transactions, min_count, frequent: HashMap::new(), candidates, counts, offset: 0 }
+    }
+
+    // count the next chunk of transactions, at least one, returns true once mining is done
+    fn step(&mut self) -> bool {
+        let chunk = (CHECKS_PER_CHUNK / self.candidates.len().max(1)).max(1);
+        let end = (self.offset + chunk).min(self.transactions.len());
+        for transaction in &self.transactions[self.offset..end] {
+            for (candidate, count) in self.candidates.iter().zip(self.counts.iter_mut()) {
+                if candidate.iter().all(|item_id| transaction.contains(item_id)) {
+                    *count += 1;
+                }
+            }
+        }
+        self.offset = end;
+        if self.offset < self.transactions.len() {
+            return false;
+        }
+
+        let level: Vec<Vec<u64>> = self
+            .candidates
+            .drain(..)
+            .zip(self.counts.drain(..))
+            .filter(|(_, count)| *count >= self.min_count)
+            .map(|(itemset, count)| {
+                self.frequent.insert(itemset.clone(), count);
+                itemset
+            })
+            .collect();
+        if level.first().is_none_or(|itemset| itemset.len() >= MAX_ITEMSET_SIZE) {
+            return true;
+        }
+        self.candidates = self.join(&level);
+        self.counts = vec![0; self.candidates.len()];
+        self.offset = 0;
+        self.candidates.is_empty()
+    }
+
+    // candidates of the next level: unions of two itemsets sharing all but their last item,
+    // whose every subset is frequent
+    fn join(&self, level: &[Vec<u64>]) -> Vec<Vec<u64>> {
+        let mut candidates = vec![];
+        for (index, a) in level.iter().enumerate() {
+            for b in &level[index + 1..] {
+                let prefix = a.len() - 1;
+                if a[..prefix] != b[..prefix] {
+                    continue;
+                }
+                let mut candidate = a.clone();
+                candidate.push(b[prefix]);
+                candidate.sort();
+                let all_frequent = (0..candidate.len()).all(|skip| {
+                    let subset: Vec<u64> =
+                        candidate.iter().enumerate().filter(|(i, _)| *i != skip).map(|(_, id)| *id).collect();
+                    self.frequent.contains_key(&subset)
+                });
+                if all_frequent {
+                    candidates.push(candidate);
+                }
+            }
+        }
+        candidates
+    }
+
+    // rules with a single consequent from every frequent itemset of two items or more
+    fn rules(&self, min_confidence: f64) -> Vec<AssociationRule> {
+        let total = self.transactions.len() as f64;
+        let mut rules = vec![];
+        for (itemset, &count) in self.frequent.iter().filter(|(itemset, _)| itemset.len() > 1) {
+            for &consequent in itemset {
+                let antecedent: Vec<u64> = itemset.iter().copied().filter(|&id| id != consequent).collect();
+                let confidence = count as f64 / self.frequent[&antecedent] as f64;
+                if confidence >= min_confidence {
+                    let consequent_support = self.frequent[&vec![consequent]] as f64 / total;
+                    rules.push(AssociationRule {
+                        antecedent,
+                        consequent,
+                        support: count as f64 / total,
+                        confidence,
+                        lift: confidence / consequent_support,
+                    });
+                }
+            }
+        }
+        rules.sort_by(|a, b| {
+            b.confidence
+                .total_cmp(&a.confidence)
+                .then(b.lift.total_cmp(&a.lift))
+                .then(a.antecedent.cmp(&b.antecedent))
+                .then(a.consequent.cmp(&b.consequent))
+        });
+        rules
+    }
+}
+
+// recommendation systems left to mine and the one being mined
+#[derive(Default)]
+struct MiningRun {
+    pending: Vec<u64>,
+    current: Option<(u64, Miner, f64)>,
+}
+
+thread_local! {
+    static RULES: RefCell<StableBTreeMap<RuleKey, ItemRules, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(33))))
+    );
+
+    // the run in progress, restarted by the next interval after an upgrade
+    static RUN: RefCell<Option<MiningRun>> = const { RefCell::new(None) };
+}
+
+fn is_active(recommendation_system_id: u64) -> bool {
+    RECOMMENDATION_SYSTEM_STORAGE
+        .with(|service| service.borrow().get(&recommendation_system_id))
+        .is_some_and(|record| record.deleted_at.is_none())
+}
+
+// the items each user of a recommendation system liked, rated at least at the user's mean rating
+fn transactions(recommendation_system_id: u64) -> Vec<HashSet<u64>> {
+    let Some(recommendation_system) = RECOMMENDATION_SYSTEM_STORAGE
+        .with(|service| service.borrow().get(&recommendation_system_id))
+        .filter(|record| record.deleted_at.is_none())
+    else {
+        return vec![];
+    };
+    load_ratings(&recommendation_system)
+        .by_user
+        .into_values()
+        .map(|ratings| {
+            let mean = ratings.values().sum::<f64>() / ratings.len().max(1) as f64;
+            ratings.into_iter().filter(|(_, rating)| *rating >= mean).map(|(item_id, _)| item_id).collect()
+        })
+        .collect()
+}
+
+// drop the rules of recommendation systems deleted or purged since they were mined
+fn remove_inactive_rules() {
+    let stale: Vec<RuleKey> = RULES.with(|m| {
+        m.borrow().iter().map(|(key, _)| key).filter(|key| !is_active(key.recommendation_system_id)).collect()
+    });
+    RULES.with(|m| {
+        let mut m = m.borrow_mut();
+        for key in stale {
+            m.remove(&key);
+        }
+    });
+}
+
+// replace the stored rules of a recommendation system
+fn store(recommendation_system_id: u64, rules: Vec<AssociationRule>) {
+    let stale: Vec<RuleKey> = RULES.with(|m| {
+        m.borrow().iter().map(|(key, _)| key).filter(|key| key.recommendation_system_id == recommendation_system_id).collect()
+    });
+    let mut by_item: HashMap<u64, ItemRules> = HashMap::new();
+    for rule in rules {
+        for &item_id in &rule.antecedent {
+            let item_rules = by_item.entry(item_id).or_default();
+            if item_rules.rules.len() < MAX_RULES_PER_ITEM {
+                item_rules.rules.push(rule.clone());
+            }
+        }
+    }
+    RULES.with(|m| {
+        let mut m = m.borrow_mut();
+        for key in stale {
+            m.remove(&key);
+        }
+        for (item_id, item_rules) in by_item {
+            m.insert(RuleKey { item_id, recommendation_system_id }, item_rules);
+        }
+    });
+}
+
+// mine the next chunk of the run in progress and schedule the following one
+fn mine_chunk() {
+    let scheduled = RUN.with(|run| {
+        let mut run = run.borrow_mut();
+        let Some(state) = run.as_mut() else {
+            return false;
+        };
+        match state.current.as_mut() {
+            Some((recommendation_system_id, miner, min_confidence)) => {
+                if miner.step() {
+                    store(*recommendation_system_id, miner.rules(*min_confidence));
+                    state.current = None;
+                }
+            }
+            None => match state.pending.pop() {
+                Some(recommendation_system_id) => {
+                    let settings = settings::settings_of(recommendation_system_id).associations.unwrap_or_default();
+                    let miner = Miner::new(
+                        transactions(recommendation_system_id),
+                        settings.min_support.unwrap_or(DEFAULT_MIN_SUPPORT),
+                    );
+                    let min_confidence = settings.min_confidence.unwrap_or(DEFAULT_MIN_CONFIDENCE);
+                    state.current = Some((recommendation_system_id, miner, min_confidence));
+                }
+                None => {
+                    *run = None;
+                    return false;
+                }
+            },
+        }
+        true
+    });
+    if scheduled {
+        ic_cdk_timers::set_timer(Duration::ZERO, mine_chunk);
+    }
+}
+
+// start mining every recommendation system unless a run is in progress
+fn start_run() {
+    let started = RUN.with(|run| {
+        if run.borrow().is_some() {
+            return false;
+        }
+        remove_inactive_rules();
+        let pending = RECOMMENDATION_SYSTEM_STORAGE.with(|service| {
+            service.borrow().iter().filter(|(_, record)| record.deleted_at.is_none()).map(|(id, _)| id).collect()
+        });
+        *run.borrow_mut() = Some(MiningRun { pending, current: None });
+        true
+    });
+    if started {
+        mine_chunk();
+    }
+}
+
+pub(crate) fn start_mining_timer() {
+    ic_cdk_timers::set_timer(Duration::ZERO, start_run);
+    ic_cdk_timers::set_timer_interval(MINING_INTERVAL, start_run);
+}
+
+fn rules_of(item_id: u64) -> Vec<(u64, AssociationRule)> {
+    let start = RuleKey { item_id, recommendation_system_id: 0 };
+    let end = RuleKey { item_id, recommendation_system_id: u64::MAX };
+    RULES.with(|m| {
+        m.borrow()
+            .range(start..=end)
+            .flat_map(|(key, item_rules)| {
+                item_rules.rules.into_iter().map(move |rule| (key.recommendation_system_id, rule))
+            })
+            .collect()
+    })
+}
+
+// function to get the mined rules of a recommendation system whose antecedent contains an item
+#[ic_cdk::query]
+fn get_association_rules(recommendation_system_id: u64, item_id: u64) -> Vec<AssociationRule> {
+    if !is_active(recommendation_system_id) {
+        return vec![];
+    }
+    RULES.with(|m| m.borrow().get(&RuleKey { item_id, recommendation_system_id })).unwrap_or_default().rules
+}
+
+// function to get the k items most often liked together with an item, by confidence of the rule
+// item -> other item in any recommendation system
+#[ic_cdk::query]
+fn get_frequently_bought_together(item_id: u64, k: u64) -> Vec<FrequentlyBoughtTogether> {
+    let mut pairs: Vec<(u64, AssociationRule)> = rules_of(item_id)
+        .into_iter()
+        .filter(|(recommendation_system_id, rule)| rule.antecedent == [item_id] && is_active(*recommendation_system_id))
+        .collect();
+    pairs.sort_by(|(_, a), (_, b)| b.confidence.total_cmp(&a.confidence).then(b.lift.total_cmp(&a.lift)));
+
+    let mut seen = HashSet::new();
+    pairs
+        .into_iter()
+        .filter(|(_, rule)| seen.insert(rule.consequent))
+        .filter_map(|(recommendation_system_id, rule)| {
+            ITEM_STORAGE.with(|m| m.borrow().get(&rule.consequent)).filter(|item| item.deleted_at.is_none()).map(|item| {
+                FrequentlyBoughtTogether {
+                    recommendation_system_id,
+                    item,
+                    support: rule.support,
+                    confidence: rule.confidence,
+                    lift: rule.lift,
+                }
+            })
+        })
+        .take(k.clamp(1, TOP_N as u64) as usize)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RecommendationSystem;
+
+    fn mine(transactions: &[&[u64]], min_support: f64, min_confidence: f64) -> Vec<AssociationRule> {
+        let mut miner =
+            Miner::new(transactions.iter().map(|transaction| transaction.iter().copied().collect()).collect(), min_support);
+        while !miner.step() {}
+        miner.rules(min_confidence)
+    }
+
+    #[test]
+    fn apriori_finds_rules_with_support_confidence_and_lift() {
+        let rules = mine(&[&[1, 2, 3], &[1, 2, 3], &[1, 2], &[1, 4], &[4, 5]], 0.4, 0.6);
+
+        let rule = rules.iter().find(|rule| rule.antecedent == [2] && rule.consequent == 1).unwrap();
+        assert_eq!((rule.support, rule.confidence), (0.6, 1.0));
+        assert!((rule.lift - 1.25).abs() < 1e-12);
+        let rule = rules.iter().find(|rule| rule.antecedent == [1, 2] && rule.consequent == 3).unwrap();
+        assert!((rule.confidence - 2.0 / 3.0).abs() < 1e-12);
+        // {1, 4} is seen once only and 1 -> 3 has a confidence of 0.5
+        assert!(rules.iter().all(|rule| rule.consequent != 4 && !(rule.antecedent == [1] && rule.consequent == 3)));
+    }
+
+    #[test]
+    fn stored_rules_replace_previous_ones() {
+        let rule = |antecedent: Vec<u64>, consequent| AssociationRule {
+            antecedent,
+            consequent,
+            support: 0.5,
+            confidence: 0.5,
+            lift: 1.0,
+        };
+        for id in [1, 2] {
+            RECOMMENDATION_SYSTEM_STORAGE.with(|m| m.borrow_mut().insert(id, RecommendationSystem { id, ..Default::default() }));
+        }
+        store(1, vec![rule(vec![1], 2), rule(vec![1, 3], 2)]);
+        store(2, vec![rule(vec![1], 4)]);
+        assert_eq!(rules_of(1).len(), 3);
+        assert_eq!(get_association_rules(1, 3).len(), 1);
+
+        store(1, vec![rule(vec![5], 6)]);
+        assert_eq!(rules_of(1), vec![(2, rule(vec![1], 4))]);
+        assert!(get_association_rules(1, 3).is_empty());
+
+        // rules of a deleted system are hidden, then dropped by the next run
+        RECOMMENDATION_SYSTEM_STORAGE
+            .with(|m| m.borrow_mut().insert(2, RecommendationSystem { id: 2, deleted_at: Some(1), ..Default::default() }));
+        assert!(get_association_rules(2, 1).is_empty());
+        remove_inactive_rules();
+        assert_eq!(rules_of(1), vec![]);
+        assert_eq!(rules_of(5).len(), 1);
+    }
+}
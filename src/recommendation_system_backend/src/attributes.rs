@@ -0,0 +1,483 @@
+use crate::search::SearchFilters;
+use crate::{audit, ensure_controller, item_is_active, Error, Memory, MEMORY_MANAGER, RECOMMENDATION_SYSTEM_STORAGE};
+use candid::{CandidType, Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::collections::HashSet;
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_TAG_LENGTH: usize = 64;
+const MAX_TAGS: usize = 50;
+const MAX_ATTRIBUTES: usize = 100;
+const MAX_ATTRIBUTE_NAME_LENGTH: usize = 64;
+const MAX_TEXT_LENGTH: usize = 4096;
+
+#[derive(CandidType, Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub(crate) enum AttributeType {
+    Text,
+    Number,
+    Bool,
+    Tags,
+    // nanoseconds since the epoch, like the timestamps of the records
+    Date,
+}
+
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub(crate) enum AttributeValue {
+    Text(String),
+    Number(f64),
+    Bool(bool),
+    Tags(Vec<String>),
+    Date(u64),
+}
+
+impl AttributeValue {
+    fn attribute_type(&self) -> AttributeType {
+        match self {
+            AttributeValue::Text(_) => AttributeType::Text,
+            AttributeValue::Number(_) => AttributeType::Number,
+            AttributeValue::Bool(_) => AttributeType::Bool,
+            AttributeValue::Tags(_) => AttributeType::Tags,
+            AttributeValue::Date(_) => AttributeType::Date,
+        }
+    }
+
+    // numbers and dates compare with the min/max of a filter
+    fn as_number(&self) -> Option<f64> {
+        match self {
+            AttributeValue::Number(number) => Some(*number),
+            AttributeValue::Date(date) => Some(*date as f64),
+            _ => None,
+        }
+    }
+}
+
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub(crate) struct AttributeDefinition {
+    name: String,
+    attribute_type: AttributeType,
+    required: bool,
+}
+
+// attributes the items of a recommendation system may carry; when empty any attribute is accepted
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+pub(crate) struct AttributeSchema {
+    attributes: Vec<AttributeDefinition>,
+}
+
+impl Storable for AttributeSchema {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for AttributeSchema {
+    const MAX_SIZE: u32 = 16384;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// typed metadata of an item, stored apart from the item so it is not bound by the item's size
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+pub(crate) struct ItemMetadata {
+    tags: Vec<String>,
+    price: Option<f64>,
+    available: Option<bool>,
+    attributes: Vec<(String, AttributeValue)>,
+}
+
+impl Storable for ItemMetadata {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ItemMetadata {
+    const MAX_SIZE: u32 = 32768;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl ItemMetadata {
+    fn attribute(&self, name: &str) -> Option<&AttributeValue> {
+        self.attributes.iter().find(|(attribute, _)| attribute == name).map(|(_, value)| value)
+    }
+}
+
+// filter on an attribute: equal to value (for tags, carrying all of them) and/or between min and max
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug, Default)]
+pub(crate) struct AttributeFilter {
+    name: String,
+    value: Option<AttributeValue>,
+    min: Option<f64>,
+    max: Option<f64>,
+}
+
+// key of the tag index, one entry per (tag, item); tags cannot contain a NUL byte so it separates the two
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct TagKey {
+    tag: String,
+    item_id: u64,
+}
+
+impl Storable for TagKey {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        let mut bytes = self.tag.as_bytes().to_vec();
+        bytes.push(0);
+        bytes.extend_from_slice(&self.item_id.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let (tag, item_id) = bytes.split_at(bytes.len() - 9);
+        TagKey {
+            tag: String::from_utf8(tag.to_vec()).unwrap(),
+            item_id: u64::from_be_bytes(item_id[1..].try_into().unwrap()),
+        }
+    }
+}
+
+impl BoundedStorable for TagKey {
+    const MAX_SIZE: u32 = MAX_TAG_LENGTH as u32 + 9;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // recommendation system id -> attribute schema
+    static SCHEMAS: RefCell<StableBTreeMap<u64, AttributeSchema, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(36))))
+    );
+
+    // item id -> metadata
+    static METADATA: RefCell<StableBTreeMap<u64, ItemMetadata, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(37))))
+    );
+
+    static TAG_INDEX: RefCell<StableBTreeMap<TagKey, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(38))))
+    );
+}
+
+fn normalize_tag(tag: &str) -> String {
+    tag.trim().to_lowercase()
+}
+
+// lowercased, deduplicated tags in their first order
+pub(crate) fn normalize_tags(tags: &[String]) -> Result<Vec<String>, Error> {
+    let mut normalized: Vec<String> = vec![];
+    for tag in tags.iter().map(|tag| normalize_tag(tag)) {
+        if tag.is_empty() || tag.len() > MAX_TAG_LENGTH || tag.contains('\0') {
+            return Err(Error::InvalidInput {
+                msg: format!("tags must have between 1 and {} bytes and no NUL character", MAX_TAG_LENGTH),
+            });
+        }
+        if !normalized.contains(&tag) {
+            normalized.push(tag);
+        }
+    }
+    if normalized.len() > MAX_TAGS {
+        return Err(Error::InvalidInput { msg: format!("at most {} tags", MAX_TAGS) });
+    }
+    Ok(normalized)
+}
+
+// check the metadata on its own and normalize its tags
+fn validate_metadata(mut metadata: ItemMetadata) -> Result<ItemMetadata, Error> {
+    metadata.tags = normalize_tags(&metadata.tags)?;
+    if metadata.price.is_some_and(|price| !(price.is_finite() && price >= 0.0)) {
+        return Err(Error::InvalidInput { msg: "price must be a non-negative number".to_string() });
+    }
+    if metadata.attributes.len() > MAX_ATTRIBUTES {
+        return Err(Error::InvalidInput { msg: format!("at most {} attributes", MAX_ATTRIBUTES) });
+    }
+    let mut names = HashSet::new();
+    for (name, value) in metadata.attributes.iter_mut() {
+        if name.is_empty() || name.len() > MAX_ATTRIBUTE_NAME_LENGTH || !names.insert(name.clone()) {
+            return Err(Error::InvalidInput {
+                msg: format!("attribute names must be unique and have between 1 and {} bytes", MAX_ATTRIBUTE_NAME_LENGTH),
+            });
+        }
+        match value {
+            AttributeValue::Text(text) if text.len() > MAX_TEXT_LENGTH => {
+                return Err(Error::InvalidInput { msg: format!("attribute {} is longer than {} bytes", name, MAX_TEXT_LENGTH) });
+            }
+            AttributeValue::Number(number) if !number.is_finite() => {
+                return Err(Error::InvalidInput { msg: format!("attribute {} must be a finite number", name) });
+            }
+            AttributeValue::Tags(tags) => *tags = normalize_tags(tags)?,
+            _ => {}
+        }
+    }
+    if Encode!(&metadata).unwrap().len() > ItemMetadata::MAX_SIZE as usize {
+        return Err(Error::InvalidInput { msg: format!("metadata cannot exceed {} bytes", ItemMetadata::MAX_SIZE) });
+    }
+    Ok(metadata)
+}
+
+// check the attributes of an item against the schema of a recommendation system
+fn conforms(schema: &AttributeSchema, metadata: &ItemMetadata) -> Result<(), String> {
+    if schema.attributes.is_empty() {
+        return Ok(());
+    }
+    for (name, value) in &metadata.attributes {
+        match schema.attributes.iter().find(|definition| &definition.name == name) {
+            None => return Err(format!("attribute {} is not in the schema", name)),
+            Some(definition) if definition.attribute_type != value.attribute_type() => {
+                return Err(format!("attribute {} must be of type {:?}", name, definition.attribute_type));
+            }
+            Some(_) => {}
+        }
+    }
+    match schema.attributes.iter().find(|definition| definition.required && metadata.attribute(&definition.name).is_none()) {
+        Some(definition) => Err(format!("attribute {} is required", definition.name)),
+        None => Ok(()),
+    }
+}
+
+fn schema_of(recommendation_system_id: u64) -> AttributeSchema {
+    SCHEMAS.with(|m| m.borrow().get(&recommendation_system_id)).unwrap_or_default()
+}
+
+fn metadata_of(item_id: u64) -> ItemMetadata {
+    METADATA.with(|m| m.borrow().get(&item_id)).unwrap_or_default()
+}
+
+// check that an item can join a recommendation system, its metadata must conform to the system's schema
+pub(crate) fn check_item(recommendation_system_id: u64, item_id: u64) -> Result<(), Error> {
+    conforms(&schema_of(recommendation_system_id), &metadata_of(item_id)).map_err(|msg| Error::InvalidInput {
+        msg: format!("item with id={} does not fit recommendation system with id={}: {}", item_id, recommendation_system_id, msg),
+    })
+}
+
+// tags of the items, for content-based similarities
+pub(crate) fn tags_of(item_ids: impl IntoIterator<Item = u64>) -> std::collections::HashMap<u64, HashSet<String>> {
+    METADATA.with(|m| {
+        let m = m.borrow();
+        item_ids
+            .into_iter()
+            .filter_map(|item_id| m.get(&item_id).map(|metadata| (item_id, metadata.tags.into_iter().collect())))
+            .collect()
+    })
+}
+
+// items carrying a tag, from the tag index
+fn items_with_tag(tag: &str) -> HashSet<u64> {
+    let start = TagKey { tag: tag.to_string(), item_id: 0 };
+    let end = TagKey { tag: tag.to_string(), item_id: u64::MAX };
+    TAG_INDEX.with(|m| m.borrow().range(start..=end).map(|(key, _)| key.item_id).collect())
+}
+
+// restrict candidate items to those carrying every tag of the filters
+pub(crate) fn narrow_by_tags(item_ids: &HashSet<u64>, filters: &SearchFilters) -> HashSet<u64> {
+    let Some(tags) = filters.tags.as_ref().filter(|tags| !tags.is_empty()) else {
+        return item_ids.clone();
+    };
+    tags.iter().fold(item_ids.clone(), |item_ids, tag| {
+        let tagged = items_with_tag(&normalize_tag(tag));
+        item_ids.into_iter().filter(|item_id| tagged.contains(item_id)).collect()
+    })
+}
+
+fn matches_attribute(metadata: &ItemMetadata, filter: &AttributeFilter) -> bool {
+    let Some(value) = metadata.attribute(&filter.name) else {
+        return false;
+    };
+    let equal = match (&filter.value, value) {
+        (None, _) => true,
+        (Some(AttributeValue::Tags(wanted)), AttributeValue::Tags(tags)) => {
+            wanted.iter().all(|tag| tags.contains(&normalize_tag(tag)))
+        }
+        (Some(AttributeValue::Text(wanted)), AttributeValue::Text(text)) => wanted.trim().eq_ignore_ascii_case(text.trim()),
+        (Some(wanted), value) => wanted == value,
+    };
+    let number = value.as_number();
+    equal
+        && filter.min.is_none_or(|min| number.is_some_and(|number| number >= min))
+        && filter.max.is_none_or(|max| number.is_some_and(|number| number <= max))
+}
+
+// whether an item's metadata passes the price, availability and attribute filters
+pub(crate) fn matches(item_id: u64, filters: &SearchFilters) -> bool {
+    let unfiltered = filters.min_price.is_none()
+        && filters.max_price.is_none()
+        && filters.available.is_none()
+        && filters.attributes.as_ref().is_none_or(|attributes| attributes.is_empty());
+    if unfiltered {
+        return true;
+    }
+    let metadata = metadata_of(item_id);
+    filters.min_price.is_none_or(|min| metadata.price.is_some_and(|price| price >= min))
+        && filters.max_price.is_none_or(|max| metadata.price.is_some_and(|price| price <= max))
+        && filters.available.is_none_or(|available| metadata.available.unwrap_or(true) == available)
+        && filters.attributes.iter().flatten().all(|filter| matches_attribute(&metadata, filter))
+}
+
+fn index_tags(item_id: u64, tags: &[String], index: bool) {
+    TAG_INDEX.with(|m| {
+        let mut m = m.borrow_mut();
+        for tag in tags {
+            let key = TagKey { tag: tag.clone(), item_id };
+            if index {
+                m.insert(key, ());
+            } else {
+                m.remove(&key);
+            }
+        }
+    });
+}
+
+// drop the metadata of an item purged from the trash
+pub(crate) fn remove_item(item_id: u64) {
+    if let Some(metadata) = METADATA.with(|m| m.borrow_mut().remove(&item_id)) {
+        index_tags(item_id, &metadata.tags, false);
+    }
+}
+
+// function to get the attribute schema of a recommendation system
+#[ic_cdk::query]
+fn get_attribute_schema(recommendation_system_id: u64) -> AttributeSchema {
+    schema_of(recommendation_system_id)
+}
+
+// function to set the attribute schema of a recommendation system, restricted to controllers;
+// the items already in the system must conform to it
+#[ic_cdk::update]
+fn set_attribute_schema(recommendation_system_id: u64, schema: AttributeSchema) -> Result<AttributeSchema, Error> {
+    ensure_controller("change attribute schemas")?;
+    let recommendation_system = RECOMMENDATION_SYSTEM_STORAGE
+        .with(|service| service.borrow().get(&recommendation_system_id))
+        .filter(|record| record.deleted_at.is_none())
+        .ok_or(Error::NotFound {
+            msg: format!("recommendation system with id={} not found", recommendation_system_id),
+        })?;
+    let mut names = HashSet::new();
+    if schema.attributes.len() > MAX_ATTRIBUTES
+        || schema.attributes.iter().any(|definition| {
+            definition.name.is_empty() || definition.name.len() > MAX_ATTRIBUTE_NAME_LENGTH || !names.insert(&definition.name)
+        })
+    {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "at most {} attributes with unique names of 1 to {} bytes",
+                MAX_ATTRIBUTES, MAX_ATTRIBUTE_NAME_LENGTH
+            ),
+        });
+    }
+    for item in &recommendation_system.items {
+        conforms(&schema, &metadata_of(item.id)).map_err(|msg| Error::Conflict {
+            msg: format!("item with id={} does not fit the schema: {}", item.id, msg),
+        })?;
+    }
+
+    let previous = schema_of(recommendation_system_id);
+    SCHEMAS.with(|m| m.borrow_mut().insert(recommendation_system_id, schema.clone()));
+    audit::record("set_attribute_schema", vec![recommendation_system_id], Some(&previous), Some(&schema));
+    Ok(schema)
+}
+
+// function to get the metadata of an item, empty when none was set
+#[ic_cdk::query]
+fn get_item_metadata(item_id: u64) -> Result<ItemMetadata, Error> {
+    if !item_is_active(item_id) {
+        return Err(Error::NotFound { msg: format!("item with id={} not found", item_id) });
+    }
+    Ok(metadata_of(item_id))
+}
+
+// function to set the tags, price, availability and attributes of an item; they must conform to the
+// schemas of the recommendation systems holding the item
+#[ic_cdk::update]
+fn set_item_metadata(item_id: u64, metadata: ItemMetadata) -> Result<ItemMetadata, Error> {
+    if !item_is_active(item_id) {
+        return Err(Error::NotFound { msg: format!("item with id={} not found", item_id) });
+    }
+    let metadata = validate_metadata(metadata)?;
+    let systems: Vec<u64> = RECOMMENDATION_SYSTEM_STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(_, system)| system.deleted_at.is_none() && system.items.iter().any(|item| item.id == item_id))
+            .map(|(id, _)| id)
+            .collect()
+    });
+    for recommendation_system_id in systems {
+        conforms(&schema_of(recommendation_system_id), &metadata).map_err(|msg| Error::InvalidInput {
+            msg: format!("schema of recommendation system with id={}: {}", recommendation_system_id, msg),
+        })?;
+    }
+
+    let previous = metadata_of(item_id);
+    index_tags(item_id, &previous.tags, false);
+    index_tags(item_id, &metadata.tags, true);
+    METADATA.with(|m| m.borrow_mut().insert(item_id, metadata.clone()));
+    audit::record("set_item_metadata", vec![item_id], Some(&previous), Some(&metadata));
+    Ok(metadata)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn definition(name: &str, attribute_type: AttributeType, required: bool) -> AttributeDefinition {
+        AttributeDefinition { name: name.to_string(), attribute_type, required }
+    }
+
+    #[test]
+    fn metadata_is_normalized_and_checked_against_schemas() {
+        let metadata = ItemMetadata {
+            tags: vec![" Sci-Fi".to_string(), "sci-fi".to_string(), "Classic".to_string()],
+            price: Some(12.5),
+            attributes: vec![
+                ("pages".to_string(), AttributeValue::Number(412.0)),
+                ("themes".to_string(), AttributeValue::Tags(vec!["Desert".to_string()])),
+            ],
+            ..Default::default()
+        };
+        let metadata = validate_metadata(metadata).unwrap();
+        assert_eq!(metadata.tags, vec!["sci-fi", "classic"]);
+        assert_eq!(metadata.attribute("themes"), Some(&AttributeValue::Tags(vec!["desert".to_string()])));
+        assert!(validate_metadata(ItemMetadata { price: Some(-1.0), ..Default::default() }).is_err());
+
+        let schema = AttributeSchema {
+            attributes: vec![definition("pages", AttributeType::Number, true), definition("themes", AttributeType::Tags, false)],
+        };
+        assert!(conforms(&schema, &metadata).is_ok());
+        assert!(conforms(&schema, &ItemMetadata::default()).is_err());
+        let mistyped = AttributeSchema { attributes: vec![definition("pages", AttributeType::Text, false)] };
+        assert!(conforms(&mistyped, &metadata).is_err());
+        assert!(conforms(&AttributeSchema::default(), &metadata).is_ok());
+    }
+
+    #[test]
+    fn filters_use_tag_index_and_attributes() {
+        for (item_id, tags, price, year) in [(1, vec!["a", "b"], 5.0, 1965.0), (2, vec!["a"], 20.0, 1990.0), (3, vec!["b"], 8.0, 2001.0)] {
+            let metadata = ItemMetadata {
+                tags: tags.into_iter().map(String::from).collect(),
+                price: Some(price),
+                attributes: vec![("year".to_string(), AttributeValue::Number(year))],
+                ..Default::default()
+            };
+            index_tags(item_id, &metadata.tags, true);
+            METADATA.with(|m| m.borrow_mut().insert(item_id, metadata));
+        }
+        let all: HashSet<u64> = [1, 2, 3].into();
+        let filters = SearchFilters { tags: Some(vec!["A".to_string()]), ..Default::default() };
+        assert_eq!(narrow_by_tags(&all, &filters), [1, 2].into());
+
+        let filters = SearchFilters {
+            max_price: Some(10.0),
+            attributes: Some(vec![AttributeFilter { name: "year".to_string(), min: Some(1980.0), ..Default::default() }]),
+            ..Default::default()
+        };
+        assert_eq!(all.iter().copied().filter(|&item_id| matches(item_id, &filters)).collect::<Vec<_>>(), vec![3]);
+
+        remove_item(1);
+        assert_eq!(items_with_tag("b"), [3].into());
+    }
+}
@@ -0,0 +1,288 @@
+use crate::{ensure_controller, Error, Memory, MEMORY_MANAGER};
+use candid::{CandidType, Decode, Encode, Principal};
+use serde::Serialize;
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use sha2::{Digest, Sha256};
+use std::{borrow::Cow, cell::RefCell, ops::Bound};
+
+// keep the last 100k entries by default
+const DEFAULT_AUDIT_LOG_RETENTION: u64 = 100_000;
+
+// bounds the work done by a single append after the retention limit is lowered
+const MAX_PRUNED_PER_APPEND: usize = 100;
+
+// maximum number of entries returned by one get_audit_log call
+const MAX_AUDIT_LOG_PAGE_SIZE: u64 = 100;
+
+// struct to represent one mutating call recorded in the audit log
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug)]
+pub(crate) struct AuditEntry {
+    id: u64,
+    caller: Principal,
+    method: String,
+    entity_ids: Vec<u64>,
+    // sha256 of the candid encoded record before and after the call
+    before_hash: Option<String>,
+    after_hash: Option<String>,
+    // top level fields that differ between before and after
+    changed_fields: Vec<String>,
+    timestamp: u64,
+}
+
+impl Storable for AuditEntry {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for AuditEntry {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// filter and cursor for get_audit_log, entries are returned oldest first
+#[derive(CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct AuditLogQuery {
+    caller: Option<Principal>,
+    method: Option<String>,
+    entity_id: Option<u64>,
+    from: Option<u64>,
+    to: Option<u64>,
+    start_after: Option<u64>,
+    limit: Option<u64>,
+}
+
+#[derive(CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct AuditLogPage {
+    entries: Vec<AuditEntry>,
+    // pass as start_after to fetch the next page
+    next: Option<u64>,
+}
+
+thread_local! {
+    static AUDIT_ID_COUNTER: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static AUDIT_LOG_RETENTION: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11))), DEFAULT_AUDIT_LOG_RETENTION)
+            .expect("Cannot create the audit log retention")
+    );
+
+    static AUDIT_LOG: RefCell<StableBTreeMap<u64, AuditEntry, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12))))
+    );
+}
+
+// record a successful mutating call made by the current caller
+pub(crate) fn record<T: CandidType + Serialize>(method: &str, entity_ids: Vec<u64>, before: Option<&T>, after: Option<&T>) {
+    record_as(ic_cdk::caller(), method, entity_ids, before, after);
+}
+
+// record a mutation made on behalf of `caller`, e.g. the canister itself for timer jobs
+pub(crate) fn record_as<T: CandidType + Serialize>(
+    caller: Principal,
+    method: &str,
+    entity_ids: Vec<u64>,
+    before: Option<&T>,
+    after: Option<&T>,
+) {
+    record_at(caller, method, entity_ids, before, after, time());
+}
+
+// record a mutation at a given time, the caller reads the clock once for a batch
+pub(crate) fn record_at<T: CandidType + Serialize>(
+    caller: Principal,
+    method: &str,
+    entity_ids: Vec<u64>,
+    before: Option<&T>,
+    after: Option<&T>,
+    timestamp: u64,
+) {
+    append(AuditEntry {
+        id: 0,
+        caller,
+        method: method.to_string(),
+        entity_ids,
+        before_hash: before.map(hash),
+        after_hash: after.map(hash),
+        changed_fields: changed_fields(before, after),
+        timestamp,
+    });
+}
+
+// append an entry to the log and drop the oldest entries beyond the retention limit
+fn append(mut entry: AuditEntry) -> u64 {
+    let id = AUDIT_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment id counter");
+    entry.id = id;
+
+    let retention = AUDIT_LOG_RETENTION.with(|cell| *cell.borrow().get());
+    AUDIT_LOG.with(|m| {
+        let mut log = m.borrow_mut();
+        log.insert(id, entry);
+        for _ in 0..MAX_PRUNED_PER_APPEND {
+            match log.first_key_value() {
+                Some((oldest, _)) if log.len() > retention => log.remove(&oldest),
+                _ => break,
+            };
+        }
+    });
+    id
+}
+
+fn hash<T: CandidType>(value: &T) -> String {
+    Sha256::digest(Encode!(value).unwrap())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn changed_fields<T: Serialize>(before: Option<&T>, after: Option<&T>) -> Vec<String> {
+    let fields = |value: Option<&T>| match value.map(serde_json::to_value) {
+        Some(Ok(serde_json::Value::Object(map))) => map,
+        _ => serde_json::Map::new(),
+    };
+    let (before, after) = (fields(before), fields(after));
+    let mut changed: Vec<String> = before
+        .keys()
+        .chain(after.keys())
+        .filter(|key| before.get(*key) != after.get(*key))
+        .cloned()
+        .collect();
+    changed.sort();
+    changed.dedup();
+    changed
+}
+
+fn query_log(query: &AuditLogQuery) -> AuditLogPage {
+    let limit = query.limit.unwrap_or(MAX_AUDIT_LOG_PAGE_SIZE).clamp(1, MAX_AUDIT_LOG_PAGE_SIZE) as usize;
+    let start = query.start_after.map_or(Bound::Unbounded, Bound::Excluded);
+    let matches = |entry: &AuditEntry| {
+        query.caller.is_none_or(|caller| entry.caller == caller)
+            && query.method.as_ref().is_none_or(|method| &entry.method == method)
+            && query.entity_id.is_none_or(|id| entry.entity_ids.contains(&id))
+            && query.from.is_none_or(|from| entry.timestamp >= from)
+            && query.to.is_none_or(|to| entry.timestamp <= to)
+    };
+
+    AUDIT_LOG.with(|m| {
+        let log = m.borrow();
+        let mut entries: Vec<AuditEntry> = log
+            .range((start, Bound::Unbounded))
+            .map(|(_, entry)| entry)
+            .filter(|entry| matches(entry))
+            .take(limit + 1)
+            .collect();
+        let next = if entries.len() > limit {
+            entries.truncate(limit);
+            entries.last().map(|entry| entry.id)
+        } else {
+            None
+        };
+        AuditLogPage { entries, next }
+    })
+}
+
+// function to read the audit log, restricted to controllers
+#[ic_cdk::query]
+fn get_audit_log(query: AuditLogQuery) -> Result<AuditLogPage, Error> {
+    ensure_controller("read the audit log")?;
+    Ok(query_log(&query))
+}
+
+// function to get the maximum number of audit log entries kept
+#[ic_cdk::query]
+fn get_audit_log_retention() -> u64 {
+    AUDIT_LOG_RETENTION.with(|cell| *cell.borrow().get())
+}
+
+// function to set the maximum number of audit log entries kept, restricted to controllers
+#[ic_cdk::update]
+fn set_audit_log_retention(max_entries: u64) -> Result<u64, Error> {
+    ensure_controller("change the audit log retention")?;
+    if max_entries == 0 {
+        return Err(Error::InvalidInput { msg: "retention must keep at least one entry".to_string() });
+    }
+    let previous = get_audit_log_retention();
+    AUDIT_LOG_RETENTION
+        .with(|cell| cell.borrow_mut().set(max_entries))
+        .expect("cannot set the audit log retention");
+    record("set_audit_log_retention", vec![], Some(&previous), Some(&max_entries));
+    Ok(max_entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(method: &str, entity_ids: Vec<u64>, timestamp: u64) -> AuditEntry {
+        AuditEntry {
+            id: 0,
+            caller: Principal::anonymous(),
+            method: method.to_string(),
+            entity_ids,
+            before_hash: None,
+            after_hash: None,
+            changed_fields: vec![],
+            timestamp,
+        }
+    }
+
+    #[test]
+    fn append_enforces_retention() {
+        AUDIT_LOG_RETENTION.with(|cell| cell.borrow_mut().set(3)).unwrap();
+        for timestamp in 0..5 {
+            append(entry("add_item", vec![timestamp], timestamp));
+        }
+
+        let ids: Vec<u64> = AUDIT_LOG.with(|m| m.borrow().iter().map(|(id, _)| id).collect());
+        assert_eq!(ids, vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn query_log_filters_and_paginates() {
+        append(entry("add_item", vec![42], 10));
+        append(entry("delete_item", vec![42], 20));
+        append(entry("delete_item", vec![7], 30));
+        append(entry("delete_item", vec![42], 40));
+
+        let query = AuditLogQuery {
+            method: Some("delete_item".to_string()),
+            entity_id: Some(42),
+            limit: Some(1),
+            ..Default::default()
+        };
+        let page = query_log(&query);
+        assert_eq!(page.entries.iter().map(|entry| entry.timestamp).collect::<Vec<_>>(), vec![20]);
+        assert_eq!(page.next, Some(1));
+
+        let page = query_log(&AuditLogQuery { start_after: page.next, ..query.clone() });
+        assert_eq!(page.entries.iter().map(|entry| entry.timestamp).collect::<Vec<_>>(), vec![40]);
+        assert_eq!(page.next, None);
+
+        let page = query_log(&AuditLogQuery { from: Some(15), to: Some(35), ..Default::default() });
+        assert_eq!(page.entries.len(), 2);
+    }
+
+    #[test]
+    fn changed_fields_lists_differing_fields() {
+        let before = crate::Item { id: 1, name: "a".to_string(), ..Default::default() };
+        let after = crate::Item { name: "b".to_string(), updated_at: Some(5), ..before.clone() };
+
+        assert_eq!(changed_fields(Some(&before), Some(&after)), vec!["name", "updated_at"]);
+        assert_ne!(hash(&before), hash(&after));
+        assert_eq!(hash(&before).len(), 64);
+    }
+}
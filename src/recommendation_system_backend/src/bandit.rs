@@ -0,0 +1,359 @@
+use crate::impressions::{self, Impression};
+use crate::linucb;
+use crate::recommendations::{load_ratings, Ratings, Recommendation};
+use crate::rules::{self, RecommendationRules};
+use crate::settings::Algorithm;
+use crate::{audit, Error, Memory, ITEM_STORAGE, MEMORY_MANAGER, RECOMMENDATION_SYSTEM_STORAGE};
+use candid::{CandidType, Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const DEFAULT_EPSILON: f64 = 0.1;
+const DEFAULT_EXPLORATION_SLOTS: u64 = 1;
+const MAX_EXPLORATION_SLOTS: u64 = 10;
+
+#[derive(CandidType, Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub(crate) enum BanditPolicy {
+    EpsilonGreedy,
+    Ucb1,
+    ThompsonSampling,
+}
+
+// exploration layer of a recommendation system: the last `slots` positions of every list are
+// given to items chosen by a bandit policy, with the items of the system as arms
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug)]
+pub(crate) struct ExplorationSettings {
+    pub(crate) policy: BanditPolicy,
+    // probability of a uniformly random arm for EpsilonGreedy, 0.1 by default
+    pub(crate) epsilon: Option<f64>,
+    // number of explored positions, 1 by default
+    pub(crate) slots: Option<u64>,
+}
+
+impl ExplorationSettings {
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        if self.epsilon.is_some_and(|epsilon| !(0.0..=1.0).contains(&epsilon)) {
+            return Err(Error::InvalidInput { msg: "epsilon must be between 0 and 1".to_string() });
+        }
+        if self.slots.is_some_and(|slots| slots > MAX_EXPLORATION_SLOTS) {
+            return Err(Error::InvalidInput {
+                msg: format!("at most {} exploration slots are allowed", MAX_EXPLORATION_SLOTS),
+            });
+        }
+        Ok(())
+    }
+}
+
+// key of the arm statistics, one arm per item of a recommendation system
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+struct ArmKey {
+    recommendation_system_id: u64,
+    item_id: u64,
+}
+
+impl Storable for ArmKey {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        let mut bytes = self.recommendation_system_id.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&self.item_id.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let (recommendation_system_id, item_id) = bytes.split_at(8);
+        ArmKey {
+            recommendation_system_id: u64::from_be_bytes(recommendation_system_id.try_into().unwrap()),
+            item_id: u64::from_be_bytes(item_id.try_into().unwrap()),
+        }
+    }
+}
+
+impl BoundedStorable for ArmKey {
+    const MAX_SIZE: u32 = 16;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+#[derive(CandidType, Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq)]
+pub(crate) struct ArmStats {
+    pulls: u64,
+    // sum of the rewards, each between 0 and 1
+    rewards: f64,
+}
+
+impl Storable for ArmStats {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ArmStats {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl ArmStats {
+    fn mean(&self) -> f64 {
+        if self.pulls == 0 {
+            0.0
+        } else {
+            self.rewards / self.pulls as f64
+        }
+    }
+}
+
+thread_local! {
+    // state of the PRNG, seeded from raw_rand
+    static RNG_STATE: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22))), 0)
+            .expect("Cannot create the rng state")
+    );
+
+    static ARMS: RefCell<StableBTreeMap<ArmKey, ArmStats, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23))))
+    );
+}
+
+// splitmix64 over a state kept in stable memory
+pub(crate) struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub(crate) fn load() -> Self {
+        Rng { state: RNG_STATE.with(|cell| *cell.borrow().get()) }
+    }
+
+    pub(crate) fn save(&self) {
+        RNG_STATE.with(|cell| cell.borrow_mut().set(self.state)).expect("cannot save the rng state");
+    }
+
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // uniform in [0, 1)
+    pub(crate) fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn normal(&mut self) -> f64 {
+        let u = 1.0 - self.next_f64();
+        let v = self.next_f64();
+        (-2.0 * u.ln()).sqrt() * (2.0 * std::f64::consts::PI * v).cos()
+    }
+
+    // Marsaglia and Tsang, for shape >= 1
+    fn gamma(&mut self, shape: f64) -> f64 {
+        let d = shape - 1.0 / 3.0;
+        let c = 1.0 / (9.0 * d).sqrt();
+        loop {
+            let x = self.normal();
+            let v = (1.0 + c * x).powi(3);
+            if v <= 0.0 {
+                continue;
+            }
+            let u = 1.0 - self.next_f64();
+            if u.ln() < 0.5 * x * x + d - d * v + d * v.ln() {
+                return d * v;
+            }
+        }
+    }
+
+    fn beta(&mut self, alpha: f64, beta: f64) -> f64 {
+        let x = self.gamma(alpha);
+        let y = self.gamma(beta);
+        x / (x + y)
+    }
+}
+
+// mix 32 bytes from the management canister into the PRNG state, after install and upgrades
+pub(crate) fn start_seeding() {
+    ic_cdk_timers::set_timer(std::time::Duration::ZERO, || {
+        ic_cdk::spawn(async {
+            if let Ok((bytes,)) = ic_cdk::api::management_canister::main::raw_rand().await {
+                let mut rng = Rng::load();
+                for chunk in bytes.chunks(8) {
+                    let mut word = [0; 8];
+                    word[..chunk.len()].copy_from_slice(chunk);
+                    rng.state ^= u64::from_le_bytes(word);
+                    rng.next_u64();
+                }
+                rng.save();
+            }
+        });
+    });
+}
+
+// index of the arm to pull
+pub(crate) fn choose(policy: BanditPolicy, epsilon: f64, arms: &[(u64, ArmStats)], rng: &mut Rng) -> usize {
+    let best_by = |value: &mut dyn FnMut(&ArmStats) -> f64| {
+        let mut best = 0;
+        let mut best_value = f64::NEG_INFINITY;
+        for (index, (_, stats)) in arms.iter().enumerate() {
+            let value = value(stats);
+            if value > best_value {
+                best = index;
+                best_value = value;
+            }
+        }
+        best
+    };
+    match policy {
+        BanditPolicy::EpsilonGreedy => {
+            if rng.next_f64() < epsilon {
+                (rng.next_u64() % arms.len() as u64) as usize
+            } else {
+                best_by(&mut |stats| stats.mean())
+            }
+        }
+        BanditPolicy::Ucb1 => {
+            let total: u64 = arms.iter().map(|(_, stats)| stats.pulls).sum();
+            best_by(&mut |stats| {
+                if stats.pulls == 0 {
+                    f64::INFINITY
+                } else {
+                    stats.mean() + (2.0 * (total as f64).ln() / stats.pulls as f64).sqrt()
+                }
+            })
+        }
+        BanditPolicy::ThompsonSampling => {
+            best_by(&mut |stats| rng.beta(1.0 + stats.rewards, 1.0 + stats.pulls as f64 - stats.rewards))
+        }
+    }
+}
+
+fn arm_stats(recommendation_system_id: u64, item_id: u64) -> ArmStats {
+    ARMS.with(|m| m.borrow().get(&ArmKey { recommendation_system_id, item_id })).unwrap_or_default()
+}
+
+fn update_arm(recommendation_system_id: u64, item_id: u64, update: impl FnOnce(&mut ArmStats)) {
+    let mut stats = arm_stats(recommendation_system_id, item_id);
+    update(&mut stats);
+    ARMS.with(|m| m.borrow_mut().insert(ArmKey { recommendation_system_id, item_id }, stats));
+}
+
+// give the last slots of the list to explored items, returns their ids
+pub(crate) fn explore(
+    settings: &ExplorationSettings,
+    rules: &RecommendationRules,
+    ratings: &Ratings,
+    recommendation_system_id: u64,
+    user_id: u64,
+    recommendations: &mut Vec<Recommendation>,
+    limit: usize,
+) -> Vec<u64> {
+    let slots = (settings.slots.unwrap_or(DEFAULT_EXPLORATION_SLOTS) as usize).min(limit);
+    let rated = ratings.by_user.get(&user_id);
+    let mut arms: Vec<(u64, ArmStats)> = ratings
+        .items
+        .iter()
+        .filter(|id| !(rules.exclude_rated() && rated.is_some_and(|rated| rated.contains_key(id))))
+        .filter(|id| !recommendations[..recommendations.len().min(limit - slots)].iter().any(|r| r.item.id == **id))
+        .filter_map(|&id| ITEM_STORAGE.with(|m| m.borrow().get(&id)))
+        .filter(|item| rules::allows(rules, item))
+        .map(|item| (item.id, arm_stats(recommendation_system_id, item.id)))
+        .collect();
+
+    let mut rng = Rng::load();
+    let mut explored = vec![];
+    while explored.len() < slots && !arms.is_empty() {
+        let (item_id, stats) = arms.remove(choose(settings.policy, settings.epsilon.unwrap_or(DEFAULT_EPSILON), &arms, &mut rng));
+        explored.push((item_id, stats));
+    }
+    rng.save();
+
+    recommendations.truncate(limit - explored.len());
+    for &(item_id, stats) in &explored {
+        recommendations.retain(|recommendation| recommendation.item.id != item_id);
+        if let Some(item) = ITEM_STORAGE.with(|m| m.borrow().get(&item_id)) {
+            recommendations.push(Recommendation::new(item, stats.mean()));
+        }
+        update_arm(recommendation_system_id, item_id, |stats| stats.pulls += 1);
+    }
+    explored.into_iter().map(|(item_id, _)| item_id).collect()
+}
+
+fn apply_reward(impression: &mut Impression, reward: f64) -> Result<(), Error> {
+    if !(0.0..=1.0).contains(&reward) {
+        return Err(Error::InvalidInput { msg: format!("reward must be between 0 and 1, got {}", reward) });
+    }
+    if impression.reward.is_some() {
+        return Err(Error::Conflict { msg: format!("impression with id={} was already rewarded", impression.id) });
+    }
+    impression.reward = Some(reward);
+    for &item_id in &impression.explored_item_ids {
+        update_arm(impression.recommendation_system_id, item_id, |stats| stats.rewards += reward);
+    }
+    if impression.algorithm == Some(Algorithm::LinUcb) {
+        let recommendation_system =
+            RECOMMENDATION_SYSTEM_STORAGE.with(|m| m.borrow().get(&impression.recommendation_system_id));
+        if let Some(recommendation_system) = recommendation_system {
+            // explored items were not chosen by the model
+            let scored: Vec<u64> =
+                impression.item_ids.iter().copied().filter(|id| !impression.explored_item_ids.contains(id)).collect();
+            linucb::reward(recommendation_system.id, &load_ratings(&recommendation_system), impression.user_id, &scored, reward);
+        }
+    }
+    Ok(())
+}
+
+// function to reward an impression between 0 and 1, once: the reward goes to its explored items
+// and, for LinUcb lists, to the model of the recommendation system
+#[ic_cdk::update]
+fn record_reward(impression_id: u64, reward: f64) -> Result<(), Error> {
+    let mut impression = impressions::get(impression_id).ok_or(Error::NotFound {
+        msg: format!("impression with id={} not found", impression_id),
+    })?;
+    let before = impression.clone();
+    apply_reward(&mut impression, reward)?;
+    impressions::save(impression.clone());
+    audit::record("record_reward", vec![impression_id], Some(&before), Some(&impression));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn arms(stats: &[(u64, f64)]) -> Vec<(u64, ArmStats)> {
+        stats.iter().enumerate().map(|(id, &(pulls, rewards))| (id as u64, ArmStats { pulls, rewards })).collect()
+    }
+
+    #[test]
+    fn policies_pick_expected_arms() {
+        let mut rng = Rng { state: 42 };
+        let arms = arms(&[(10, 2.0), (10, 8.0), (0, 0.0)]);
+
+        assert_eq!(choose(BanditPolicy::EpsilonGreedy, 0.0, &arms, &mut rng), 1);
+        assert_eq!(choose(BanditPolicy::Ucb1, 0.0, &arms, &mut rng), 2);
+        let picks = (0..200).filter(|_| choose(BanditPolicy::ThompsonSampling, 0.0, &arms, &mut rng) == 0).count();
+        assert!(picks < 20, "arm 0 was picked {} times", picks);
+    }
+
+    #[test]
+    fn beta_samples_have_the_expected_mean() {
+        let mut rng = Rng { state: 7 };
+        let mean = (0..2000).map(|_| rng.beta(3.0, 7.0)).sum::<f64>() / 2000.0;
+        assert!((mean - 0.3).abs() < 0.02, "mean {}", mean);
+        assert!((0..1000).map(|_| rng.next_f64()).all(|x| (0.0..1.0).contains(&x)));
+    }
+
+    #[test]
+    fn rewards_are_applied_once() {
+        let mut impression = Impression { id: 1, recommendation_system_id: 1, explored_item_ids: vec![5], ..Default::default() };
+        update_arm(1, 5, |stats| stats.pulls += 1);
+
+        assert!(matches!(apply_reward(&mut impression, 1.5), Err(Error::InvalidInput { .. })));
+        apply_reward(&mut impression, 1.0).unwrap();
+        assert!(matches!(apply_reward(&mut impression, 1.0), Err(Error::Conflict { .. })));
+        assert_eq!(arm_stats(1, 5), ArmStats { pulls: 1, rewards: 1.0 });
+    }
+}
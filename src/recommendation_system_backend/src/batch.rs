@@ -0,0 +1,150 @@
+use crate::{
+    attributes, audit, get_item_by_id, get_user_by_id, insert_item, insert_user_preference, item_is_active,
+    validate_item_payload, validate_user_preference_payload, Error, Item, ItemPayload,
+    RecommendationSystem, User, UserPreference, UserPreferencePayload, RECOMMENDATION_SYSTEM_STORAGE,
+};
+use candid::Encode;
+use ic_stable_structures::BoundedStorable;
+
+// maximum number of elements accepted by one batch call
+const MAX_BATCH_SIZE: usize = 100;
+
+// check the size of a batch and the result of validating each of its elements;
+// if anything failed every element gets an error and nothing must be applied
+fn check_batch<T>(validations: Vec<Result<(), Error>>) -> Result<(), Vec<Result<T, Error>>> {
+    if validations.len() > MAX_BATCH_SIZE {
+        let msg = format!("batch of {} elements exceeds the limit of {}", validations.len(), MAX_BATCH_SIZE);
+        return Err(validations.iter().map(|_| Err(Error::InvalidInput { msg: msg.clone() })).collect());
+    }
+    let failed: Vec<usize> = validations
+        .iter()
+        .enumerate()
+        .filter(|(_, validation)| validation.is_err())
+        .map(|(index, _)| index)
+        .collect();
+    if failed.is_empty() {
+        return Ok(());
+    }
+
+    let msg = format!("batch not applied, elements {:?} are invalid", failed);
+    Err(validations
+        .into_iter()
+        .map(|validation| match validation {
+            Ok(()) => Err(Error::Aborted { msg: msg.clone() }),
+            Err(error) => Err(error),
+        })
+        .collect())
+}
+
+// function to get several items by id, with one result per id
+#[ic_cdk::query]
+fn get_items_by_ids(ids: Vec<u64>) -> Vec<Result<Item, Error>> {
+    if let Err(results) = check_batch(ids.iter().map(|_| Ok(())).collect()) {
+        return results;
+    }
+    ids.into_iter().map(get_item_by_id).collect()
+}
+
+// function to get several users by id, with one result per id
+#[ic_cdk::query]
+fn get_users_by_ids(ids: Vec<u64>) -> Vec<Result<User, Error>> {
+    if let Err(results) = check_batch(ids.iter().map(|_| Ok(())).collect()) {
+        return results;
+    }
+    ids.into_iter().map(get_user_by_id).collect()
+}
+
+// function to add several items, either all of them are added or none
+#[ic_cdk::update]
+fn add_items(payloads: Vec<ItemPayload>) -> Vec<Result<Item, Error>> {
+    if let Err(results) = check_batch(payloads.iter().map(validate_item_payload).collect()) {
+        return results;
+    }
+    payloads
+        .into_iter()
+        .map(|payload| {
+            let item = insert_item(payload);
+            audit::record("add_items", vec![item.id], None, Some(&item));
+            Ok(item)
+        })
+        .collect()
+}
+
+// function to add several user preferences, either all of them are added or none
+#[ic_cdk::update]
+fn add_user_preferences(payloads: Vec<UserPreferencePayload>) -> Vec<Result<UserPreference, Error>> {
+    if let Err(results) = check_batch(payloads.iter().map(validate_user_preference_payload).collect()) {
+        return results;
+    }
+    payloads
+        .into_iter()
+        .map(|payload| {
+            let user_preference = insert_user_preference(payload);
+            audit::record("add_user_preferences", vec![user_preference.id], None, Some(&user_preference));
+            Ok(user_preference)
+        })
+        .collect()
+}
+
+// function to add several items to a recommendation system, either all of them are added or none
+#[ic_cdk::update]
+fn add_items_to_recommendation_system(recommendation_system_id: u64, item_ids: Vec<u64>) -> Vec<Result<Item, Error>> {
+    let recommendation_system = RECOMMENDATION_SYSTEM_STORAGE
+        .with(|service| service.borrow().get(&recommendation_system_id))
+        .filter(|record| record.deleted_at.is_none());
+    let validations = item_ids
+        .iter()
+        .map(|&item_id| match &recommendation_system {
+            None => Err(Error::NotFound {
+                msg: format!("recommendation system with id={} not found", recommendation_system_id),
+            }),
+            Some(_) if !item_is_active(item_id) => Err(Error::NotFound {
+                msg: format!("item with id={} not found", item_id),
+            }),
+            Some(_) => attributes::check_item(recommendation_system_id, item_id),
+        })
+        .collect();
+    if let Err(results) = check_batch(validations) {
+        return results;
+    }
+    let Some(mut recommendation_system) = recommendation_system else {
+        return vec![];
+    };
+
+    let before = recommendation_system.clone();
+    let items: Vec<Item> = item_ids.iter().map(|&item_id| get_item_by_id(item_id).unwrap()).collect();
+    recommendation_system.items.extend(items.iter().cloned());
+    if Encode!(&recommendation_system).unwrap().len() > RecommendationSystem::MAX_SIZE as usize {
+        let msg = format!("recommendation system with id={} cannot hold {} more items", recommendation_system_id, items.len());
+        return items.iter().map(|_| Err(Error::InvalidInput { msg: msg.clone() })).collect();
+    }
+
+    RECOMMENDATION_SYSTEM_STORAGE.with(|m| m.borrow_mut().insert(recommendation_system_id, recommendation_system.clone()));
+    let mut entity_ids = vec![recommendation_system_id];
+    entity_ids.extend(&item_ids);
+    audit::record("add_items_to_recommendation_system", entity_ids, Some(&before), Some(&recommendation_system));
+    items.into_iter().map(Ok).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_batch_aborts_valid_elements_when_one_fails() {
+        let invalid = Error::InvalidInput { msg: "All fields are required".to_string() };
+        let results = check_batch::<()>(vec![Ok(()), Err(invalid), Ok(())]).unwrap_err();
+
+        assert!(matches!(results[0], Err(Error::Aborted { .. })));
+        assert!(matches!(results[1], Err(Error::InvalidInput { .. })));
+        assert!(matches!(results[2], Err(Error::Aborted { .. })));
+        assert!(check_batch::<()>(vec![Ok(()), Ok(())]).is_ok());
+    }
+
+    #[test]
+    fn check_batch_rejects_oversized_batches() {
+        let results = check_batch::<()>(vec![Ok(()); MAX_BATCH_SIZE + 1]).unwrap_err();
+        assert_eq!(results.len(), MAX_BATCH_SIZE + 1);
+        assert!(results.iter().all(|result| matches!(result, Err(Error::InvalidInput { .. }))));
+    }
+}
@@ -0,0 +1,205 @@
+use crate::recommendations::{self, CacheKey, CachedRecommendations};
+use crate::{Error, Item, ITEM_STORAGE};
+use candid::{CandidType, Encode};
+use ic_certified_map::{AsHashTree, Hash, HashTree, RbTree};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+// label of the subtree holding item id -> sha256 of the candid encoded item
+const ITEMS: &str = "items";
+
+// label of the subtree holding "<recommendation system id>/<user id>" -> sha256 of the candid
+// encoded cached top-N list
+const RECOMMENDATIONS: &str = "recommendations";
+
+// an item together with the proof that it is part of the certified state
+#[derive(CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct CertifiedItem {
+    item: Item,
+    // certificate issued by the subnet, only available in query calls
+    certificate: Option<Vec<u8>>,
+    // CBOR encoded hash tree revealing the path ["items", "<id>"]
+    witness: Vec<u8>,
+}
+
+// a cached top-N list together with the proof that it is part of the certified state
+#[derive(CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct CertifiedRecommendations {
+    recommendations: CachedRecommendations,
+    certificate: Option<Vec<u8>>,
+    // CBOR encoded hash tree revealing the path ["recommendations", "<system id>/<user id>"]
+    witness: Vec<u8>,
+}
+
+thread_local! {
+    // label -> subtree, its root hash is the canister certified data;
+    // kept on the heap and rebuilt from stable memory after an upgrade
+    static TREE: RefCell<RbTree<&'static str, RbTree<String, Hash>>> = const { RefCell::new(RbTree::new()) };
+}
+
+fn leaf<T: CandidType>(value: &T) -> Hash {
+    Sha256::digest(Encode!(value).unwrap()).into()
+}
+
+fn modify_subtree(label: &'static str, f: impl FnOnce(&mut RbTree<String, Hash>)) {
+    TREE.with(|tree| {
+        let mut tree = tree.borrow_mut();
+        if tree.get(label.as_bytes()).is_none() {
+            tree.insert(label, RbTree::new());
+        }
+        tree.modify(label.as_bytes(), f);
+    });
+}
+
+fn insert_item(item: &Item) {
+    modify_subtree(ITEMS, |items| items.insert(item.id.to_string(), leaf(item)));
+}
+
+fn remove_item(id: u64) {
+    modify_subtree(ITEMS, |items| items.delete(id.to_string().as_bytes()));
+}
+
+fn recommendations_key(key: CacheKey) -> String {
+    format!("{}/{}", key.recommendation_system_id, key.user_id)
+}
+
+fn insert_recommendations(key: CacheKey, entry: &CachedRecommendations) {
+    modify_subtree(RECOMMENDATIONS, |lists| lists.insert(recommendations_key(key), leaf(entry)));
+}
+
+fn root_hash() -> Hash {
+    TREE.with(|tree| tree.borrow().root_hash())
+}
+
+fn witness(label: &'static str, key: &str) -> Vec<u8> {
+    TREE.with(|tree| {
+        let tree = tree.borrow();
+        let witness: HashTree = tree.nested_witness(label.as_bytes(), |subtree| subtree.witness(key.as_bytes()));
+        let mut serializer = serde_cbor::ser::Serializer::new(vec![]);
+        serializer.self_describe().unwrap();
+        witness.serialize(&mut serializer).unwrap();
+        serializer.into_inner()
+    })
+}
+
+// certify the current or updated version of an item
+pub(crate) fn certify_item(item: &Item) {
+    insert_item(item);
+    ic_cdk::api::set_certified_data(&root_hash());
+}
+
+// stop certifying an item that was deleted
+pub(crate) fn uncertify_item(id: u64) {
+    remove_item(id);
+    ic_cdk::api::set_certified_data(&root_hash());
+}
+
+// certify a new or refreshed top-N list
+pub(crate) fn certify_recommendations(key: CacheKey, entry: &CachedRecommendations) {
+    insert_recommendations(key, entry);
+    ic_cdk::api::set_certified_data(&root_hash());
+}
+
+// stop certifying a top-N list that was dropped from the cache
+pub(crate) fn uncertify_recommendations(key: CacheKey) {
+    modify_subtree(RECOMMENDATIONS, |lists| lists.delete(recommendations_key(key).as_bytes()));
+    ic_cdk::api::set_certified_data(&root_hash());
+}
+
+// rebuild the tree from the items and cached lists in stable memory and certify its root
+pub(crate) fn rebuild() {
+    TREE.with(|tree| *tree.borrow_mut() = RbTree::new());
+    ITEM_STORAGE.with(|service| {
+        for (_, item) in service.borrow().iter().filter(|(_, item)| item.deleted_at.is_none()) {
+            insert_item(&item);
+        }
+    });
+    for (key, entry) in recommendations::cached_entries() {
+        insert_recommendations(key, &entry);
+    }
+    ic_cdk::api::set_certified_data(&root_hash());
+}
+
+// function to get an item with a certificate and witness the caller can verify
+#[ic_cdk::query]
+fn get_certified_item(id: u64) -> Result<CertifiedItem, Error> {
+    let item = crate::get_item_by_id(id)?;
+    Ok(CertifiedItem {
+        witness: witness(ITEMS, &id.to_string()),
+        certificate: ic_cdk::api::data_certificate(),
+        item,
+    })
+}
+
+// function to get the cached top-N list of a user with a certificate and witness the caller can verify,
+// lists are cached by get_recommendations
+#[ic_cdk::query]
+fn get_certified_recommendations(recommendation_system_id: u64, user_id: u64) -> Result<CertifiedRecommendations, Error> {
+    let key = CacheKey { user_id, recommendation_system_id };
+    let recommendations = recommendations::cached(key).ok_or(Error::NotFound {
+        msg: format!(
+            "no cached recommendations for user with id={} in recommendation system with id={}",
+            user_id, recommendation_system_id
+        ),
+    })?;
+    Ok(CertifiedRecommendations {
+        witness: witness(RECOMMENDATIONS, &recommendations_key(key)),
+        certificate: ic_cdk::api::data_certificate(),
+        recommendations,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(id: u64, name: &str) -> Item {
+        Item { id, name: name.to_string(), ..Default::default() }
+    }
+
+    #[test]
+    fn root_hash_tracks_item_changes() {
+        let empty = root_hash();
+        insert_item(&item(1, "Dune"));
+        let with_dune = root_hash();
+        assert_ne!(empty, with_dune);
+
+        insert_item(&item(1, "Dune Messiah"));
+        assert_ne!(root_hash(), with_dune);
+
+        insert_item(&item(1, "Dune"));
+        assert_eq!(root_hash(), with_dune);
+        remove_item(1);
+        assert_ne!(root_hash(), with_dune);
+    }
+
+    #[test]
+    fn witness_reconstructs_to_root_hash() {
+        insert_item(&item(1, "Dune"));
+        insert_item(&item(2, "Emma"));
+
+        let bytes = witness(ITEMS, "2");
+        assert_eq!(&bytes[..3], &[0xd9, 0xd9, 0xf7]);
+        let tree = TREE.with(|tree| {
+            let tree = tree.borrow();
+            tree.nested_witness(ITEMS.as_bytes(), |subtree| subtree.witness(b"2")).reconstruct()
+        });
+        assert_eq!(tree, root_hash());
+    }
+
+    #[test]
+    fn subtrees_are_certified_together() {
+        insert_item(&item(1, "Dune"));
+        let items_only = root_hash();
+        let key = CacheKey { user_id: 3, recommendation_system_id: 1 };
+        insert_recommendations(key, &CachedRecommendations { recommendations: vec![], computed_at: 0 });
+        assert_ne!(root_hash(), items_only);
+
+        let tree = TREE.with(|tree| {
+            let tree = tree.borrow();
+            tree.nested_witness(RECOMMENDATIONS.as_bytes(), |subtree| subtree.witness(b"1/3")).reconstruct()
+        });
+        assert_eq!(tree, root_hash());
+    }
+}
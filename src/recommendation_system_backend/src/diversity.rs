@@ -0,0 +1,91 @@
+use crate::attributes;
+use crate::recommendations::{cosine, Ratings, Recommendation};
+use crate::Item;
+use std::collections::{HashMap, HashSet};
+
+// share of the item-item similarity that comes from the ratings, the rest from the category and tags
+const RATING_SIMILARITY_WEIGHT: f64 = 0.5;
+
+// similarity between two items from their ratings, categories and tags, between 0 and 1
+pub(crate) struct ItemSimilarity {
+    // item id -> user id -> rating
+    by_item: HashMap<u64, HashMap<u64, f64>>,
+    // item id -> tags
+    tags: HashMap<u64, HashSet<String>>,
+}
+
+impl ItemSimilarity {
+    pub(crate) fn new(ratings: &Ratings) -> Self {
+        ItemSimilarity { by_item: ratings.by_item(), tags: attributes::tags_of(ratings.items.iter().copied()) }
+    }
+
+    pub(crate) fn similarity(&self, a: &Item, b: &Item) -> f64 {
+        let ratings = cosine(self.by_item.get(&a.id), self.by_item.get(&b.id));
+        let category = if a.category.trim().eq_ignore_ascii_case(b.category.trim()) { 1.0 } else { 0.0 };
+        // items both tagged also compare by the Jaccard index of their tags
+        let content = match (self.tags.get(&a.id), self.tags.get(&b.id)) {
+            (Some(a), Some(b)) if !a.is_empty() && !b.is_empty() => {
+                (category + a.intersection(b).count() as f64 / a.union(b).count() as f64) / 2.0
+            }
+            _ => category,
+        };
+        RATING_SIMILARITY_WEIGHT * ratings + (1.0 - RATING_SIMILARITY_WEIGHT) * content
+    }
+}
+
+// Maximal Marginal Relevance: repeatedly pick the item with the best trade-off between its relevance
+// and its similarity to the items already picked; a diversity of 0 keeps the order by score
+pub(crate) fn mmr(recommendations: Vec<Recommendation>, diversity: f64, similarity: &ItemSimilarity) -> Vec<Recommendation> {
+    if diversity <= 0.0 {
+        return recommendations;
+    }
+    let max_score = recommendations.iter().map(|recommendation| recommendation.score).fold(0.0, f64::max);
+    let max_score = if max_score > 0.0 { max_score } else { 1.0 };
+
+    let mut remaining = recommendations;
+    let mut selected: Vec<Recommendation> = Vec::with_capacity(remaining.len());
+    while !remaining.is_empty() {
+        let mut best = 0;
+        let mut best_value = f64::NEG_INFINITY;
+        for (index, candidate) in remaining.iter().enumerate() {
+            let redundancy = selected
+                .iter()
+                .map(|chosen| similarity.similarity(&candidate.item, &chosen.item))
+                .fold(0.0, f64::max);
+            let value = (1.0 - diversity) * candidate.score / max_score - diversity * redundancy;
+            // ties keep the original order
+            if value > best_value {
+                best = index;
+                best_value = value;
+            }
+        }
+        selected.push(remaining.remove(best));
+    }
+    selected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    fn recommendation(id: u64, category: &str, score: f64) -> Recommendation {
+        Recommendation::new(Item { id, category: category.to_string(), ..Default::default() }, score)
+    }
+
+    #[test]
+    fn mmr_spreads_categories() {
+        let ratings = Ratings { items: BTreeSet::new(), by_user: HashMap::new() };
+        let similarity = ItemSimilarity::new(&ratings);
+        let candidates = vec![
+            recommendation(1, "Books", 1.0),
+            recommendation(2, "Books", 0.95),
+            recommendation(3, "Books", 0.9),
+            recommendation(4, "Music", 0.8),
+        ];
+
+        let ids = |list: &[Recommendation]| list.iter().map(|recommendation| recommendation.item.id).collect::<Vec<_>>();
+        assert_eq!(ids(&mmr(candidates.clone(), 0.0, &similarity)), vec![1, 2, 3, 4]);
+        assert_eq!(ids(&mmr(candidates.clone(), 0.5, &similarity)), vec![1, 4, 2, 3]);
+    }
+}
@@ -0,0 +1,402 @@
+use crate::recommendations::TOP_N;
+use crate::search::SearchResult;
+use crate::{
+    audit, ensure_controller, item_is_active, Error, Item, Memory, ITEM_STORAGE, MEMORY_MANAGER,
+    RECOMMENDATION_SYSTEM_STORAGE,
+};
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::collections::HashSet;
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_DIMENSIONS: usize = 1024;
+
+// embeddings uploaded per call
+const MAX_EMBEDDINGS_PER_CALL: usize = 500;
+
+// below this many embeddings an exact scan is as fast as the index
+const MIN_INDEXED_EMBEDDINGS: usize = 256;
+
+// k-means iterations when building the inverted file index
+const KMEANS_ITERATIONS: usize = 10;
+
+// clusters scanned per search at least, more are scanned until k results pass the filters
+const PROBED_CLUSTERS: usize = 4;
+
+#[derive(CandidType, Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq)]
+pub(crate) enum SimilarityMetric {
+    #[default]
+    Cosine,
+    DotProduct,
+}
+
+// dense vector of an item, trained outside the canister
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug)]
+pub(crate) struct ItemEmbedding {
+    item_id: u64,
+    vector: Vec<f32>,
+}
+
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug)]
+struct StoredEmbedding {
+    vector: Vec<f32>,
+    updated_at: u64,
+}
+
+impl Storable for StoredEmbedding {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for StoredEmbedding {
+    const MAX_SIZE: u32 = 5120;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct VectorSearchOptions {
+    // Cosine by default
+    metric: Option<SimilarityMetric>,
+    // when set only items of these categories are returned
+    categories: Option<Vec<String>>,
+}
+
+// inverted file index: the embeddings are clustered with k-means on their directions and a search
+// only scores the items of the clusters closest to the query
+struct IvfIndex {
+    centroids: Vec<Vec<f32>>,
+    lists: Vec<Vec<u64>>,
+}
+
+thread_local! {
+    // item id -> embedding
+    static EMBEDDINGS: RefCell<StableBTreeMap<u64, StoredEmbedding, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(35))))
+    );
+
+    // rebuilt by a timer after the embeddings change, searches scan every embedding meanwhile
+    static INDEX: RefCell<Option<IvfIndex>> = const { RefCell::new(None) };
+
+    static REBUILD_SCHEDULED: RefCell<bool> = const { RefCell::new(false) };
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f64 {
+    a.iter().zip(b).map(|(a, b)| *a as f64 * *b as f64).sum()
+}
+
+fn norm(vector: &[f32]) -> f64 {
+    dot(vector, vector).sqrt()
+}
+
+fn normalized(vector: &[f32]) -> Vec<f32> {
+    let norm = norm(vector);
+    vector.iter().map(|value| (*value as f64 / norm) as f32).collect()
+}
+
+fn similarity(metric: SimilarityMetric, query: &[f32], vector: &[f32]) -> f64 {
+    match metric {
+        SimilarityMetric::Cosine => dot(query, vector) / (norm(query) * norm(vector)),
+        SimilarityMetric::DotProduct => dot(query, vector),
+    }
+}
+
+fn nearest(centroids: &[Vec<f32>], vector: &[f32]) -> usize {
+    let mut best = 0;
+    let mut best_similarity = f64::NEG_INFINITY;
+    for (index, centroid) in centroids.iter().enumerate() {
+        let similarity = dot(centroid, vector);
+        if similarity > best_similarity {
+            best = index;
+            best_similarity = similarity;
+        }
+    }
+    best
+}
+
+impl IvfIndex {
+    // spherical k-means with sqrt(n) clusters, seeded with evenly spaced embeddings so builds are repeatable
+    fn build(embeddings: &[(u64, Vec<f32>)]) -> Self {
+        let directions: Vec<Vec<f32>> = embeddings.iter().map(|(_, vector)| normalized(vector)).collect();
+        let clusters = (directions.len() as f64).sqrt().ceil().max(1.0) as usize;
+        let mut centroids: Vec<Vec<f32>> =
+            (0..clusters).map(|index| directions[index * directions.len() / clusters].clone()).collect();
+        let mut assignments = vec![0; directions.len()];
+        for _ in 0..KMEANS_ITERATIONS {
+            for (assignment, direction) in assignments.iter_mut().zip(&directions) {
+                *assignment = nearest(&centroids, direction);
+            }
+            let dimensions = centroids[0].len();
+            let mut sums = vec![vec![0.0f32; dimensions]; clusters];
+            for (assignment, direction) in assignments.iter().zip(&directions) {
+                sums[*assignment].iter_mut().zip(direction).for_each(|(sum, value)| *sum += value);
+            }
+            for (centroid, sum) in centroids.iter_mut().zip(sums) {
+                if norm(&sum) > 0.0 {
+                    *centroid = normalized(&sum);
+                }
+            }
+        }
+        let mut lists = vec![vec![]; clusters];
+        for (assignment, (item_id, _)) in assignments.iter().zip(embeddings) {
+            lists[*assignment].push(*item_id);
+        }
+        IvfIndex { centroids, lists }
+    }
+
+    // clusters ordered by closeness of their centroid to the query
+    fn probe_order(&self, query: &[f32]) -> Vec<usize> {
+        let direction = normalized(query);
+        let mut order: Vec<(usize, f64)> =
+            self.centroids.iter().enumerate().map(|(index, centroid)| (index, dot(centroid, &direction))).collect();
+        order.sort_by(|(a, a_similarity), (b, b_similarity)| b_similarity.total_cmp(a_similarity).then(a.cmp(b)));
+        order.into_iter().map(|(index, _)| index).collect()
+    }
+}
+
+fn all_embeddings() -> Vec<(u64, Vec<f32>)> {
+    EMBEDDINGS.with(|m| m.borrow().iter().map(|(item_id, embedding)| (item_id, embedding.vector)).collect())
+}
+
+// clear the flag in its own message so that a build that traps is scheduled again by the next change
+fn start_rebuild() {
+    REBUILD_SCHEDULED.with(|scheduled| *scheduled.borrow_mut() = false);
+    ic_cdk_timers::set_timer(Duration::ZERO, rebuild_index);
+}
+
+fn rebuild_index() {
+    let embeddings = all_embeddings();
+    let index = (embeddings.len() >= MIN_INDEXED_EMBEDDINGS).then(|| IvfIndex::build(&embeddings));
+    INDEX.with(|cell| *cell.borrow_mut() = index);
+}
+
+// drop the index and rebuild it in a later message, once per batch of changes
+pub(crate) fn schedule_rebuild() {
+    INDEX.with(|cell| *cell.borrow_mut() = None);
+    let already_scheduled = REBUILD_SCHEDULED.with(|scheduled| scheduled.replace(true));
+    if !already_scheduled {
+        ic_cdk_timers::set_timer(Duration::ZERO, start_rebuild);
+    }
+}
+
+// drop the embedding of a purged item
+pub(crate) fn remove_item(item_id: u64) {
+    if EMBEDDINGS.with(|m| m.borrow_mut().remove(&item_id)).is_some() {
+        schedule_rebuild();
+    }
+}
+
+// dimensions of the stored embeddings, all embeddings have the same
+fn dimensions() -> Option<usize> {
+    EMBEDDINGS.with(|m| m.borrow().iter().next().map(|(_, embedding)| embedding.vector.len()))
+}
+
+fn validate_vector(vector: &[f32], dimensions: Option<usize>) -> Result<(), Error> {
+    if vector.is_empty() || vector.len() > MAX_DIMENSIONS {
+        return Err(Error::InvalidInput { msg: format!("vectors must have between 1 and {} dimensions", MAX_DIMENSIONS) });
+    }
+    if let Some(dimensions) = dimensions.filter(|dimensions| *dimensions != vector.len()) {
+        return Err(Error::InvalidInput {
+            msg: format!("vectors must have {} dimensions, got {}", dimensions, vector.len()),
+        });
+    }
+    if vector.iter().any(|value| !value.is_finite()) || norm(vector) == 0.0 {
+        return Err(Error::InvalidInput { msg: "vectors must be finite and not zero".to_string() });
+    }
+    Ok(())
+}
+
+// the k items with the highest similarity to the query among the allowed ones
+fn search(
+    query: &[f32],
+    k: usize,
+    metric: SimilarityMetric,
+    allowed: impl Fn(u64) -> Option<Item>,
+    index: Option<&IvfIndex>,
+) -> Vec<(Item, f64)> {
+    let mut scored: Vec<(Item, f64)> = vec![];
+    match index {
+        Some(index) => {
+            for (probed, cluster) in index.probe_order(query).into_iter().enumerate() {
+                if probed >= PROBED_CLUSTERS && scored.len() >= k {
+                    break;
+                }
+                for &item_id in &index.lists[cluster] {
+                    let Some(item) = allowed(item_id) else {
+                        continue;
+                    };
+                    if let Some(embedding) = EMBEDDINGS.with(|m| m.borrow().get(&item_id)) {
+                        scored.push((item, similarity(metric, query, &embedding.vector)));
+                    }
+                }
+            }
+        }
+        None => EMBEDDINGS.with(|m| {
+            for (item_id, embedding) in m.borrow().iter() {
+                if let Some(item) = allowed(item_id) {
+                    scored.push((item, similarity(metric, query, &embedding.vector)));
+                }
+            }
+        }),
+    }
+    scored.sort_by(|(a, a_score), (b, b_score)| b_score.total_cmp(a_score).then(a.id.cmp(&b.id)));
+    scored.truncate(k);
+    scored
+}
+
+// function to store the embeddings of items, replacing previous ones, restricted to controllers;
+// every embedding must have the dimensions of the stored ones
+#[ic_cdk::update]
+fn set_item_embeddings(embeddings: Vec<ItemEmbedding>) -> Result<u64, Error> {
+    ensure_controller("upload embeddings")?;
+    let stored = store_embeddings(ic_cdk::caller(), embeddings, time())?;
+    schedule_rebuild();
+    Ok(stored)
+}
+
+// validate and store a batch of embeddings, with one audit entry per embedding
+fn store_embeddings(caller: Principal, embeddings: Vec<ItemEmbedding>, now: u64) -> Result<u64, Error> {
+    if embeddings.len() > MAX_EMBEDDINGS_PER_CALL {
+        return Err(Error::InvalidInput { msg: format!("at most {} embeddings per call", MAX_EMBEDDINGS_PER_CALL) });
+    }
+    // a fresh upload can change the dimensions when it replaces every stored embedding
+    let replaced: HashSet<u64> = embeddings.iter().map(|embedding| embedding.item_id).collect();
+    let dimensions = if EMBEDDINGS.with(|m| m.borrow().iter().all(|(item_id, _)| replaced.contains(&item_id))) {
+        embeddings.first().map(|embedding| embedding.vector.len())
+    } else {
+        dimensions()
+    };
+    for embedding in &embeddings {
+        if !item_is_active(embedding.item_id) {
+            return Err(Error::NotFound { msg: format!("item with id={} not found", embedding.item_id) });
+        }
+        validate_vector(&embedding.vector, dimensions)?;
+    }
+
+    for embedding in &embeddings {
+        let stored = StoredEmbedding { vector: embedding.vector.clone(), updated_at: now };
+        let before = EMBEDDINGS.with(|m| m.borrow_mut().insert(embedding.item_id, stored.clone()));
+        audit::record_at(caller, "set_item_embeddings", vec![embedding.item_id], before.as_ref(), Some(&stored), now);
+    }
+    Ok(embeddings.len() as u64)
+}
+
+// function to get the embedding of an item
+#[ic_cdk::query]
+fn get_item_embedding(item_id: u64) -> Result<Vec<f32>, Error> {
+    EMBEDDINGS
+        .with(|m| m.borrow().get(&item_id))
+        .map(|embedding| embedding.vector)
+        .ok_or(Error::NotFound { msg: format!("embedding of item with id={} not found", item_id) })
+}
+
+// function to delete the embedding of an item, restricted to controllers
+#[ic_cdk::update]
+fn delete_item_embedding(item_id: u64) -> Result<(), Error> {
+    ensure_controller("delete embeddings")?;
+    let removed = EMBEDDINGS
+        .with(|m| m.borrow_mut().remove(&item_id))
+        .ok_or(Error::NotFound { msg: format!("embedding of item with id={} not found", item_id) })?;
+    schedule_rebuild();
+    audit::record("delete_item_embedding", vec![item_id], Some(&removed.vector), None);
+    Ok(())
+}
+
+// function to find the k items of a recommendation system whose embeddings are the most similar to a vector
+#[ic_cdk::query]
+fn search_similar_by_vector(
+    recommendation_system_id: u64,
+    vector: Vec<f32>,
+    k: u64,
+    options: VectorSearchOptions,
+) -> Result<Vec<SearchResult>, Error> {
+    let recommendation_system = RECOMMENDATION_SYSTEM_STORAGE
+        .with(|service| service.borrow().get(&recommendation_system_id))
+        .filter(|record| record.deleted_at.is_none())
+        .ok_or(Error::NotFound {
+            msg: format!("recommendation system with id={} not found", recommendation_system_id),
+        })?;
+    validate_vector(&vector, dimensions())?;
+
+    let item_ids: HashSet<u64> = recommendation_system.items.iter().map(|item| item.id).collect();
+    let categories: Option<HashSet<String>> = options
+        .categories
+        .map(|categories| categories.iter().map(|category| category.trim().to_lowercase()).collect());
+    let allowed = |item_id: u64| {
+        if !item_ids.contains(&item_id) {
+            return None;
+        }
+        ITEM_STORAGE.with(|m| m.borrow().get(&item_id)).filter(|item| {
+            item.deleted_at.is_none()
+                && categories.as_ref().is_none_or(|categories| categories.contains(&item.category.trim().to_lowercase()))
+        })
+    };
+    let k = k.clamp(1, TOP_N as u64) as usize;
+    let metric = options.metric.unwrap_or_default();
+    let results = INDEX.with(|cell| search(&vector, k, metric, allowed, cell.borrow().as_ref()));
+    Ok(results.into_iter().map(|(item, score)| SearchResult::new(item, score)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(item_id: u64, vector: Vec<f32>) {
+        let item = Item { id: item_id, ..Default::default() };
+        ITEM_STORAGE.with(|m| m.borrow_mut().insert(item_id, item));
+        EMBEDDINGS.with(|m| m.borrow_mut().insert(item_id, StoredEmbedding { vector, updated_at: 0 }));
+    }
+
+    #[test]
+    fn index_matches_exact_search() {
+        // points on a circle, every 7 degrees
+        for id in 0..300u64 {
+            let angle = (id * 7 % 360) as f32 * std::f32::consts::PI / 180.0;
+            store(id, vec![angle.cos(), angle.sin(), 0.5]);
+        }
+        let index = IvfIndex::build(&all_embeddings());
+        assert_eq!(index.lists.iter().map(|list| list.len()).sum::<usize>(), 300);
+
+        let query = [1.0, 0.1, 0.5];
+        let allowed = |item_id: u64| ITEM_STORAGE.with(|m| m.borrow().get(&item_id));
+        let exact: Vec<u64> = search(&query, 5, SimilarityMetric::Cosine, allowed, None).iter().map(|(item, _)| item.id).collect();
+        let approximate: Vec<u64> =
+            search(&query, 5, SimilarityMetric::Cosine, allowed, Some(&index)).iter().map(|(item, _)| item.id).collect();
+        assert_eq!(exact, approximate);
+
+        let odd = |item_id: u64| allowed(item_id).filter(|item| item.id % 2 == 1);
+        assert!(search(&query, 5, SimilarityMetric::DotProduct, odd, Some(&index)).iter().all(|(item, _)| item.id % 2 == 1));
+    }
+
+    #[test]
+    fn vectors_are_validated() {
+        assert!(validate_vector(&[1.0, 0.0], Some(2)).is_ok());
+        assert!(validate_vector(&[1.0, 0.0], Some(3)).is_err());
+        assert!(validate_vector(&[0.0, 0.0], None).is_err());
+        assert!(validate_vector(&[f32::NAN], None).is_err());
+        assert!(validate_vector(&[], None).is_err());
+    }
+
+    #[test]
+    fn a_full_batch_of_embeddings_is_stored() {
+        let embeddings: Vec<ItemEmbedding> = (0..MAX_EMBEDDINGS_PER_CALL as u64)
+            .map(|item_id| {
+                ITEM_STORAGE.with(|m| m.borrow_mut().insert(item_id, Item { id: item_id, ..Default::default() }));
+                ItemEmbedding { item_id, vector: vec![1.0, item_id as f32] }
+            })
+            .collect();
+        let stored = store_embeddings(Principal::anonymous(), embeddings.clone(), 0).unwrap();
+        assert_eq!(stored, MAX_EMBEDDINGS_PER_CALL as u64);
+        assert_eq!(EMBEDDINGS.with(|m| m.borrow().len()), MAX_EMBEDDINGS_PER_CALL as u64);
+
+        let too_many = [embeddings, vec![ItemEmbedding { item_id: 0, vector: vec![1.0, 0.0] }]].concat();
+        assert!(store_embeddings(Principal::anonymous(), too_many, 0).is_err());
+    }
+}
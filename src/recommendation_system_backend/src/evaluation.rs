@@ -0,0 +1,133 @@
+use crate::diversity::ItemSimilarity;
+use crate::recommendations::{compute, finish, hydrate, load_ratings, Ratings, Recommendation, RecommendationRequest};
+use crate::settings::Algorithm;
+use crate::{ensure_controller, settings, Error, RECOMMENDATION_SYSTEM_STORAGE};
+use candid::CandidType;
+use std::collections::HashSet;
+
+// number of users whose lists are evaluated by one call
+const MAX_EVALUATED_USERS: usize = 100;
+
+// length of the evaluated lists
+const EVALUATED_LIST_SIZE: usize = 10;
+
+// metrics of the recommendation lists of a recommendation system, averaged over its users
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug, Default)]
+pub(crate) struct EvaluationReport {
+    users: u64,
+    // mean dissimilarity between the items of a list, from 0 to 1
+    intra_list_diversity: f64,
+    // distinct categories per item of a list, from 0 to 1
+    category_coverage: f64,
+    // mean self-information of the recommended items, higher for less rated items
+    novelty: f64,
+}
+
+pub(crate) fn intra_list_diversity(list: &[Recommendation], similarity: &ItemSimilarity) -> f64 {
+    let mut total = 0.0;
+    let mut pairs = 0.0;
+    for (index, a) in list.iter().enumerate() {
+        for b in &list[index + 1..] {
+            total += 1.0 - similarity.similarity(&a.item, &b.item);
+            pairs += 1.0;
+        }
+    }
+    if pairs > 0.0 {
+        total / pairs
+    } else {
+        0.0
+    }
+}
+
+pub(crate) fn category_coverage(list: &[Recommendation]) -> f64 {
+    let categories: HashSet<String> =
+        list.iter().map(|recommendation| recommendation.item.category.trim().to_lowercase()).collect();
+    categories.len() as f64 / list.len().max(1) as f64
+}
+
+// -log2 of the smoothed share of users who rated each item
+pub(crate) fn novelty(list: &[Recommendation], ratings: &Ratings) -> f64 {
+    let users = ratings.by_user.len() as f64;
+    let total: f64 = list
+        .iter()
+        .map(|recommendation| {
+            let raters = ratings.by_user.values().filter(|user_ratings| user_ratings.contains_key(&recommendation.item.id)).count();
+            -((raters as f64 + 1.0) / (users + 1.0)).log2()
+        })
+        .sum();
+    total / list.len().max(1) as f64
+}
+
+// function to report diversity metrics of the recommendations of a recommendation system, restricted to controllers;
+// diversity and algorithm override the system settings to compare trade-offs
+#[ic_cdk::query]
+fn evaluate_recommendations(
+    recommendation_system_id: u64,
+    diversity: Option<f64>,
+    algorithm: Option<Algorithm>,
+) -> Result<EvaluationReport, Error> {
+    ensure_controller("evaluate recommendations")?;
+    settings::validate_diversity(diversity)?;
+    let recommendation_system = RECOMMENDATION_SYSTEM_STORAGE
+        .with(|service| service.borrow().get(&recommendation_system_id))
+        .filter(|record| record.deleted_at.is_none())
+        .ok_or(Error::NotFound {
+            msg: format!("recommendation system with id={} not found", recommendation_system_id),
+        })?;
+
+    let ratings = load_ratings(&recommendation_system);
+    let similarity = ItemSimilarity::new(&ratings);
+    let mut user_ids: Vec<u64> = ratings.by_user.keys().copied().collect();
+    user_ids.sort();
+    user_ids.truncate(MAX_EVALUATED_USERS);
+
+    let mut settings = settings::settings_of(recommendation_system_id);
+    settings.algorithm = algorithm.or(settings.algorithm);
+    let mut report = EvaluationReport::default();
+    for (index, &user_id) in user_ids.iter().enumerate() {
+        let request = RecommendationRequest { recommendation_system_id, user_id, diversity, ..Default::default() };
+        let candidates = hydrate(&compute(&recommendation_system, &settings, user_id, index == 0));
+        let list = finish(&recommendation_system, &settings, &request, candidates, EVALUATED_LIST_SIZE);
+        report.intra_list_diversity += intra_list_diversity(&list, &similarity);
+        report.category_coverage += category_coverage(&list);
+        report.novelty += novelty(&list, &ratings);
+        report.users += 1;
+    }
+    if report.users > 0 {
+        let users = report.users as f64;
+        report.intra_list_diversity /= users;
+        report.category_coverage /= users;
+        report.novelty /= users;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Item;
+    use std::collections::{BTreeSet, HashMap};
+
+    fn recommendation(id: u64, category: &str) -> Recommendation {
+        Recommendation::new(Item { id, category: category.to_string(), ..Default::default() }, 1.0)
+    }
+
+    #[test]
+    fn diversity_metrics() {
+        let mut ratings = Ratings { items: BTreeSet::new(), by_user: HashMap::new() };
+        ratings.by_user.insert(1, HashMap::from([(1, 5.0)]));
+        ratings.by_user.insert(2, HashMap::from([(1, 4.0)]));
+        ratings.by_user.insert(3, HashMap::from([(2, 4.0)]));
+        let similarity = ItemSimilarity::new(&ratings);
+
+        let same = vec![recommendation(1, "Books"), recommendation(3, "Books")];
+        let mixed = vec![recommendation(1, "Books"), recommendation(2, "Music")];
+        assert_eq!(intra_list_diversity(&same, &similarity), 0.5);
+        assert_eq!(intra_list_diversity(&mixed, &similarity), 1.0);
+        assert_eq!(category_coverage(&same), 0.5);
+        assert_eq!(category_coverage(&mixed), 1.0);
+        // item 1 was rated by 2 of 3 users, item 3 by nobody
+        assert!((novelty(&[recommendation(1, "Books")], &ratings) - 0.415).abs() < 1e-3);
+        assert_eq!(novelty(&[recommendation(3, "Books")], &ratings), 2.0);
+    }
+}
@@ -0,0 +1,451 @@
+use crate::impressions;
+use crate::settings::RecommendationSettings;
+use crate::{
+    audit, ensure_controller, Error, Memory, MEMORY_MANAGER, RECOMMENDATION_SYSTEM_STORAGE,
+    USER_PREFERENCE_STORAGE,
+};
+use candid::{CandidType, Decode, Encode};
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_VARIANTS: usize = 8;
+
+// a recommender configuration under test, weight is its share of the experiment's traffic
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug)]
+pub(crate) struct Variant {
+    name: String,
+    weight: u64,
+    settings: RecommendationSettings,
+}
+
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug)]
+pub(crate) struct ExperimentPayload {
+    recommendation_system_id: u64,
+    name: String,
+    // the first variant is the control the others are compared to
+    variants: Vec<Variant>,
+    // scheduled start and stop, in nanoseconds since the epoch
+    starts_at: Option<u64>,
+    ends_at: Option<u64>,
+}
+
+// an A/B test of recommender configurations, running from starts_at until ends_at;
+// users of the recommendation system are split between the variants while it runs
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug)]
+pub(crate) struct Experiment {
+    id: u64,
+    recommendation_system_id: u64,
+    name: String,
+    variants: Vec<Variant>,
+    starts_at: Option<u64>,
+    ends_at: Option<u64>,
+    created_at: u64,
+}
+
+impl Storable for Experiment {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Experiment {
+    const MAX_SIZE: u32 = 16384;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Experiment {
+    fn is_running(&self, now: u64) -> bool {
+        self.starts_at.is_some_and(|starts_at| starts_at <= now) && self.ends_at.is_none_or(|ends_at| now < ends_at)
+    }
+
+    fn has_ended(&self, now: u64) -> bool {
+        self.ends_at.is_some_and(|ends_at| ends_at <= now)
+    }
+
+    // whether both experiments are scheduled and their periods intersect
+    fn overlaps(&self, other: &Experiment) -> bool {
+        match (self.starts_at, other.starts_at) {
+            (Some(start), Some(other_start)) => {
+                start < other.ends_at.unwrap_or(u64::MAX) && other_start < self.ends_at.unwrap_or(u64::MAX)
+            }
+            _ => false,
+        }
+    }
+}
+
+// variant a user is assigned to
+pub(crate) struct Assignment {
+    pub(crate) experiment_id: u64,
+    pub(crate) variant: String,
+    pub(crate) settings: RecommendationSettings,
+}
+
+// metrics of a variant; rates are per shown item and p-values compare the variant to the control
+// with a two-sided two-proportion z-test, unset for the control itself
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug, Default)]
+pub(crate) struct VariantReport {
+    name: String,
+    users: u64,
+    impressions: u64,
+    items_shown: u64,
+    clicks: u64,
+    conversions: u64,
+    click_through_rate: f64,
+    conversion_rate: f64,
+    // mean explicit rating the users gave to the items recommended to them
+    average_rating: Option<f64>,
+    rated_items: u64,
+    click_through_p_value: Option<f64>,
+    conversion_p_value: Option<f64>,
+}
+
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug)]
+pub(crate) struct ExperimentReport {
+    experiment: Experiment,
+    variants: Vec<VariantReport>,
+}
+
+thread_local! {
+    static EXPERIMENT_ID_COUNTER: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static EXPERIMENTS: RefCell<StableBTreeMap<u64, Experiment, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29))))
+    );
+}
+
+// index of the variant of a user, from a hash of the experiment and user ids so it never changes
+fn variant_index(experiment_id: u64, user_id: u64, variants: &[Variant]) -> usize {
+    let mut hasher = Sha256::new();
+    hasher.update(experiment_id.to_be_bytes());
+    hasher.update(user_id.to_be_bytes());
+    let digest = hasher.finalize();
+    let total: u64 = variants.iter().map(|variant| variant.weight).sum();
+    let mut bucket = u64::from_be_bytes(digest[..8].try_into().unwrap()) % total;
+    for (index, variant) in variants.iter().enumerate() {
+        if bucket < variant.weight {
+            return index;
+        }
+        bucket -= variant.weight;
+    }
+    variants.len() - 1
+}
+
+// the variant of a user in the running experiment of a recommendation system, if any
+pub(crate) fn assign(recommendation_system_id: u64, user_id: u64, now: u64) -> Option<Assignment> {
+    let experiment = EXPERIMENTS.with(|m| {
+        m.borrow()
+            .iter()
+            .map(|(_, experiment)| experiment)
+            .find(|experiment| experiment.recommendation_system_id == recommendation_system_id && experiment.is_running(now))
+    })?;
+    let variant = &experiment.variants[variant_index(experiment.id, user_id, &experiment.variants)];
+    Some(Assignment { experiment_id: experiment.id, variant: variant.name.clone(), settings: variant.settings.clone() })
+}
+
+fn normal_cdf(z: f64) -> f64 {
+    // Abramowitz and Stegun 7.1.26
+    let x = z.abs() / std::f64::consts::SQRT_2;
+    let t = 1.0 / (1.0 + 0.327_591_1 * x);
+    let polynomial = t * (0.254_829_592 + t * (-0.284_496_736 + t * (1.421_413_741 + t * (-1.453_152_027 + t * 1.061_405_429))));
+    let erf = 1.0 - polynomial * (-x * x).exp();
+    if z >= 0.0 {
+        0.5 * (1.0 + erf)
+    } else {
+        0.5 * (1.0 - erf)
+    }
+}
+
+// two-sided p-value of the difference between two proportions, none without data
+fn two_proportion_p_value(successes: u64, trials: u64, other_successes: u64, other_trials: u64) -> Option<f64> {
+    if trials == 0 || other_trials == 0 {
+        return None;
+    }
+    let (n1, n2) = (trials as f64, other_trials as f64);
+    let (p1, p2) = (successes as f64 / n1, other_successes as f64 / n2);
+    let pooled = (successes + other_successes) as f64 / (n1 + n2);
+    let standard_error = (pooled * (1.0 - pooled) * (1.0 / n1 + 1.0 / n2)).sqrt();
+    if standard_error == 0.0 {
+        return Some(1.0);
+    }
+    let z = (p1 - p2) / standard_error;
+    Some(2.0 * (1.0 - normal_cdf(z.abs())))
+}
+
+fn rate(count: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        count as f64 / total as f64
+    }
+}
+
+// aggregate the impressions of an experiment per variant, ratings maps (user id, item id) to explicit ratings
+fn report(experiment: &Experiment, impressions: &[impressions::Impression], ratings: &HashMap<(u64, u64), f64>) -> Vec<VariantReport> {
+    let mut reports: Vec<VariantReport> = experiment
+        .variants
+        .iter()
+        .map(|variant| VariantReport { name: variant.name.clone(), ..Default::default() })
+        .collect();
+    let mut users: Vec<HashSet<u64>> = vec![HashSet::new(); reports.len()];
+    let mut rating_sums = vec![0.0; reports.len()];
+    for impression in impressions {
+        let Some(index) = reports.iter().position(|report| Some(&report.name) == impression.variant.as_ref()) else {
+            continue;
+        };
+        let report = &mut reports[index];
+        users[index].insert(impression.user_id);
+        report.impressions += 1;
+        report.items_shown += impression.item_ids.len() as u64;
+        report.clicks += impression.clicked_item_ids.as_ref().map_or(0, |ids| ids.len() as u64);
+        report.conversions += impression.converted_item_ids.as_ref().map_or(0, |ids| ids.len() as u64);
+        for item_id in &impression.item_ids {
+            if let Some(rating) = ratings.get(&(impression.user_id, *item_id)) {
+                rating_sums[index] += rating;
+                report.rated_items += 1;
+            }
+        }
+    }
+
+    let control = reports.first().cloned().unwrap_or_default();
+    for (index, report) in reports.iter_mut().enumerate() {
+        report.users = users[index].len() as u64;
+        report.click_through_rate = rate(report.clicks, report.items_shown);
+        report.conversion_rate = rate(report.conversions, report.items_shown);
+        report.average_rating = (report.rated_items > 0).then(|| rating_sums[index] / report.rated_items as f64);
+        if index > 0 {
+            report.click_through_p_value =
+                two_proportion_p_value(report.clicks, report.items_shown, control.clicks, control.items_shown);
+            report.conversion_p_value =
+                two_proportion_p_value(report.conversions, report.items_shown, control.conversions, control.items_shown);
+        }
+    }
+    reports
+}
+
+fn validate(payload: &ExperimentPayload) -> Result<(), Error> {
+    RECOMMENDATION_SYSTEM_STORAGE
+        .with(|service| service.borrow().get(&payload.recommendation_system_id))
+        .filter(|record| record.deleted_at.is_none())
+        .ok_or(Error::NotFound {
+            msg: format!("recommendation system with id={} not found", payload.recommendation_system_id),
+        })?;
+    if payload.name.trim().is_empty() {
+        return Err(Error::InvalidInput { msg: "experiment name is required".to_string() });
+    }
+    if !(2..=MAX_VARIANTS).contains(&payload.variants.len()) {
+        return Err(Error::InvalidInput { msg: format!("an experiment needs between 2 and {} variants", MAX_VARIANTS) });
+    }
+    let mut names = HashSet::new();
+    for variant in &payload.variants {
+        if variant.name.trim().is_empty() || !names.insert(variant.name.as_str()) {
+            return Err(Error::InvalidInput { msg: "variant names must be unique and not empty".to_string() });
+        }
+        if variant.weight == 0 {
+            return Err(Error::InvalidInput { msg: format!("weight of variant {} must be positive", variant.name) });
+        }
+        variant.settings.validate()?;
+    }
+    if let (Some(starts_at), Some(ends_at)) = (payload.starts_at, payload.ends_at) {
+        if ends_at <= starts_at {
+            return Err(Error::InvalidInput { msg: "ends_at must be after starts_at".to_string() });
+        }
+    }
+    Ok(())
+}
+
+// only one experiment of a recommendation system may run at a time
+fn ensure_no_overlap(experiment: &Experiment) -> Result<(), Error> {
+    let overlapping = EXPERIMENTS.with(|m| {
+        m.borrow().iter().map(|(_, other)| other).find(|other| {
+            other.id != experiment.id
+                && other.recommendation_system_id == experiment.recommendation_system_id
+                && other.overlaps(experiment)
+        })
+    });
+    match overlapping {
+        Some(other) => Err(Error::Conflict {
+            msg: format!("experiment with id={} runs at the same time on this recommendation system", other.id),
+        }),
+        None => Ok(()),
+    }
+}
+
+fn experiment_by_id(id: u64) -> Result<Experiment, Error> {
+    EXPERIMENTS
+        .with(|m| m.borrow().get(&id))
+        .ok_or(Error::NotFound { msg: format!("experiment with id={} not found", id) })
+}
+
+fn save(method: &str, before: Option<&Experiment>, experiment: Experiment) -> Result<Experiment, Error> {
+    if Encode!(&experiment).unwrap().len() > Experiment::MAX_SIZE as usize {
+        return Err(Error::InvalidInput { msg: "experiment is too large".to_string() });
+    }
+    EXPERIMENTS.with(|m| m.borrow_mut().insert(experiment.id, experiment.clone()));
+    audit::record(method, vec![experiment.id], before, Some(&experiment));
+    Ok(experiment)
+}
+
+// function to create an experiment, restricted to controllers
+#[ic_cdk::update]
+fn create_experiment(payload: ExperimentPayload) -> Result<Experiment, Error> {
+    ensure_controller("create experiments")?;
+    validate(&payload)?;
+    let id = EXPERIMENT_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment id counter");
+    let experiment = Experiment {
+        id,
+        recommendation_system_id: payload.recommendation_system_id,
+        name: payload.name,
+        variants: payload.variants,
+        starts_at: payload.starts_at,
+        ends_at: payload.ends_at,
+        created_at: time(),
+    };
+    ensure_no_overlap(&experiment)?;
+    save("create_experiment", None, experiment)
+}
+
+// function to start an experiment now, restricted to controllers
+#[ic_cdk::update]
+fn start_experiment(id: u64) -> Result<Experiment, Error> {
+    ensure_controller("start experiments")?;
+    let now = time();
+    let before = experiment_by_id(id)?;
+    if before.is_running(now) || before.has_ended(now) {
+        return Err(Error::Conflict { msg: format!("experiment with id={} already started", id) });
+    }
+    let experiment = Experiment { starts_at: Some(now), ..before.clone() };
+    ensure_no_overlap(&experiment)?;
+    save("start_experiment", Some(&before), experiment)
+}
+
+// function to stop an experiment now, restricted to controllers
+#[ic_cdk::update]
+fn stop_experiment(id: u64) -> Result<Experiment, Error> {
+    ensure_controller("stop experiments")?;
+    let now = time();
+    let before = experiment_by_id(id)?;
+    if before.has_ended(now) {
+        return Err(Error::Conflict { msg: format!("experiment with id={} already ended", id) });
+    }
+    let experiment = Experiment { ends_at: Some(now), ..before.clone() };
+    save("stop_experiment", Some(&before), experiment)
+}
+
+// function to get an experiment by id, restricted to controllers
+#[ic_cdk::query]
+fn get_experiment(id: u64) -> Result<Experiment, Error> {
+    ensure_controller("read experiments")?;
+    experiment_by_id(id)
+}
+
+// function to get the experiments of a recommendation system, restricted to controllers
+#[ic_cdk::query]
+fn get_experiments(recommendation_system_id: u64) -> Result<Vec<Experiment>, Error> {
+    ensure_controller("read experiments")?;
+    Ok(EXPERIMENTS.with(|m| {
+        m.borrow()
+            .iter()
+            .map(|(_, experiment)| experiment)
+            .filter(|experiment| experiment.recommendation_system_id == recommendation_system_id)
+            .collect()
+    }))
+}
+
+// function to report the metrics of every variant of an experiment, restricted to controllers
+#[ic_cdk::query]
+fn get_experiment_report(id: u64) -> Result<ExperimentReport, Error> {
+    ensure_controller("read experiments")?;
+    let experiment = experiment_by_id(id)?;
+    let ratings: HashMap<(u64, u64), f64> = USER_PREFERENCE_STORAGE.with(|m| {
+        m.borrow()
+            .iter()
+            .map(|(_, preference)| preference)
+            .filter(|preference| preference.deleted_at.is_none())
+            .map(|preference| ((preference.user_id, preference.item_id), preference.rating as f64))
+            .collect()
+    });
+    let variants = report(&experiment, &impressions::of_experiment(id), &ratings);
+    Ok(ExperimentReport { experiment, variants })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::impressions::Impression;
+
+    fn variant(name: &str, weight: u64) -> Variant {
+        Variant { name: name.to_string(), weight, settings: RecommendationSettings::default() }
+    }
+
+    fn experiment(starts_at: Option<u64>, ends_at: Option<u64>) -> Experiment {
+        Experiment {
+            id: 1,
+            recommendation_system_id: 1,
+            name: "test".to_string(),
+            variants: vec![variant("control", 3), variant("treatment", 1)],
+            starts_at,
+            ends_at,
+            created_at: 0,
+        }
+    }
+
+    #[test]
+    fn assignment_is_stable_and_follows_the_weights() {
+        let variants = experiment(None, None).variants;
+        let counts = (0..4000).fold([0; 2], |mut counts, user_id| {
+            let index = variant_index(1, user_id, &variants);
+            assert_eq!(index, variant_index(1, user_id, &variants));
+            counts[index] += 1;
+            counts
+        });
+        assert!((2800..3200).contains(&counts[0]), "{:?}", counts);
+
+        assert!(experiment(Some(10), Some(20)).is_running(10));
+        assert!(!experiment(Some(10), Some(20)).is_running(20));
+        assert!(!experiment(None, None).is_running(10));
+        assert!(experiment(Some(10), None).overlaps(&experiment(Some(5), Some(11))));
+        assert!(!experiment(Some(10), None).overlaps(&experiment(Some(5), Some(10))));
+    }
+
+    #[test]
+    fn reports_compare_variants_to_the_control() {
+        let impression = |user_id, variant: &str, clicks: usize| Impression {
+            user_id,
+            item_ids: (1..=10).collect(),
+            clicked_item_ids: Some((1..=clicks as u64).collect()),
+            variant: Some(variant.to_string()),
+            ..Default::default()
+        };
+        let mut impressions = vec![];
+        for user_id in 0..50 {
+            impressions.push(impression(user_id, "control", 1));
+            impressions.push(impression(user_id + 50, "treatment", 3));
+        }
+        let ratings = HashMap::from([((0, 1), 4.0), ((0, 2), 2.0)]);
+
+        let reports = report(&experiment(None, None), &impressions, &ratings);
+        assert_eq!((reports[0].users, reports[0].items_shown, reports[0].clicks), (50, 500, 50));
+        assert_eq!(reports[0].average_rating, Some(3.0));
+        assert_eq!(reports[0].click_through_p_value, None);
+        assert!((reports[1].click_through_rate - 0.3).abs() < 1e-12);
+        assert!(reports[1].click_through_p_value.unwrap() < 0.001);
+        assert_eq!(reports[1].conversion_p_value, Some(1.0));
+        assert!((two_proportion_p_value(10, 100, 10, 100).unwrap() - 1.0).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,185 @@
+use crate::recommendations::{cosine, Ratings};
+use crate::search::{stem, tokenize};
+use crate::{Item, ITEM_STORAGE};
+use candid::CandidType;
+use std::collections::{HashMap, HashSet};
+
+// number of rated items and similar users listed in an explanation
+const MAX_CONTRIBUTORS: usize = 3;
+
+// number of matching terms listed in an explanation
+const MAX_MATCHING_TERMS: usize = 5;
+
+// an item the user rated that is similar to the recommended item
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub(crate) struct ContributingItem {
+    item_id: u64,
+    rating: f64,
+    similarity: f64,
+}
+
+// a user with similar ratings who liked the recommended item
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub(crate) struct SimilarUser {
+    user_id: u64,
+    rating: f64,
+    similarity: f64,
+}
+
+// why an item was recommended, every part that applies is filled in
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug, Default)]
+pub(crate) struct RecommendationExplanation {
+    // item-based: "because you liked X"
+    rated_items: Vec<ContributingItem>,
+    // user-based: "users like you liked it"
+    similar_users: Vec<SimilarUser>,
+    // content-based: category and words shared with items the user liked
+    matching_category: Option<String>,
+    matching_terms: Vec<String>,
+    // fallback: set when nothing personal explains the item
+    popular_in_category: Option<String>,
+}
+
+// explains recommendations for one user from the ratings of a recommendation system
+pub(crate) struct Explainer {
+    user_id: u64,
+    by_user: HashMap<u64, HashMap<u64, f64>>,
+    // item id -> user id -> rating
+    by_item: HashMap<u64, HashMap<u64, f64>>,
+    liked_categories: HashSet<String>,
+    liked_stems: HashSet<String>,
+}
+
+impl Explainer {
+    pub(crate) fn new(ratings: Ratings, user_id: u64) -> Self {
+        let by_item = ratings.by_item();
+
+        // items rated at least at the user's mean rating
+        let user_ratings = ratings.by_user.get(&user_id).cloned().unwrap_or_default();
+        let mean = mean(&user_ratings);
+        let liked: Vec<Item> = user_ratings
+            .iter()
+            .filter(|(_, &rating)| rating >= mean)
+            .filter_map(|(id, _)| ITEM_STORAGE.with(|m| m.borrow().get(id)))
+            .collect();
+        let liked_categories = liked.iter().map(|item| item.category.trim().to_lowercase()).collect();
+        let liked_stems = liked.iter().flat_map(words).map(|word| stem(&word)).collect();
+
+        Explainer { user_id, by_user: ratings.by_user, by_item, liked_categories, liked_stems }
+    }
+
+    pub(crate) fn explain(&self, item: &Item) -> RecommendationExplanation {
+        let no_ratings = HashMap::new();
+        let user_ratings = self.by_user.get(&self.user_id).unwrap_or(&no_ratings);
+
+        let mut rated_items: Vec<ContributingItem> = user_ratings
+            .iter()
+            .map(|(&item_id, &rating)| ContributingItem {
+                item_id,
+                rating,
+                similarity: cosine(self.by_item.get(&item_id), self.by_item.get(&item.id)),
+            })
+            .filter(|contributor| contributor.similarity > 0.0)
+            .collect();
+        rated_items.sort_by(|a, b| {
+            (b.similarity * b.rating).total_cmp(&(a.similarity * a.rating)).then(a.item_id.cmp(&b.item_id))
+        });
+        rated_items.truncate(MAX_CONTRIBUTORS);
+
+        let mut similar_users: Vec<SimilarUser> = self
+            .by_item
+            .get(&item.id)
+            .into_iter()
+            .flatten()
+            .filter(|(&user_id, &rating)| user_id != self.user_id && rating >= mean(&self.by_user[&user_id]))
+            .map(|(&user_id, &rating)| SimilarUser {
+                user_id,
+                rating,
+                similarity: cosine(Some(user_ratings), self.by_user.get(&user_id)),
+            })
+            .filter(|user| user.similarity > 0.0)
+            .collect();
+        similar_users.sort_by(|a, b| b.similarity.total_cmp(&a.similarity).then(a.user_id.cmp(&b.user_id)));
+        similar_users.truncate(MAX_CONTRIBUTORS);
+
+        let category = item.category.trim().to_lowercase();
+        let matching_category = self.liked_categories.contains(&category).then(|| item.category.clone());
+        let mut matching_terms: Vec<String> = vec![];
+        for word in words(item) {
+            if self.liked_stems.contains(&stem(&word)) && !matching_terms.contains(&word) {
+                matching_terms.push(word);
+            }
+        }
+        matching_terms.truncate(MAX_MATCHING_TERMS);
+
+        let personal = !rated_items.is_empty() || !similar_users.is_empty() || matching_category.is_some();
+        RecommendationExplanation {
+            rated_items,
+            similar_users,
+            matching_category,
+            matching_terms,
+            popular_in_category: (!personal).then(|| item.category.clone()),
+        }
+    }
+}
+
+fn words(item: &Item) -> Vec<String> {
+    tokenize(&format!("{} {}", item.name, item.description))
+}
+
+fn mean(ratings: &HashMap<u64, f64>) -> f64 {
+    ratings.values().sum::<f64>() / ratings.len().max(1) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    fn add(id: u64, name: &str, category: &str, description: &str) -> Item {
+        let item = Item {
+            id,
+            name: name.to_string(),
+            category: category.to_string(),
+            description: description.to_string(),
+            ..Default::default()
+        };
+        ITEM_STORAGE.with(|m| m.borrow_mut().insert(id, item.clone()));
+        item
+    }
+
+    fn ratings(preferences: &[(u64, u64, f64)]) -> Ratings {
+        let mut ratings = Ratings { items: BTreeSet::new(), by_user: HashMap::new() };
+        for &(user_id, item_id, rating) in preferences {
+            ratings.items.insert(item_id);
+            ratings.by_user.entry(user_id).or_default().insert(item_id, rating);
+        }
+        ratings
+    }
+
+    #[test]
+    fn explains_collaborative_and_content_matches() {
+        add(1, "Dune", "Books", "Desert planet science fiction");
+        let foundation = add(2, "Foundation", "Books", "Galactic empire science fiction");
+        let explainer = Explainer::new(ratings(&[(1, 1, 5.0), (2, 1, 5.0), (2, 2, 5.0)]), 1);
+
+        let explanation = explainer.explain(&foundation);
+        assert_eq!(explanation.rated_items.len(), 1);
+        assert_eq!(explanation.rated_items[0].item_id, 1);
+        assert_eq!(explanation.similar_users.iter().map(|user| user.user_id).collect::<Vec<_>>(), vec![2]);
+        assert_eq!(explanation.matching_category.as_deref(), Some("Books"));
+        assert_eq!(explanation.matching_terms, vec!["science", "fiction"]);
+        assert_eq!(explanation.popular_in_category, None);
+    }
+
+    #[test]
+    fn falls_back_to_popular_in_category() {
+        add(1, "Dune", "Books", "Desert planet");
+        let blender = add(3, "Blender", "Kitchen", "Fast and quiet");
+        let explainer = Explainer::new(ratings(&[(1, 1, 5.0), (2, 3, 4.0)]), 1);
+
+        let explanation = explainer.explain(&blender);
+        assert!(explanation.rated_items.is_empty() && explanation.similar_users.is_empty());
+        assert_eq!(explanation.popular_in_category.as_deref(), Some("Kitchen"));
+    }
+}
@@ -0,0 +1,218 @@
+use crate::bandit::Rng;
+use crate::recommendations::{Ratings, ScoredItem};
+use crate::Error;
+use candid::CandidType;
+use std::collections::HashMap;
+
+const DEFAULT_ALPHA: f64 = 1.0;
+const DEFAULT_BETA: f64 = 0.5;
+const DEFAULT_RESTART_PROBABILITY: f64 = 0.15;
+
+// the personalized PageRank is estimated from this many random walks of at most MAX_WALK_LENGTH items each
+const WALKS: usize = 2000;
+const MAX_WALK_LENGTH: usize = 50;
+
+// parameters of the random walks over the user-item graph
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+pub(crate) struct GraphSettings {
+    // exponent of the transition probabilities of RP3beta, 1 by default (plain P3)
+    pub(crate) alpha: Option<f64>,
+    // popularity penalty of RP3beta, 0.5 by default
+    pub(crate) beta: Option<f64>,
+    // probability of jumping back to the user at each step of the personalized PageRank, 0.15 by default
+    pub(crate) restart_probability: Option<f64>,
+}
+
+impl GraphSettings {
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        if self.alpha.is_some_and(|alpha| !(alpha.is_finite() && alpha > 0.0)) {
+            return Err(Error::InvalidInput { msg: "alpha must be positive".to_string() });
+        }
+        if self.beta.is_some_and(|beta| !(0.0..=1.0).contains(&beta)) {
+            return Err(Error::InvalidInput { msg: "beta must be between 0 and 1".to_string() });
+        }
+        if self.restart_probability.is_some_and(|restart| !(restart > 0.0 && restart < 1.0)) {
+            return Err(Error::InvalidInput { msg: "restart_probability must be between 0 and 1, exclusive".to_string() });
+        }
+        Ok(())
+    }
+}
+
+// bipartite graph of the users and items of a recommendation system, with the transition
+// probabilities of a walker following the edges in proportion to the ratings
+struct Graph {
+    user_items: HashMap<u64, Vec<(u64, f64)>>,
+    item_users: HashMap<u64, Vec<(u64, f64)>>,
+}
+
+impl Graph {
+    fn new(ratings: &Ratings) -> Self {
+        let mut user_items: HashMap<u64, Vec<(u64, f64)>> = HashMap::new();
+        let mut item_users: HashMap<u64, Vec<(u64, f64)>> = HashMap::new();
+        for (&user_id, user_ratings) in &ratings.by_user {
+            for (&item_id, &rating) in user_ratings {
+                user_items.entry(user_id).or_default().push((item_id, rating));
+                item_users.entry(item_id).or_default().push((user_id, rating));
+            }
+        }
+        for edges in user_items.values_mut().chain(item_users.values_mut()) {
+            let total: f64 = edges.iter().map(|(_, weight)| weight).sum();
+            edges.iter_mut().for_each(|(_, weight)| *weight /= total);
+        }
+        Graph { user_items, item_users }
+    }
+
+    fn step_to_users(&self, items: &HashMap<u64, f64>, alpha: f64) -> HashMap<u64, f64> {
+        let mut users: HashMap<u64, f64> = HashMap::new();
+        for (item_id, mass) in items {
+            for (user_id, probability) in self.item_users.get(item_id).into_iter().flatten() {
+                *users.entry(*user_id).or_insert(0.0) += mass * probability.powf(alpha);
+            }
+        }
+        users
+    }
+
+    fn step_to_items(&self, users: &HashMap<u64, f64>, alpha: f64) -> HashMap<u64, f64> {
+        let mut items: HashMap<u64, f64> = HashMap::new();
+        for (user_id, mass) in users {
+            for (item_id, probability) in self.user_items.get(user_id).into_iter().flatten() {
+                *items.entry(*item_id).or_insert(0.0) += mass * probability.powf(alpha);
+            }
+        }
+        items
+    }
+
+    // RP3beta (Paudel et al., 2016): three-step walk user -> item -> user -> item with the transition
+    // probabilities raised to alpha, divided by the item's degree to the power beta
+    fn rp3beta(&self, user_id: u64, alpha: f64, beta: f64) -> HashMap<u64, f64> {
+        let start = HashMap::from([(user_id, 1.0)]);
+        let mut scores = self.step_to_items(&self.step_to_users(&self.step_to_items(&start, alpha), alpha), alpha);
+        for (item_id, score) in scores.iter_mut() {
+            let degree = self.item_users.get(item_id).map_or(1, |users| users.len()) as f64;
+            *score /= degree.powf(beta);
+        }
+        scores
+    }
+
+    // random walk with restart to the user, estimated by Monte-Carlo: each walk alternates between users and
+    // items and stops with the restart probability at every step, the item scores are the share of visits
+    fn personalized_pagerank(&self, user_id: u64, restart_probability: f64, rng: &mut Rng) -> HashMap<u64, f64> {
+        let mut visits: HashMap<u64, f64> = HashMap::new();
+        for _ in 0..WALKS {
+            let mut user = user_id;
+            for _ in 0..MAX_WALK_LENGTH {
+                let Some(item) = pick(self.user_items.get(&user), rng) else {
+                    break;
+                };
+                *visits.entry(item).or_insert(0.0) += 1.0;
+                if rng.next_f64() < restart_probability {
+                    break;
+                }
+                match pick(self.item_users.get(&item), rng) {
+                    Some(next) if rng.next_f64() >= restart_probability => user = next,
+                    _ => break,
+                }
+            }
+        }
+        let total: f64 = visits.values().sum();
+        visits.values_mut().for_each(|count| *count /= total);
+        visits
+    }
+}
+
+// next node of a walk, drawn in proportion to the transition probabilities
+fn pick(edges: Option<&Vec<(u64, f64)>>, rng: &mut Rng) -> Option<u64> {
+    let edges = edges?;
+    let mut draw = rng.next_f64();
+    for &(node, probability) in edges {
+        if draw < probability {
+            return Some(node);
+        }
+        draw -= probability;
+    }
+    edges.last().map(|&(node, _)| node)
+}
+
+fn top(scores: HashMap<u64, f64>, ratings: &Ratings, user_id: u64, n: usize, exclude_rated: bool) -> Vec<ScoredItem> {
+    let rated = ratings.by_user.get(&user_id);
+    let mut scores: Vec<(u64, f64)> = scores
+        .into_iter()
+        .filter(|(item_id, score)| {
+            *score > 0.0 && !(exclude_rated && rated.is_some_and(|rated| rated.contains_key(item_id)))
+        })
+        .collect();
+    scores.sort_by(|(a, a_score), (b, b_score)| b_score.total_cmp(a_score).then(a.cmp(b)));
+    scores.truncate(n);
+    scores.into_iter().map(|(item_id, score)| ScoredItem { item_id, score }).collect()
+}
+
+// top-N items by RP3beta score
+pub(crate) fn recommend_rp3beta(
+    ratings: &Ratings,
+    user_id: u64,
+    n: usize,
+    exclude_rated: bool,
+    settings: &GraphSettings,
+) -> Vec<ScoredItem> {
+    let scores = Graph::new(ratings).rp3beta(
+        user_id,
+        settings.alpha.unwrap_or(DEFAULT_ALPHA),
+        settings.beta.unwrap_or(DEFAULT_BETA),
+    );
+    top(scores, ratings, user_id, n, exclude_rated)
+}
+
+// top-N items by personalized PageRank
+pub(crate) fn recommend_pagerank(
+    ratings: &Ratings,
+    user_id: u64,
+    n: usize,
+    exclude_rated: bool,
+    settings: &GraphSettings,
+) -> Vec<ScoredItem> {
+    let restart_probability = settings.restart_probability.unwrap_or(DEFAULT_RESTART_PROBABILITY);
+    let mut rng = Rng::load();
+    let scores = Graph::new(ratings).personalized_pagerank(user_id, restart_probability, &mut rng);
+    rng.save();
+    top(scores, ratings, user_id, n, exclude_rated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    fn ratings(preferences: &[(u64, u64, f64)]) -> Ratings {
+        let mut ratings = Ratings { items: BTreeSet::new(), by_user: HashMap::new() };
+        for &(user_id, item_id, rating) in preferences {
+            ratings.items.insert(item_id);
+            ratings.by_user.entry(user_id).or_default().insert(item_id, rating);
+        }
+        ratings
+    }
+
+    fn ids(scored: &[ScoredItem]) -> Vec<u64> {
+        scored.iter().map(|scored| scored.item_id).collect()
+    }
+
+    #[test]
+    fn walks_reach_items_of_similar_users() {
+        // user 2 shares item 1 with user 1 and likes 2; item 3 is popular with unrelated users
+        let ratings = ratings(&[
+            (1, 1, 5.0),
+            (2, 1, 5.0),
+            (2, 2, 5.0),
+            (2, 3, 1.0),
+            (3, 3, 5.0),
+            (4, 3, 5.0),
+            (5, 4, 5.0),
+        ]);
+        let settings = GraphSettings::default();
+        assert_eq!(ids(&recommend_rp3beta(&ratings, 1, 10, true, &settings)), vec![2, 3]);
+        assert_eq!(ids(&recommend_pagerank(&ratings, 1, 10, true, &settings)), vec![2, 3]);
+
+        // without exponent nor penalty the three-step walk is a probability distribution
+        let scores = Graph::new(&ratings).rp3beta(1, 1.0, 0.0);
+        assert!((scores.values().sum::<f64>() - 1.0).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,352 @@
+use crate::impressions::{record_click, record_conversion};
+use crate::onboarding::{get_onboarding_items, submit_onboarding_ratings};
+use crate::recommendations::{get_recommendations, RecommendationRequest};
+use crate::search::{search_items, Page, SearchFilters};
+use crate::sessions::{get_next_item_recommendations, record_session_event, start_session};
+use crate::taxonomy::{get_categories, get_category, get_items_under_category};
+use crate::{
+    add_item, add_item_to_recommendation_system, add_recommendation_system, add_user, add_user_preference,
+    add_user_preference_to_recommendation_system, add_user_to_recommendation_system, delete_item,
+    delete_recommendation_system, delete_user, delete_user_preference, get_item_by_id, get_items,
+    get_items_in_recommendation_system, get_recommendation_system_by_id, get_recommendation_systems, get_user_by_id,
+    get_user_preference_by_id, get_user_preferences, get_user_preferences_in_recommendation_system, get_users,
+    get_users_in_recommendation_system, restore_item, restore_recommendation_system, restore_user,
+    restore_user_preference, update_item, update_user, update_user_preference, Error,
+};
+use candid::CandidType;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+// request and response types of the HTTP gateway interface
+#[derive(CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct HttpRequest {
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+#[derive(CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct HttpResponse {
+    status_code: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    upgrade: Option<bool>,
+}
+
+impl HttpResponse {
+    fn json<T: Serialize>(status_code: u16, value: &T) -> Self {
+        HttpResponse {
+            status_code,
+            headers: vec![("Content-Type".to_string(), "application/json".to_string())],
+            body: serde_json::to_vec(value).unwrap(),
+            upgrade: None,
+        }
+    }
+
+    fn error(error: Error) -> Self {
+        let status_code = match error {
+            Error::NotFound { .. } => 404,
+            Error::AlreadyExists { .. } | Error::Conflict { .. } => 409,
+            Error::InvalidInput { .. } | Error::Aborted { .. } => 400,
+            Error::Unauthorized { .. } => 403,
+        };
+        HttpResponse::json(status_code, &error)
+    }
+
+    // ask the boundary node to resend the request as an update call
+    fn upgrade() -> Self {
+        HttpResponse { status_code: 200, headers: vec![], body: vec![], upgrade: Some(true) }
+    }
+}
+
+fn respond<T: Serialize>(result: Result<T, Error>) -> HttpResponse {
+    match result {
+        Ok(value) => HttpResponse::json(200, &value),
+        Err(error) => HttpResponse::error(error),
+    }
+}
+
+fn respond_created<T: Serialize>(result: Result<T, Error>) -> HttpResponse {
+    match result {
+        Ok(value) => HttpResponse::json(201, &value),
+        Err(error) => HttpResponse::error(error),
+    }
+}
+
+fn parse_body<T: DeserializeOwned>(body: &[u8]) -> Result<T, Error> {
+    serde_json::from_slice(body).map_err(|error| Error::InvalidInput {
+        msg: format!("invalid JSON body: {}", error),
+    })
+}
+
+fn parse_id(segment: &str) -> Result<u64, Error> {
+    segment.parse().map_err(|_| Error::InvalidInput {
+        msg: format!("invalid id: {}", segment),
+    })
+}
+
+// query string parameters, percent-decoded, in order
+fn parse_query(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(key), percent_decode(value))
+        })
+        .collect()
+}
+
+fn percent_decode(value: &str) -> String {
+    let hex = |byte: u8| (byte as char).to_digit(16).map(|digit| digit as u8);
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match (bytes[i], bytes.get(i + 1).copied().and_then(hex), bytes.get(i + 2).copied().and_then(hex)) {
+            (b'%', Some(high), Some(low)) => {
+                decoded.push(high << 4 | low);
+                i += 2;
+            }
+            (b'+', _, _) => decoded.push(b' '),
+            (byte, _, _) => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn param<'a>(params: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    params.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+}
+
+fn parse_param<T: std::str::FromStr>(params: &[(String, String)], name: &str) -> Result<Option<T>, Error> {
+    param(params, name)
+        .map(|value| {
+            value.parse().map_err(|_| Error::InvalidInput {
+                msg: format!("invalid {} parameter: {}", name, value),
+            })
+        })
+        .transpose()
+}
+
+fn is_read_only(method: &str) -> bool {
+    matches!(method, "GET" | "HEAD")
+}
+
+// REST routes for reads, served from query calls
+fn route_query(path: &[&str], params: &[(String, String)]) -> Result<HttpResponse, Error> {
+    Ok(match path {
+        ["users"] => respond(get_users()),
+        ["users", id] => respond(get_user_by_id(parse_id(id)?)),
+        ["items"] => respond(get_items()),
+        ["items", id] => respond(get_item_by_id(parse_id(id)?)),
+        ["categories"] => respond(Ok(get_categories())),
+        ["categories", id] => respond(get_category(parse_id(id)?)),
+        ["categories", id, "items"] => respond(get_items_under_category(parse_id(id)?)),
+        ["preferences"] => respond(get_user_preferences()),
+        ["preferences", id] => respond(get_user_preference_by_id(parse_id(id)?)),
+        ["systems"] => respond(get_recommendation_systems()),
+        ["systems", id] => respond(get_recommendation_system_by_id(parse_id(id)?)),
+        ["systems", id, "users"] => respond(get_users_in_recommendation_system(parse_id(id)?)),
+        ["systems", id, "items"] => respond(get_items_in_recommendation_system(parse_id(id)?)),
+        ["systems", id, "preferences"] => respond(get_user_preferences_in_recommendation_system(parse_id(id)?)),
+        ["systems", id, "search"] => {
+            let filters = SearchFilters {
+                categories: param(params, "category").map(|_| {
+                    params.iter().filter(|(key, _)| key == "category").map(|(_, value)| value.clone()).collect()
+                }),
+                user_id: parse_param(params, "user_id")?,
+                tags: param(params, "tag").map(|_| {
+                    params.iter().filter(|(key, _)| key == "tag").map(|(_, value)| value.clone()).collect()
+                }),
+                min_price: parse_param(params, "min_price")?,
+                max_price: parse_param(params, "max_price")?,
+                available: parse_param(params, "available")?,
+                ..Default::default()
+            };
+            let page = Page { offset: parse_param(params, "offset")?, limit: parse_param(params, "limit")? };
+            let query = param(params, "q").unwrap_or_default().to_string();
+            respond(search_items(parse_id(id)?, query, filters, page))
+        }
+        ["systems", id, "onboarding"] => {
+            let user_id = parse_param(params, "user_id")?.ok_or(Error::InvalidInput {
+                msg: "missing user_id parameter".to_string(),
+            })?;
+            respond(get_onboarding_items(parse_id(id)?, user_id, parse_param(params, "k")?))
+        }
+        ["sessions", id, "next"] => {
+            respond(get_next_item_recommendations(parse_id(id)?, parse_param(params, "k")?.unwrap_or(10)))
+        }
+        // recommendations fill the cache, so they are served from update calls
+        ["systems", _, "recommendations"] | ["systems", _, "users", _, "recommendations"] => HttpResponse::upgrade(),
+        _ => return Err(not_found()),
+    })
+}
+
+// recommendation request with the explain and diversity parameters of the query string
+fn recommendation_request(
+    recommendation_system_id: u64,
+    user_id: u64,
+    limit: Option<u64>,
+    params: &[(String, String)],
+) -> Result<RecommendationRequest, Error> {
+    Ok(RecommendationRequest {
+        recommendation_system_id,
+        user_id,
+        limit,
+        explain: param(params, "explain").map(|value| value == "true" || value == "1"),
+        diversity: param(params, "diversity")
+            .map(|value| {
+                value.parse().map_err(|_| Error::InvalidInput {
+                    msg: format!("invalid diversity parameter: {}", value),
+                })
+            })
+            .transpose()?,
+    })
+}
+
+// REST routes for writes, served from update calls
+fn route_update(method: &str, path: &[&str], params: &[(String, String)], body: &[u8]) -> Result<HttpResponse, Error> {
+    Ok(match (method, path) {
+        ("GET", ["systems", id, "recommendations"]) => {
+            let user_id = parse_param(params, "user_id")?.ok_or(Error::InvalidInput {
+                msg: "missing user_id parameter".to_string(),
+            })?;
+            let request = recommendation_request(parse_id(id)?, user_id, parse_param(params, "limit")?, params)?;
+            respond(get_recommendations(request))
+        }
+        ("GET", ["systems", id, "users", user_id, "recommendations"]) => {
+            let request = recommendation_request(parse_id(id)?, parse_id(user_id)?, parse_param(params, "k")?, params)?;
+            respond(get_recommendations(request))
+        }
+        ("POST", ["users"]) => respond_created(add_user(parse_body(body)?)),
+        ("PATCH", ["users", id]) => respond(update_user(parse_id(id)?, parse_body(body)?)),
+        ("DELETE", ["users", id]) => respond(delete_user(parse_id(id)?)),
+        ("POST", ["users", id, "restore"]) => respond(restore_user(parse_id(id)?)),
+        ("POST", ["items"]) => respond_created(add_item(parse_body(body)?)),
+        ("PATCH", ["items", id]) => respond(update_item(parse_id(id)?, parse_body(body)?)),
+        ("DELETE", ["items", id]) => respond(delete_item(parse_id(id)?)),
+        ("POST", ["items", id, "restore"]) => respond(restore_item(parse_id(id)?)),
+        ("POST", ["preferences"]) => respond_created(add_user_preference(parse_body(body)?)),
+        ("PATCH", ["preferences", id]) => respond(update_user_preference(parse_id(id)?, parse_body(body)?)),
+        ("DELETE", ["preferences", id]) => respond(delete_user_preference(parse_id(id)?)),
+        ("POST", ["preferences", id, "restore"]) => respond(restore_user_preference(parse_id(id)?)),
+        ("POST", ["systems"]) => respond_created(add_recommendation_system()),
+        ("DELETE", ["systems", id]) => respond(delete_recommendation_system(parse_id(id)?)),
+        ("POST", ["systems", id, "restore"]) => respond(restore_recommendation_system(parse_id(id)?)),
+        ("POST", ["systems", id, "users", user_id]) => {
+            respond(add_user_to_recommendation_system(parse_id(id)?, parse_id(user_id)?))
+        }
+        ("POST", ["systems", id, "items", item_id]) => {
+            respond(add_item_to_recommendation_system(parse_id(id)?, parse_id(item_id)?))
+        }
+        ("POST", ["systems", id, "preferences", user_preference_id]) => {
+            respond(add_user_preference_to_recommendation_system(parse_id(id)?, parse_id(user_preference_id)?))
+        }
+        ("POST", ["systems", id, "onboarding"]) => {
+            let user_id = parse_param(params, "user_id")?.ok_or(Error::InvalidInput {
+                msg: "missing user_id parameter".to_string(),
+            })?;
+            respond(submit_onboarding_ratings(parse_id(id)?, user_id, parse_body(body)?))
+        }
+        ("POST", ["systems", id, "sessions"]) => respond_created(start_session(parse_id(id)?)),
+        ("POST", ["sessions", id, "events", item_id]) => {
+            respond(record_session_event(parse_id(id)?, parse_id(item_id)?))
+        }
+        ("POST", ["impressions", id, "clicks", item_id]) => respond(record_click(parse_id(id)?, parse_id(item_id)?)),
+        ("POST", ["impressions", id, "conversions", item_id]) => {
+            respond(record_conversion(parse_id(id)?, parse_id(item_id)?))
+        }
+        _ if is_read_only(method) => return route_query(path, params),
+        _ => return Err(not_found()),
+    })
+}
+
+fn not_found() -> Error {
+    Error::NotFound { msg: "no such route".to_string() }
+}
+
+// split a request url into its path segments and query parameters
+fn split_url(url: &str) -> (Vec<&str>, Vec<(String, String)>) {
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    let segments = path.split('/').filter(|segment| !segment.is_empty()).collect();
+    (segments, parse_query(query))
+}
+
+fn handle(request: &HttpRequest, allow_updates: bool) -> HttpResponse {
+    let (path, params) = split_url(&request.url);
+    let method = request.method.to_uppercase();
+    let result = if allow_updates {
+        route_update(&method, &path, &params, &request.body)
+    } else if is_read_only(&method) {
+        route_query(&path, &params)
+    } else {
+        return HttpResponse::upgrade();
+    };
+    result.unwrap_or_else(HttpResponse::error)
+}
+
+// function to serve the JSON REST API over the HTTP gateway, writes are upgraded to update calls
+#[ic_cdk::query]
+fn http_request(request: HttpRequest) -> HttpResponse {
+    handle(&request, false)
+}
+
+// function to serve the writes of the JSON REST API
+#[ic_cdk::update]
+fn http_request_update(request: HttpRequest) -> HttpResponse {
+    handle(&request, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, url: &str) -> HttpRequest {
+        HttpRequest { method: method.to_string(), url: url.to_string(), headers: vec![], body: vec![] }
+    }
+
+    #[test]
+    fn queries_upgrade_writes() {
+        let response = http_request(request("POST", "/items"));
+        assert_eq!(response.upgrade, Some(true));
+        assert_eq!(http_request(request("GET", "/items/1")).upgrade, None);
+        assert_eq!(http_request(request("GET", "/systems/1/recommendations?user_id=2")).upgrade, Some(true));
+        assert_eq!(http_request(request("GET", "/systems/1/users/2/recommendations?k=10")).upgrade, Some(true));
+    }
+
+    #[test]
+    fn errors_map_to_status_codes() {
+        assert_eq!(http_request(request("GET", "/items/1")).status_code, 404);
+        assert_eq!(http_request(request("GET", "/items/abc")).status_code, 400);
+        assert_eq!(http_request(request("GET", "/nowhere")).status_code, 404);
+        assert_eq!(http_request_update(request("PATCH", "/items/1")).status_code, 400);
+        assert_eq!(http_request_update(request("GET", "/systems/1/users/2/recommendations?k=10")).status_code, 404);
+        assert_eq!(http_request_update(request("GET", "/systems/1/users/2/recommendations?k=ten")).status_code, 400);
+        assert_eq!(HttpResponse::error(Error::Conflict { msg: String::new() }).status_code, 409);
+        assert_eq!(HttpResponse::error(Error::Unauthorized { msg: String::new() }).status_code, 403);
+    }
+
+    #[test]
+    fn get_returns_json() {
+        let item = crate::Item { id: 7, name: "Dune".to_string(), ..Default::default() };
+        crate::ITEM_STORAGE.with(|m| m.borrow_mut().insert(7, item));
+
+        let response = http_request(request("GET", "/items/7?pretty=1"));
+        assert_eq!(response.status_code, 200);
+        let body: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(body["name"], "Dune");
+    }
+
+    #[test]
+    fn query_parameters_are_decoded() {
+        let (path, params) = split_url("/systems/1/search?q=science%20fiction+books&category=Books&category=E%2FBooks");
+        assert_eq!(path, vec!["systems", "1", "search"]);
+        assert_eq!(param(&params, "q"), Some("science fiction books"));
+        assert_eq!(params.iter().filter(|(key, _)| key == "category").count(), 2);
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("caf%C3%A9%2"), "café%2");
+    }
+}
@@ -0,0 +1,434 @@
+use crate::recommendations;
+use crate::settings::Algorithm;
+use crate::{audit, Error, Memory, MEMORY_MANAGER};
+use candid::{CandidType, Decode, Encode};
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use std::collections::BTreeMap;
+use std::{borrow::Cow, cell::RefCell};
+
+// implicit ratings given by a click and a conversion, explicit preferences take precedence
+const CLICK_RATING: f64 = 3.0;
+const CONVERSION_RATING: f64 = 5.0;
+
+// impressions kept in the log, the oldest are pruned first; click-through counters and implicit
+// ratings are aggregated when feedback arrives and outlive the impressions
+const IMPRESSION_RETENTION: u64 = 100_000;
+
+// bounds the work done by a single log call, e.g. after an upgrade lowered the retention
+const MAX_PRUNED_PER_LOG: usize = 100;
+
+// a recommendation list shown to a user
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug, Default)]
+pub(crate) struct Impression {
+    pub(crate) id: u64,
+    pub(crate) recommendation_system_id: u64,
+    pub(crate) user_id: u64,
+    // returned items, in order
+    pub(crate) item_ids: Vec<u64>,
+    // items placed by the exploration layer
+    pub(crate) explored_item_ids: Vec<u64>,
+    pub(crate) created_at: u64,
+    pub(crate) reward: Option<f64>,
+    // algorithm that scored the list
+    pub(crate) algorithm: Option<Algorithm>,
+    pub(crate) clicked_item_ids: Option<Vec<u64>>,
+    pub(crate) converted_item_ids: Option<Vec<u64>>,
+    // experiment and variant the user was assigned to
+    pub(crate) experiment_id: Option<u64>,
+    pub(crate) variant: Option<String>,
+}
+
+impl Storable for Impression {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Impression {
+    const MAX_SIZE: u32 = 8192;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// key of the engagement counters, one per position of the lists of an algorithm
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+struct PositionKey {
+    recommendation_system_id: u64,
+    algorithm: u8,
+    position: u32,
+}
+
+impl Storable for PositionKey {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        let mut bytes = self.recommendation_system_id.to_be_bytes().to_vec();
+        bytes.push(self.algorithm);
+        bytes.extend_from_slice(&self.position.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        PositionKey {
+            recommendation_system_id: u64::from_be_bytes(bytes[..8].try_into().unwrap()),
+            algorithm: bytes[8],
+            position: u32::from_be_bytes(bytes[9..].try_into().unwrap()),
+        }
+    }
+}
+
+impl BoundedStorable for PositionKey {
+    const MAX_SIZE: u32 = 13;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+#[derive(CandidType, Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq)]
+struct Engagement {
+    impressions: u64,
+    clicks: u64,
+    conversions: u64,
+}
+
+impl Storable for Engagement {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Engagement {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Engagement {
+    fn add(&mut self, other: &Engagement) {
+        self.impressions += other.impressions;
+        self.clicks += other.clicks;
+        self.conversions += other.conversions;
+    }
+
+    fn rate(count: u64, impressions: u64) -> f64 {
+        if impressions == 0 {
+            0.0
+        } else {
+            count as f64 / impressions as f64
+        }
+    }
+}
+
+// key of the implicit interactions, per user and item
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+struct InteractionKey {
+    user_id: u64,
+    item_id: u64,
+}
+
+impl Storable for InteractionKey {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        let mut bytes = self.user_id.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&self.item_id.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let (user_id, item_id) = bytes.split_at(8);
+        InteractionKey {
+            user_id: u64::from_be_bytes(user_id.try_into().unwrap()),
+            item_id: u64::from_be_bytes(item_id.try_into().unwrap()),
+        }
+    }
+}
+
+impl BoundedStorable for InteractionKey {
+    const MAX_SIZE: u32 = 16;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+// click-through of one position of the lists of an algorithm, 0 is the top
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug)]
+pub(crate) struct PositionClickThrough {
+    position: u64,
+    impressions: u64,
+    clicks: u64,
+    conversions: u64,
+    click_through_rate: f64,
+    conversion_rate: f64,
+}
+
+// click-through of the lists of an algorithm, impressions count every shown item
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug)]
+pub(crate) struct AlgorithmClickThrough {
+    algorithm: Algorithm,
+    impressions: u64,
+    clicks: u64,
+    conversions: u64,
+    click_through_rate: f64,
+    conversion_rate: f64,
+    positions: Vec<PositionClickThrough>,
+}
+
+thread_local! {
+    static IMPRESSION_ID_COUNTER: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static IMPRESSIONS: RefCell<StableBTreeMap<u64, Impression, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24))))
+    );
+
+    static ENGAGEMENT: RefCell<StableBTreeMap<PositionKey, Engagement, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27))))
+    );
+
+    // (user id, item id) -> implicit rating from clicks and conversions
+    static INTERACTIONS: RefCell<StableBTreeMap<InteractionKey, f64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28))))
+    );
+}
+
+fn algorithm_code(algorithm: Algorithm) -> u8 {
+    match algorithm {
+        Algorithm::ItemBasedCollaborativeFiltering => 0,
+        Algorithm::LinUcb => 1,
+        Algorithm::SlopeOne => 2,
+        Algorithm::Rp3Beta => 3,
+        Algorithm::PersonalizedPageRank => 4,
+        Algorithm::Demographic => 5,
+    }
+}
+
+fn algorithm_of_code(code: u8) -> Algorithm {
+    match code {
+        1 => Algorithm::LinUcb,
+        2 => Algorithm::SlopeOne,
+        3 => Algorithm::Rp3Beta,
+        4 => Algorithm::PersonalizedPageRank,
+        5 => Algorithm::Demographic,
+        _ => Algorithm::ItemBasedCollaborativeFiltering,
+    }
+}
+
+fn update_engagement(impression: &Impression, position: usize, update: impl FnOnce(&mut Engagement)) {
+    let key = PositionKey {
+        recommendation_system_id: impression.recommendation_system_id,
+        algorithm: algorithm_code(impression.algorithm.unwrap_or_default()),
+        position: position as u32,
+    };
+    let mut engagement = ENGAGEMENT.with(|m| m.borrow().get(&key)).unwrap_or_default();
+    update(&mut engagement);
+    ENGAGEMENT.with(|m| m.borrow_mut().insert(key, engagement));
+}
+
+// count an impression for every position of the list
+fn count_impression(impression: &Impression) {
+    for position in 0..impression.item_ids.len() {
+        update_engagement(impression, position, |engagement| engagement.impressions += 1);
+    }
+}
+
+// log an impression and return its id
+pub(crate) fn log(mut impression: Impression) -> u64 {
+    let id = IMPRESSION_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment id counter");
+    impression.id = id;
+    impression.created_at = time();
+    count_impression(&impression);
+    IMPRESSIONS.with(|m| m.borrow_mut().insert(id, impression));
+    prune(IMPRESSION_RETENTION);
+    id
+}
+
+// drop the oldest impressions above the retention, at most MAX_PRUNED_PER_LOG of them
+fn prune(retention: u64) {
+    IMPRESSIONS.with(|m| {
+        let mut log = m.borrow_mut();
+        for _ in 0..MAX_PRUNED_PER_LOG {
+            match log.first_key_value() {
+                Some((oldest, _)) if log.len() > retention => log.remove(&oldest),
+                _ => break,
+            };
+        }
+    });
+}
+
+pub(crate) fn get(id: u64) -> Option<Impression> {
+    IMPRESSIONS.with(|m| m.borrow().get(&id))
+}
+
+pub(crate) fn save(impression: Impression) {
+    IMPRESSIONS.with(|m| m.borrow_mut().insert(impression.id, impression));
+}
+
+#[derive(Clone, Copy)]
+enum Feedback {
+    Click,
+    Conversion,
+}
+
+// record a click or a conversion of an item of an impression, once per item and kind
+fn engage(impression: &mut Impression, item_id: u64, feedback: Feedback) -> Result<(), Error> {
+    let position = impression.item_ids.iter().position(|&id| id == item_id).ok_or(Error::InvalidInput {
+        msg: format!("item with id={} is not part of impression with id={}", item_id, impression.id),
+    })?;
+    let (recorded, rating) = match feedback {
+        Feedback::Click => (impression.clicked_item_ids.get_or_insert_with(Vec::new), CLICK_RATING),
+        Feedback::Conversion => (impression.converted_item_ids.get_or_insert_with(Vec::new), CONVERSION_RATING),
+    };
+    if recorded.contains(&item_id) {
+        return Err(Error::Conflict { msg: format!("item with id={} was already recorded for this impression", item_id) });
+    }
+    recorded.push(item_id);
+    update_engagement(impression, position, |engagement| match feedback {
+        Feedback::Click => engagement.clicks += 1,
+        Feedback::Conversion => engagement.conversions += 1,
+    });
+
+    let key = InteractionKey { user_id: impression.user_id, item_id };
+    let previous = INTERACTIONS.with(|m| m.borrow().get(&key)).unwrap_or(0.0);
+    INTERACTIONS.with(|m| m.borrow_mut().insert(key, previous.max(rating)));
+    Ok(())
+}
+
+fn record_feedback(method: &str, impression_id: u64, item_id: u64, feedback: Feedback) -> Result<(), Error> {
+    let mut impression = get(impression_id).ok_or(Error::NotFound {
+        msg: format!("impression with id={} not found", impression_id),
+    })?;
+    let before = impression.clone();
+    engage(&mut impression, item_id, feedback)?;
+    save(impression.clone());
+    recommendations::invalidate_user(impression.user_id);
+    audit::record(method, vec![impression_id, item_id], Some(&before), Some(&impression));
+    Ok(())
+}
+
+// impressions logged for the variants of an experiment
+pub(crate) fn of_experiment(experiment_id: u64) -> Vec<Impression> {
+    IMPRESSIONS.with(|m| {
+        m.borrow().iter().map(|(_, impression)| impression).filter(|impression| impression.experiment_id == Some(experiment_id)).collect()
+    })
+}
+
+// implicit ratings as (user id, item id, rating)
+pub(crate) fn implicit_ratings() -> Vec<(u64, u64, f64)> {
+    INTERACTIONS.with(|m| m.borrow().iter().map(|(key, rating)| (key.user_id, key.item_id, rating)).collect())
+}
+
+// click-through per algorithm and position of the lists of a recommendation system
+pub(crate) fn click_through(recommendation_system_id: u64) -> Vec<AlgorithmClickThrough> {
+    let start = PositionKey { recommendation_system_id, algorithm: 0, position: 0 };
+    let mut by_algorithm: BTreeMap<u8, Vec<(u32, Engagement)>> = BTreeMap::new();
+    ENGAGEMENT.with(|m| {
+        for (key, engagement) in m.borrow().range(start..) {
+            if key.recommendation_system_id != recommendation_system_id {
+                break;
+            }
+            by_algorithm.entry(key.algorithm).or_default().push((key.position, engagement));
+        }
+    });
+    by_algorithm
+        .into_iter()
+        .map(|(code, positions)| {
+            let mut total = Engagement::default();
+            positions.iter().for_each(|(_, engagement)| total.add(engagement));
+            AlgorithmClickThrough {
+                algorithm: algorithm_of_code(code),
+                impressions: total.impressions,
+                clicks: total.clicks,
+                conversions: total.conversions,
+                click_through_rate: Engagement::rate(total.clicks, total.impressions),
+                conversion_rate: Engagement::rate(total.conversions, total.impressions),
+                positions: positions
+                    .into_iter()
+                    .map(|(position, engagement)| PositionClickThrough {
+                        position: position as u64,
+                        impressions: engagement.impressions,
+                        clicks: engagement.clicks,
+                        conversions: engagement.conversions,
+                        click_through_rate: Engagement::rate(engagement.clicks, engagement.impressions),
+                        conversion_rate: Engagement::rate(engagement.conversions, engagement.impressions),
+                    })
+                    .collect(),
+            }
+        })
+        .collect()
+}
+
+// function to get an impression by id
+#[ic_cdk::query]
+fn get_impression(id: u64) -> Result<Impression, Error> {
+    get(id).ok_or(Error::NotFound { msg: format!("impression with id={} not found", id) })
+}
+
+// function to record a click on an item of an impression, the click counts as an implicit rating
+#[ic_cdk::update]
+pub(crate) fn record_click(impression_id: u64, item_id: u64) -> Result<(), Error> {
+    record_feedback("record_click", impression_id, item_id, Feedback::Click)
+}
+
+// function to record a conversion (purchase, signup, ...) of an item of an impression
+#[ic_cdk::update]
+pub(crate) fn record_conversion(impression_id: u64, item_id: u64) -> Result<(), Error> {
+    record_feedback("record_conversion", impression_id, item_id, Feedback::Conversion)
+}
+
+// function to get the click-through and conversion rates per algorithm and position
+#[ic_cdk::query]
+fn get_click_through_stats(recommendation_system_id: u64) -> Vec<AlgorithmClickThrough> {
+    click_through(recommendation_system_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn feedback_counts_per_position_and_feeds_ratings() {
+        let mut impression = Impression {
+            id: 1,
+            recommendation_system_id: 7,
+            user_id: 2,
+            item_ids: vec![10, 11, 12],
+            algorithm: Some(Algorithm::LinUcb),
+            ..Default::default()
+        };
+        count_impression(&impression);
+        count_impression(&Impression { recommendation_system_id: 7, item_ids: vec![10], ..Default::default() });
+
+        engage(&mut impression, 11, Feedback::Click).unwrap();
+        engage(&mut impression, 11, Feedback::Conversion).unwrap();
+        assert!(matches!(engage(&mut impression, 11, Feedback::Click), Err(Error::Conflict { .. })));
+        assert!(matches!(engage(&mut impression, 99, Feedback::Click), Err(Error::InvalidInput { .. })));
+
+        let stats = click_through(7);
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].algorithm, Algorithm::ItemBasedCollaborativeFiltering);
+        assert_eq!((stats[0].impressions, stats[0].clicks), (1, 0));
+        let linucb = &stats[1];
+        assert_eq!((linucb.impressions, linucb.clicks, linucb.conversions), (3, 1, 1));
+        assert!((linucb.click_through_rate - 1.0 / 3.0).abs() < 1e-12);
+        assert_eq!(linucb.positions[1].click_through_rate, 1.0);
+        assert!(click_through(8).is_empty());
+        assert_eq!(implicit_ratings(), vec![(2, 11, CONVERSION_RATING)]);
+    }
+
+    #[test]
+    fn pruning_keeps_the_newest_impressions() {
+        for id in 0..5 {
+            save(Impression { id, ..Default::default() });
+        }
+        prune(2);
+        assert_eq!(IMPRESSIONS.with(|m| m.borrow().iter().map(|(id, _)| id).collect::<Vec<u64>>()), vec![3, 4]);
+    }
+}
@@ -1,10 +1,53 @@
 #[macro_use]
 extern crate serde;
+mod associations;
+mod attributes;
+mod audit;
+mod bandit;
+mod batch;
+mod certification;
+mod diversity;
+mod embeddings;
+mod evaluation;
+mod experiments;
+mod explanations;
+mod graph;
+mod http;
+mod impressions;
+mod linucb;
+mod onboarding;
+mod profiles;
+mod recommendations;
+mod rules;
+mod settings;
+mod search;
+mod sessions;
+mod slope_one;
+mod taxonomy;
+
 use candid::{Decode, Encode};
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
 use std::{borrow::Cow, cell::RefCell};
 use ic_cdk::api::time;
+use std::time::Duration;
+use associations::{AssociationRule, FrequentlyBoughtTogether};
+use attributes::{AttributeSchema, ItemMetadata};
+use audit::{AuditLogPage, AuditLogQuery};
+use search::{Page, SearchFilters, SearchPage, SearchResult};
+use http::{HttpRequest, HttpResponse};
+use certification::{CertifiedItem, CertifiedRecommendations};
+use recommendations::{Recommendation, RecommendationCacheStats, RecommendationRequest, RecommendationResponse};
+use rules::RecommendationRules;
+use settings::{Algorithm, RecommendationSettings};
+use evaluation::EvaluationReport;
+use experiments::{Experiment, ExperimentPayload, ExperimentReport};
+use sessions::Session;
+use profiles::UserProfile;
+use onboarding::{OnboardingItem, OnboardingRating};
+use embeddings::{ItemEmbedding, VectorSearchOptions};
+use impressions::{AlgorithmClickThrough, Impression};
+use taxonomy::{Category, CategoryAffinity, CategoryPopularity};
 
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
@@ -18,6 +61,7 @@ struct User {
     password: String,
     created_at: u64,
     updated_at: Option<u64>,
+    deleted_at: Option<u64>,
 }
 
 // struct to represent an item
@@ -25,10 +69,13 @@ struct User {
 struct Item {
     id: u64,
     name: String,
+    // name of the item's category, kept in sync with the taxonomy
     category: String,
+    category_id: Option<u64>,
     description: String,
     created_at: u64,
     updated_at: Option<u64>,
+    deleted_at: Option<u64>,
 }
 
 // struct to represent User preferences or interactions with items
@@ -40,6 +87,7 @@ struct UserPreference {
     rating: u64,
     created_at: u64,
     updated_at: Option<u64>,
+    deleted_at: Option<u64>,
 }
 
 // Struct to manage the recommendation system
@@ -49,7 +97,7 @@ struct RecommendationSystem {
     users : Vec<User>,
     items : Vec<Item>,
     user_preferences : Vec<UserPreference>,
-    
+    deleted_at: Option<u64>,
 }
 
 
@@ -81,6 +129,24 @@ impl Storable for Item {
 }
 
 impl BoundedStorable for Item {
+    const MAX_SIZE: u32 = 32768;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// items as stored before they moved to a larger region, see migrate_item_storage
+struct LegacyItem(Item);
+
+impl Storable for LegacyItem {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        self.0.to_bytes()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        LegacyItem(Item::from_bytes(bytes))
+    }
+}
+
+impl BoundedStorable for LegacyItem {
     const MAX_SIZE: u32 = 1024;
     const IS_FIXED_SIZE: bool = false;
 }
@@ -119,6 +185,45 @@ impl BoundedStorable for RecommendationSystem {
     const IS_FIXED_SIZE: bool = false;
 }
 
+// deleted records are kept in the trash for 30 days by default
+const DEFAULT_TRASH_RETENTION_SECS: u64 = 30 * 24 * 60 * 60;
+
+// how often the trash is checked for records past their retention period
+const TRASH_PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// longest item name and free-form category, and item description, in bytes; an item stays within Item::MAX_SIZE
+const MAX_ITEM_NAME_LENGTH: usize = 1024;
+const MAX_ITEM_DESCRIPTION_LENGTH: usize = 28 * 1024;
+
+// maximum length of an email address in bytes (RFC 5321)
+const MAX_EMAIL_LENGTH: usize = 254;
+
+// key of the email index, holds a normalized email address
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct EmailKey(String);
+
+impl Storable for EmailKey {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Borrowed(self.0.as_bytes())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        EmailKey(String::from_utf8(bytes.into_owned()).unwrap())
+    }
+}
+
+impl EmailKey {
+    // key of a stored email address, users created before the index can have unnormalized addresses
+    fn of(email: &str) -> Self {
+        EmailKey(email.trim().to_lowercase())
+    }
+}
+
+impl BoundedStorable for EmailKey {
+    const MAX_SIZE: u32 = MAX_EMAIL_LENGTH as u32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
 // thread memory manager 
 thread_local! {
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
@@ -149,10 +254,15 @@ thread_local! {
         StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))))
     );
 
-    static ITEM_STORAGE: RefCell<StableBTreeMap<u64, Item, Memory>> = RefCell::new(
+    // items of up to 1024 bytes, emptied into ITEM_STORAGE on upgrade
+    static LEGACY_ITEM_STORAGE: RefCell<StableBTreeMap<u64, LegacyItem, Memory>> = RefCell::new(
         StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5))))
     );
 
+    static ITEM_STORAGE: RefCell<StableBTreeMap<u64, Item, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(43))))
+    );
+
     static USER_PREFERENCE_STORAGE: RefCell<StableBTreeMap<u64, UserPreference, Memory>> = RefCell::new(
         StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6))))
     );
@@ -160,6 +270,46 @@ thread_local! {
     static RECOMMENDATION_SYSTEM_STORAGE: RefCell<StableBTreeMap<u64, RecommendationSystem, Memory>> = RefCell::new(
         StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7))))
     );
+
+    // how long deleted records stay in the trash before they are purged, in seconds
+    static TRASH_RETENTION: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9))), DEFAULT_TRASH_RETENTION_SECS)
+            .expect("Cannot create the trash retention")
+    );
+
+    // normalized email -> user id, enforces email uniqueness
+    static EMAIL_INDEX: RefCell<StableBTreeMap<EmailKey, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8))))
+    );
+}
+
+#[ic_cdk::init]
+fn init() {
+    // a new canister has no free-form categories to migrate
+    taxonomy::migrate_items(ic_cdk::id(), time());
+    certification::rebuild();
+    start_trash_purge_timer();
+    recommendations::start_refresh_timer();
+    bandit::start_seeding();
+    associations::start_mining_timer();
+}
+
+// move items out of their former 1024-byte region and rebuild the email index after an upgrade so users
+// created before it existed are indexed, move free-form item categories into the taxonomy,
+// the certification tree lives on the heap and is rebuilt as well
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    migrate_item_storage();
+    rebuild_email_index();
+    search::build_index_if_empty();
+    taxonomy::migrate_items(ic_cdk::id(), time());
+    slope_one::build_if_empty();
+    embeddings::schedule_rebuild();
+    certification::rebuild();
+    start_trash_purge_timer();
+    recommendations::start_refresh_timer();
+    bandit::start_seeding();
+    associations::start_mining_timer();
 }
 
 // user payload
@@ -174,7 +324,10 @@ struct UserPayload {
 #[derive(candid::CandidType, Clone, Serialize, Deserialize)]
 struct ItemPayload {
     name: String,
+    // a category name is matched against the taxonomy, the item stays uncategorized when no category matches;
+    // category_id picks a category of the taxonomy instead
     category: String,
+    category_id: Option<u64>,
     description: String,
 }
 
@@ -186,12 +339,42 @@ struct UserPreferencePayload {
     rating: u64,
 }
 
+// user patch, only the provided fields are changed
+// expected_updated_at guards against lost updates: when set it must match the stored
+// updated_at (or created_at if the record was never updated)
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct UserPatch {
+    name: Option<String>,
+    email: Option<String>,
+    password: Option<String>,
+    expected_updated_at: Option<u64>,
+}
+
+// item patch, only the provided fields are changed
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ItemPatch {
+    name: Option<String>,
+    category: Option<String>,
+    category_id: Option<u64>,
+    description: Option<String>,
+    expected_updated_at: Option<u64>,
+}
+
+// user preference patch, only the provided fields are changed
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct UserPreferencePatch {
+    user_id: Option<u64>,
+    item_id: Option<u64>,
+    rating: Option<u64>,
+    expected_updated_at: Option<u64>,
+}
+
 
 // function to get all users
 #[ic_cdk::query]
 fn get_users() -> Result<Vec<User>,Error> {
 
-    let users = USER_STORAGE.with(|m| m.borrow().iter().map(|(_, v)| v.clone()).collect::<Vec<_>>());
+    let users = USER_STORAGE.with(|m| m.borrow().iter().map(|(_, v)| v).filter(|v| v.deleted_at.is_none()).collect::<Vec<_>>());
     if users.len() == 0 {
         return Err(Error::NotFound { msg: "No users found".to_string() });
     }
@@ -205,6 +388,7 @@ fn get_user_by_id(id: u64) -> Result<User,Error> {
         service
             .borrow_mut()
             .get(&id)
+            .filter(|record| record.deleted_at.is_none())
             .ok_or(Error::NotFound {
                 msg: format!("player with id={} not found", id),
             })
@@ -217,8 +401,11 @@ fn add_user(payload: UserPayload) -> Result<User,Error> {
 
     // validate user payload all fields are required
     if payload.name.is_empty() || payload.email.is_empty() || payload.password.is_empty() {
-        return Err(Error::NotFound { msg: "All fields are required".to_string() });
+        return Err(Error::InvalidInput { msg: "All fields are required".to_string() });
     }
+    let email = normalize_email(&payload.email)?;
+    ensure_email_available(&email, None)?;
+
     let id = USER_ID_COUNTER
     .with(|counter| {
         let current_value = *counter.borrow().get();
@@ -229,31 +416,53 @@ fn add_user(payload: UserPayload) -> Result<User,Error> {
     let user = User {
         id,
         name: payload.name,
-        email: payload.email,
+        email: email.clone(),
         password: payload.password,
         created_at: time(),
         updated_at: None,
+        deleted_at: None,
     };
     USER_STORAGE.with(|m| m.borrow_mut().insert(id, user.clone()));
+    EMAIL_INDEX.with(|m| m.borrow_mut().insert(EmailKey(email), id));
+    audit::record("add_user", vec![id], None, Some(&user));
     Ok(user)
 }
 
 // function to update user
 #[ic_cdk::update]
-fn update_user(id: u64, payload: UserPayload) -> Result<User,Error> {
+fn update_user(id: u64, patch: UserPatch) -> Result<User,Error> {
 
-    // validate user payload all fields are required
-    if payload.name.is_empty() || payload.email.is_empty() || payload.password.is_empty() {
-        return Err(Error::NotFound { msg: "All fields are required".to_string() });
+    // validate user patch provided fields cannot be empty
+    if [&patch.name, &patch.email, &patch.password].iter().any(|field| matches!(field, Some(value) if value.is_empty())) {
+        return Err(Error::InvalidInput { msg: "Provided fields cannot be empty".to_string() });
     }
+    let email = patch.email.as_deref().map(normalize_email).transpose()?;
 
-    match USER_STORAGE.with(|service| service.borrow().get(&id)) {
+    match USER_STORAGE.with(|service| service.borrow().get(&id)).filter(|record| record.deleted_at.is_none()) {
         Some(mut user) => {
-            user.name = payload.name;
-            user.email = payload.email;
-            user.password = payload.password;
+            let before = user.clone();
+            check_expected_updated_at(patch.expected_updated_at, user.created_at, user.updated_at, "user", id)?;
+            if let Some(email) = email {
+                ensure_email_available(&email, Some(id))?;
+                let previous_email = std::mem::replace(&mut user.email, email.clone());
+                EMAIL_INDEX.with(|m| {
+                    let mut index = m.borrow_mut();
+                    let previous_key = EmailKey::of(&previous_email);
+                    if index.get(&previous_key) == Some(id) {
+                        index.remove(&previous_key);
+                    }
+                    index.insert(EmailKey(email), id);
+                });
+            }
+            if let Some(name) = patch.name {
+                user.name = name;
+            }
+            if let Some(password) = patch.password {
+                user.password = password;
+            }
             user.updated_at = Some(time());
             USER_STORAGE.with(|m| m.borrow_mut().insert(id, user.clone()));
+            audit::record("update_user", vec![id], Some(&before), Some(&user));
             Ok(user)
         }
         None => Err(Error::NotFound {
@@ -262,19 +471,126 @@ fn update_user(id: u64, payload: UserPayload) -> Result<User,Error> {
     }
 }
 
-// function to delete user
+// function to delete user, the user is kept in the trash until it is purged
 #[ic_cdk::update]
 fn delete_user(id: u64) -> Result<(), Error>{
-    USER_STORAGE.with(|service| {
-        service
-            .borrow_mut()
-            .remove(&id)
-            .ok_or(Error::NotFound {
-                msg: format!("User with id={} not found", id),
-            })
-    })?;
-    remove_user_from_recommendation_system(id);
-    Ok(())
+    match USER_STORAGE.with(|service| service.borrow().get(&id)).filter(|record| record.deleted_at.is_none()) {
+        Some(mut user) => {
+            let before = user.clone();
+            user.deleted_at = Some(time());
+            USER_STORAGE.with(|m| m.borrow_mut().insert(id, user.clone()));
+            slope_one::remove_user(id);
+            recommendations::invalidate_user(id);
+            audit::record("delete_user", vec![id], Some(&before), Some(&user));
+            Ok(())
+        }
+        None => Err(Error::NotFound {
+            msg: format!("user with id={} not found", id),
+        }),
+    }
+}
+
+// function to restore a deleted user from the trash
+#[ic_cdk::update]
+fn restore_user(id: u64) -> Result<User, Error>{
+    match USER_STORAGE.with(|service| service.borrow().get(&id)).filter(|record| record.deleted_at.is_some()) {
+        Some(mut user) => {
+            let before = user.clone();
+            user.deleted_at = None;
+            user.updated_at = Some(time());
+            USER_STORAGE.with(|m| m.borrow_mut().insert(id, user.clone()));
+            slope_one::add_user(id);
+            audit::record("restore_user", vec![id], Some(&before), Some(&user));
+            Ok(user)
+        }
+        None => Err(Error::NotFound {
+            msg: format!("deleted user with id={} not found", id),
+        }),
+    }
+}
+
+// check that a user exists and is not in the trash
+fn user_is_active(id: u64) -> bool {
+    USER_STORAGE.with(|service| service.borrow().get(&id)).is_some_and(|record| record.deleted_at.is_none())
+}
+
+// normalize an email address (trimmed, lowercased) and validate its format
+fn normalize_email(email: &str) -> Result<String, Error> {
+    let email = email.trim().to_lowercase();
+    let invalid = || Error::InvalidInput {
+        msg: format!("invalid email address: {}", email),
+    };
+
+    if email.is_empty() || email.len() > MAX_EMAIL_LENGTH {
+        return Err(invalid());
+    }
+    let (local, domain) = email.split_once('@').ok_or_else(invalid)?;
+
+    // local part: 1..=64 chars of the common unquoted set, no leading/trailing/double dots
+    let local_ok = !local.is_empty()
+        && local.len() <= 64
+        && !local.starts_with('.')
+        && !local.ends_with('.')
+        && !local.contains("..")
+        && local
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+/=?^_`{|}~.-".contains(c));
+
+    // domain: at least two dot-separated labels of alphanumerics and inner hyphens
+    let labels: Vec<&str> = domain.split('.').collect();
+    let domain_ok = labels.len() >= 2
+        && labels.iter().all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        });
+
+    if local_ok && domain_ok {
+        Ok(email)
+    } else {
+        Err(invalid())
+    }
+}
+
+// check that a normalized email is not registered to another user
+fn ensure_email_available(email: &str, user_id: Option<u64>) -> Result<(), Error> {
+    match EMAIL_INDEX.with(|m| m.borrow().get(&EmailKey(email.to_string()))) {
+        Some(owner) if Some(owner) != user_id => Err(Error::AlreadyExists {
+            msg: format!("user with email={} already exists", email),
+        }),
+        _ => Ok(()),
+    }
+}
+
+// copy the items of the former 1024-byte region into ITEM_STORAGE, once: the former region is emptied
+fn migrate_item_storage() {
+    let items: Vec<(u64, Item)> =
+        LEGACY_ITEM_STORAGE.with(|m| m.borrow().iter().map(|(id, LegacyItem(item))| (id, item)).collect());
+    for (id, item) in items {
+        ITEM_STORAGE.with(|m| m.borrow_mut().insert(id, item));
+        LEGACY_ITEM_STORAGE.with(|m| m.borrow_mut().remove(&id));
+    }
+}
+
+// rebuild the email index from the users storage, the oldest user keeps a duplicated email
+fn rebuild_email_index() {
+    let users: Vec<(u64, String)> =
+        USER_STORAGE.with(|service| service.borrow().iter().map(|(id, user)| (id, user.email)).collect());
+    EMAIL_INDEX.with(|m| {
+        let mut index = m.borrow_mut();
+        let keys: Vec<EmailKey> = index.iter().map(|(key, _)| key).collect();
+        for key in keys {
+            index.remove(&key);
+        }
+        for (id, email) in users {
+            let key = EmailKey::of(&email);
+            if key.0.len() <= MAX_EMAIL_LENGTH && !index.contains_key(&key) {
+                index.insert(key, id);
+            }
+        }
+    });
 }
 
 // remove user from recommendation system
@@ -283,9 +599,7 @@ fn remove_user_from_recommendation_system(user_id: u64){
         RECOMMENDATION_SYSTEM_STORAGE.with(|service| service.borrow().iter().collect());
     let mut recommendation_systems: Vec<RecommendationSystem> = recomandations_listing.into_iter().map(|(_, v)| v).collect();
     for recommendation_system in recommendation_systems.iter_mut() {
-        let  users = recommendation_system.users.clone();
         recommendation_system.users.retain(|user| user.id != user_id);
-        recommendation_system.users = users;
         RECOMMENDATION_SYSTEM_STORAGE.with(|m| m.borrow_mut().insert(recommendation_system.id, recommendation_system.clone()));
     }
 }
@@ -294,7 +608,7 @@ fn remove_user_from_recommendation_system(user_id: u64){
 #[ic_cdk::query]
 fn get_items() -> Result<Vec<Item>,Error> {
 
-    let items = ITEM_STORAGE.with(|m| m.borrow().iter().map(|(_, v)| v.clone()).collect::<Vec<_>>());
+    let items = ITEM_STORAGE.with(|m| m.borrow().iter().map(|(_, v)| v).filter(|v| v.deleted_at.is_none()).collect::<Vec<_>>());
     if items.len() == 0 {
         return Err(Error::NotFound { msg: "No items found".to_string() });
     }
@@ -308,6 +622,7 @@ fn get_item_by_id(id: u64) -> Result<Item,Error> {
         service
             .borrow_mut()
             .get(&id)
+            .filter(|record| record.deleted_at.is_none())
             .ok_or(Error::NotFound {
                 msg: format!("item with id={} not found", id),
             })
@@ -317,11 +632,41 @@ fn get_item_by_id(id: u64) -> Result<Item,Error> {
 // function to add item
 #[ic_cdk::update]
 fn add_item(payload: ItemPayload) -> Result<Item,Error> {
+    validate_item_payload(&payload)?;
+    let item = insert_item(payload);
+    audit::record("add_item", vec![item.id], None, Some(&item));
+    Ok(item)
+}
+
+// validate item payload all fields are required, the category can be given by name or id
+fn validate_item_payload(payload: &ItemPayload) -> Result<(), Error> {
+    if payload.name.is_empty() || payload.category.trim().is_empty() && payload.category_id.is_none() || payload.description.is_empty() {
+        return Err(Error::InvalidInput { msg: "All fields are required".to_string() });
+    }
+    if let Some(category_id) = payload.category_id {
+        taxonomy::category_by_id(category_id)?;
+    }
+    check_item_fields(&payload.name, &payload.category, &payload.description)
+}
 
-    // validate item payload all fields are required
-    if payload.name.is_empty() || payload.category.is_empty() || payload.description.is_empty() {
-        return Err(Error::NotFound { msg: "All fields are required".to_string() });
+// field lengths that keep an item within its storage record
+fn check_item_fields(name: &str, category: &str, description: &str) -> Result<(), Error> {
+    if name.len() > MAX_ITEM_NAME_LENGTH
+        || category.len() > MAX_ITEM_NAME_LENGTH
+        || description.len() > MAX_ITEM_DESCRIPTION_LENGTH
+    {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "name and category can have at most {} bytes and description at most {} bytes",
+                MAX_ITEM_NAME_LENGTH, MAX_ITEM_DESCRIPTION_LENGTH
+            ),
+        });
     }
+    Ok(())
+}
+
+// store a new item from a validated payload
+fn insert_item(payload: ItemPayload) -> Item {
     let id = ITEM_ID_COUNTER
     .with(|counter| {
         let current_value = *counter.borrow().get();
@@ -329,34 +674,63 @@ fn add_item(payload: ItemPayload) -> Result<Item,Error> {
     })
     .expect("cannot increment id counter");
 
+    let (category_id, category) = taxonomy::classify(payload.category_id, &payload.category);
     let item = Item {
         id,
         name: payload.name,
-        category: payload.category,
+        category,
+        category_id,
         description: payload.description,
         created_at: time(),
         updated_at: None,
+        deleted_at: None,
     };
     ITEM_STORAGE.with(|m| m.borrow_mut().insert(id, item.clone()));
-    Ok(item)
+    search::index_item(&item);
+    certification::certify_item(&item);
+    item
 }
 
 // function to update item
 #[ic_cdk::update]
-fn update_item(id: u64, payload: ItemPayload) -> Result<Item,Error> {
+fn update_item(id: u64, patch: ItemPatch) -> Result<Item,Error> {
 
-    // validate item payload all fields are required
-    if payload.name.is_empty() || payload.category.is_empty() || payload.description.is_empty() {
-        return Err(Error::NotFound { msg: "All fields are required".to_string() });
+    // validate item patch provided fields cannot be empty
+    if [&patch.name, &patch.category, &patch.description].iter().any(|field| matches!(field, Some(value) if value.is_empty()))
+        || patch.category.as_ref().is_some_and(|category| category.trim().is_empty())
+    {
+        return Err(Error::InvalidInput { msg: "Provided fields cannot be empty".to_string() });
+    }
+    if let Some(category_id) = patch.category_id {
+        taxonomy::category_by_id(category_id)?;
     }
 
-    match ITEM_STORAGE.with(|service| service.borrow().get(&id)) {
+    match ITEM_STORAGE.with(|service| service.borrow().get(&id)).filter(|record| record.deleted_at.is_none()) {
         Some(mut item) => {
-            item.name = payload.name;
-            item.category = payload.category;
-            item.description = payload.description;
+            let before = item.clone();
+            check_expected_updated_at(patch.expected_updated_at, item.created_at, item.updated_at, "item", id)?;
+            check_item_fields(
+                patch.name.as_deref().unwrap_or(&item.name),
+                patch.category.as_deref().unwrap_or(&item.category),
+                patch.description.as_deref().unwrap_or(&item.description),
+            )?;
+            if let Some(name) = patch.name {
+                item.name = name;
+            }
+            if patch.category.is_some() || patch.category_id.is_some() {
+                let (category_id, category) = taxonomy::classify(patch.category_id, &patch.category.unwrap_or_default());
+                item.category = category;
+                item.category_id = category_id;
+            }
+            if let Some(description) = patch.description {
+                item.description = description;
+            }
             item.updated_at = Some(time());
             ITEM_STORAGE.with(|m| m.borrow_mut().insert(id, item.clone()));
+            search::remove_item(&before);
+            search::index_item(&item);
+            certification::certify_item(&item);
+            audit::record("update_item", vec![id], Some(&before), Some(&item));
             Ok(item)
         }
         None => Err(Error::NotFound {
@@ -365,20 +739,48 @@ fn update_item(id: u64, payload: ItemPayload) -> Result<Item,Error> {
     }
 }
 
-// function to delete item
+// function to delete item, the item is kept in the trash until it is purged
 #[ic_cdk::update]
-
 fn delete_item(id: u64) -> Result<(), Error>{
-    ITEM_STORAGE.with(|service| {
-        service
-            .borrow_mut()
-            .remove(&id)
-            .ok_or(Error::NotFound {
-                msg: format!("item with id={} not found", id),
-            })
-    })?;
-    remove_item_from_recommendation_system(id);
-    Ok(())
+    match ITEM_STORAGE.with(|service| service.borrow().get(&id)).filter(|record| record.deleted_at.is_none()) {
+        Some(mut item) => {
+            let before = item.clone();
+            item.deleted_at = Some(time());
+            ITEM_STORAGE.with(|m| m.borrow_mut().insert(id, item.clone()));
+            search::remove_item(&item);
+            certification::uncertify_item(id);
+            audit::record("delete_item", vec![id], Some(&before), Some(&item));
+            Ok(())
+        }
+        None => Err(Error::NotFound {
+            msg: format!("item with id={} not found", id),
+        }),
+    }
+}
+
+// function to restore a deleted item from the trash
+#[ic_cdk::update]
+fn restore_item(id: u64) -> Result<Item, Error>{
+    match ITEM_STORAGE.with(|service| service.borrow().get(&id)).filter(|record| record.deleted_at.is_some()) {
+        Some(mut item) => {
+            let before = item.clone();
+            item.deleted_at = None;
+            item.updated_at = Some(time());
+            ITEM_STORAGE.with(|m| m.borrow_mut().insert(id, item.clone()));
+            search::index_item(&item);
+            certification::certify_item(&item);
+            audit::record("restore_item", vec![id], Some(&before), Some(&item));
+            Ok(item)
+        }
+        None => Err(Error::NotFound {
+            msg: format!("deleted item with id={} not found", id),
+        }),
+    }
+}
+
+// check that a item exists and is not in the trash
+fn item_is_active(id: u64) -> bool {
+    ITEM_STORAGE.with(|service| service.borrow().get(&id)).is_some_and(|record| record.deleted_at.is_none())
 }
 
 // remove item from recommendation system
@@ -387,9 +789,7 @@ fn remove_item_from_recommendation_system(item_id: u64){
         RECOMMENDATION_SYSTEM_STORAGE.with(|service| service.borrow().iter().collect());
     let mut recommendation_systems: Vec<RecommendationSystem> = recomandations_listing.into_iter().map(|(_, v)| v).collect();
     for recommendation_system in recommendation_systems.iter_mut() {
-        let  items = recommendation_system.items.clone();
         recommendation_system.items.retain(|item| item.id != item_id);
-        recommendation_system.items = items;
         RECOMMENDATION_SYSTEM_STORAGE.with(|m| m.borrow_mut().insert(recommendation_system.id, recommendation_system.clone()));
     }
 }
@@ -398,7 +798,7 @@ fn remove_item_from_recommendation_system(item_id: u64){
 #[ic_cdk::query]
 fn get_user_preferences() -> Result<Vec<UserPreference>,Error> {
 
-    let user_preferences = USER_PREFERENCE_STORAGE.with(|m| m.borrow().iter().map(|(_, v)| v.clone()).collect::<Vec<_>>());
+    let user_preferences = USER_PREFERENCE_STORAGE.with(|m| m.borrow().iter().map(|(_, v)| v).filter(|v| v.deleted_at.is_none()).collect::<Vec<_>>());
     if user_preferences.len() == 0 {
         return Err(Error::NotFound { msg: "No user preferences found".to_string() });
     }
@@ -412,6 +812,7 @@ fn get_user_preference_by_id(id: u64) -> Result<UserPreference,Error> {
         service
             .borrow_mut()
             .get(&id)
+            .filter(|record| record.deleted_at.is_none())
             .ok_or(Error::NotFound {
                 msg: format!("user preference with id={} not found", id),
             })
@@ -421,11 +822,22 @@ fn get_user_preference_by_id(id: u64) -> Result<UserPreference,Error> {
 // function to add user preference
 #[ic_cdk::update]
 fn add_user_preference(payload: UserPreferencePayload) -> Result<UserPreference,Error> {
+    validate_user_preference_payload(&payload)?;
+    let user_preference = insert_user_preference(payload);
+    audit::record("add_user_preference", vec![user_preference.id], None, Some(&user_preference));
+    Ok(user_preference)
+}
 
-    // validate user preference payload all fields are required
+// validate user preference payload all fields are required
+fn validate_user_preference_payload(payload: &UserPreferencePayload) -> Result<(), Error> {
     if payload.rating == 0 {
-        return Err(Error::NotFound { msg: "All fields are required".to_string() });
+        return Err(Error::InvalidInput { msg: "All fields are required".to_string() });
     }
+    Ok(())
+}
+
+// store a new user preference from a validated payload
+fn insert_user_preference(payload: UserPreferencePayload) -> UserPreference {
     let id = USER_PREFERENCE_ID_COUNTER
     .with(|counter| {
         let current_value = *counter.borrow().get();
@@ -440,27 +852,49 @@ fn add_user_preference(payload: UserPreferencePayload) -> Result<UserPreference,
         rating: payload.rating,
         created_at: time(),
         updated_at: None,
+        deleted_at: None,
     };
     USER_PREFERENCE_STORAGE.with(|m| m.borrow_mut().insert(id, user_preference.clone()));
-    Ok(user_preference)
+    slope_one::add_rating(&user_preference);
+    recommendations::invalidate_user(user_preference.user_id);
+    user_preference
 }
 
 // function to update user preference
 #[ic_cdk::update]
-fn update_user_preference(id: u64, payload: UserPreferencePayload) -> Result<UserPreference,Error> {
+fn update_user_preference(id: u64, patch: UserPreferencePatch) -> Result<UserPreference,Error> {
 
-    // validate user preference payload all fields are required
-    if payload.rating == 0 {
-        return Err(Error::NotFound { msg: "All fields are required".to_string() });
+    // validate user preference patch rating cannot be zero
+    if patch.rating == Some(0) {
+        return Err(Error::InvalidInput { msg: "Rating must be greater than zero".to_string() });
     }
 
-    match USER_PREFERENCE_STORAGE.with(|service| service.borrow().get(&id)) {
+    match USER_PREFERENCE_STORAGE.with(|service| service.borrow().get(&id)).filter(|record| record.deleted_at.is_none()) {
         Some(mut user_preference) => {
-            user_preference.user_id = payload.user_id;
-            user_preference.item_id = payload.item_id;
-            user_preference.rating = payload.rating;
+            let before = user_preference.clone();
+            check_expected_updated_at(
+                patch.expected_updated_at,
+                user_preference.created_at,
+                user_preference.updated_at,
+                "user preference",
+                id,
+            )?;
+            if let Some(user_id) = patch.user_id {
+                user_preference.user_id = user_id;
+            }
+            if let Some(item_id) = patch.item_id {
+                user_preference.item_id = item_id;
+            }
+            if let Some(rating) = patch.rating {
+                user_preference.rating = rating;
+            }
             user_preference.updated_at = Some(time());
             USER_PREFERENCE_STORAGE.with(|m| m.borrow_mut().insert(id, user_preference.clone()));
+            slope_one::remove_rating(&before);
+            slope_one::add_rating(&user_preference);
+            recommendations::invalidate_user(before.user_id);
+            recommendations::invalidate_user(user_preference.user_id);
+            audit::record("update_user_preference", vec![id], Some(&before), Some(&user_preference));
             Ok(user_preference)
         }
         None => Err(Error::NotFound {
@@ -469,20 +903,68 @@ fn update_user_preference(id: u64, payload: UserPreferencePayload) -> Result<Use
     }
 }
 
-// function to delete user preference
+// optimistic concurrency check, the caller must have seen the latest version of the record
+fn check_expected_updated_at(
+    expected: Option<u64>,
+    created_at: u64,
+    updated_at: Option<u64>,
+    entity: &str,
+    id: u64,
+) -> Result<(), Error> {
+    let current = updated_at.unwrap_or(created_at);
+    match expected {
+        Some(expected) if expected != current => Err(Error::Conflict {
+            msg: format!(
+                "{} with id={} was modified at {}, expected {}",
+                entity, id, current, expected
+            ),
+        }),
+        _ => Ok(()),
+    }
+}
 
+// function to delete user preference, the user preference is kept in the trash until it is purged
 #[ic_cdk::update]
 fn delete_user_preference(id: u64) -> Result<(), Error>{
-    USER_PREFERENCE_STORAGE.with(|service| {
-        service
-            .borrow_mut()
-            .remove(&id)
-            .ok_or(Error::NotFound {
-                msg: format!("user preference with id={} not found", id),
-            })
-    })?;
-    remove_user_preference_from_recommendation_system(id);
-    Ok(())
+    match USER_PREFERENCE_STORAGE.with(|service| service.borrow().get(&id)).filter(|record| record.deleted_at.is_none()) {
+        Some(mut user_preference) => {
+            let before = user_preference.clone();
+            user_preference.deleted_at = Some(time());
+            USER_PREFERENCE_STORAGE.with(|m| m.borrow_mut().insert(id, user_preference.clone()));
+            slope_one::remove_rating(&before);
+            recommendations::invalidate_user(user_preference.user_id);
+            audit::record("delete_user_preference", vec![id], Some(&before), Some(&user_preference));
+            Ok(())
+        }
+        None => Err(Error::NotFound {
+            msg: format!("user preference with id={} not found", id),
+        }),
+    }
+}
+
+// function to restore a deleted user preference from the trash
+#[ic_cdk::update]
+fn restore_user_preference(id: u64) -> Result<UserPreference, Error>{
+    match USER_PREFERENCE_STORAGE.with(|service| service.borrow().get(&id)).filter(|record| record.deleted_at.is_some()) {
+        Some(mut user_preference) => {
+            let before = user_preference.clone();
+            user_preference.deleted_at = None;
+            user_preference.updated_at = Some(time());
+            USER_PREFERENCE_STORAGE.with(|m| m.borrow_mut().insert(id, user_preference.clone()));
+            slope_one::add_rating(&user_preference);
+            recommendations::invalidate_user(user_preference.user_id);
+            audit::record("restore_user_preference", vec![id], Some(&before), Some(&user_preference));
+            Ok(user_preference)
+        }
+        None => Err(Error::NotFound {
+            msg: format!("deleted user preference with id={} not found", id),
+        }),
+    }
+}
+
+// check that a user preference exists and is not in the trash
+fn user_preference_is_active(id: u64) -> bool {
+    USER_PREFERENCE_STORAGE.with(|service| service.borrow().get(&id)).is_some_and(|record| record.deleted_at.is_none())
 }
 
 // remove user preference from recommendation system
@@ -491,9 +973,7 @@ fn remove_user_preference_from_recommendation_system(user_preference_id: u64){
         RECOMMENDATION_SYSTEM_STORAGE.with(|service| service.borrow().iter().collect());
     let mut recommendation_systems: Vec<RecommendationSystem> = recomandations_listing.into_iter().map(|(_, v)| v).collect();
     for recommendation_system in recommendation_systems.iter_mut() {
-        let  user_preferences = recommendation_system.user_preferences.clone();
         recommendation_system.user_preferences.retain(|user_preference| user_preference.id != user_preference_id);
-        recommendation_system.user_preferences = user_preferences;
         RECOMMENDATION_SYSTEM_STORAGE.with(|m| m.borrow_mut().insert(recommendation_system.id, recommendation_system.clone()));
     }
 }
@@ -503,7 +983,7 @@ fn remove_user_preference_from_recommendation_system(user_preference_id: u64){
 #[ic_cdk::query]
 fn get_recommendation_systems() -> Result<Vec<RecommendationSystem>,Error> {
 
-    let recommendation_systems = RECOMMENDATION_SYSTEM_STORAGE.with(|m| m.borrow().iter().map(|(_, v)| v.clone()).collect::<Vec<_>>());
+    let recommendation_systems = RECOMMENDATION_SYSTEM_STORAGE.with(|m| m.borrow().iter().map(|(_, v)| v).filter(|v| v.deleted_at.is_none()).collect::<Vec<_>>());
     if recommendation_systems.len() == 0 {
         return Err(Error::NotFound { msg: "No recommendation systems found".to_string() });
     }
@@ -517,6 +997,7 @@ fn get_recommendation_system_by_id(id: u64) -> Result<RecommendationSystem,Error
         service
             .borrow_mut()
             .get(&id)
+            .filter(|record| record.deleted_at.is_none())
             .ok_or(Error::NotFound {
                 msg: format!("recommendation system with id={} not found", id),
             })
@@ -539,8 +1020,10 @@ fn add_recommendation_system() -> Result<RecommendationSystem,Error> {
         users: vec![],
         items: vec![],
         user_preferences: vec![],
+        deleted_at: None,
     };
     RECOMMENDATION_SYSTEM_STORAGE.with(|m| m.borrow_mut().insert(id, recommendation_system.clone()));
+    audit::record("add_recommendation_system", vec![id], None, Some(&recommendation_system));
     Ok(recommendation_system)
 }
 
@@ -548,9 +1031,10 @@ fn add_recommendation_system() -> Result<RecommendationSystem,Error> {
 #[ic_cdk::update]
 fn update_recommendation_system(id: u64) -> Result<RecommendationSystem,Error> {
 
-    match RECOMMENDATION_SYSTEM_STORAGE.with(|service| service.borrow().get(&id)) {
+    match RECOMMENDATION_SYSTEM_STORAGE.with(|service| service.borrow().get(&id)).filter(|record| record.deleted_at.is_none()) {
         Some( recommendation_system) => {
             RECOMMENDATION_SYSTEM_STORAGE.with(|m| m.borrow_mut().insert(id, recommendation_system.clone()));
+            audit::record("update_recommendation_system", vec![id], Some(&recommendation_system), Some(&recommendation_system));
             Ok(recommendation_system)
         }
         None => Err(Error::NotFound {
@@ -559,17 +1043,40 @@ fn update_recommendation_system(id: u64) -> Result<RecommendationSystem,Error> {
     }
 }
 
-// function to delete recommendation system
+// function to delete recommendation system, the system is kept in the trash until it is purged
 #[ic_cdk::update]
 fn delete_recommendation_system(id: u64) -> Result<RecommendationSystem, Error>{
-    match RECOMMENDATION_SYSTEM_STORAGE.with(|service| service.borrow_mut().remove(&id)) {
-        Some(recommendation_system) => Ok(recommendation_system),
+    match RECOMMENDATION_SYSTEM_STORAGE.with(|service| service.borrow().get(&id)).filter(|record| record.deleted_at.is_none()) {
+        Some(mut recommendation_system) => {
+            let before = recommendation_system.clone();
+            recommendation_system.deleted_at = Some(time());
+            RECOMMENDATION_SYSTEM_STORAGE.with(|m| m.borrow_mut().insert(id, recommendation_system.clone()));
+            audit::record("delete_recommendation_system", vec![id], Some(&before), Some(&recommendation_system));
+            Ok(recommendation_system)
+        }
         None => Err(Error::NotFound {
             msg: format!("recommendation system with id={} not found", id),
         }),
     }
 }
 
+// function to restore a deleted recommendation system from the trash
+#[ic_cdk::update]
+fn restore_recommendation_system(id: u64) -> Result<RecommendationSystem, Error>{
+    match RECOMMENDATION_SYSTEM_STORAGE.with(|service| service.borrow().get(&id)).filter(|record| record.deleted_at.is_some()) {
+        Some(mut recommendation_system) => {
+            let before = recommendation_system.clone();
+            recommendation_system.deleted_at = None;
+            RECOMMENDATION_SYSTEM_STORAGE.with(|m| m.borrow_mut().insert(id, recommendation_system.clone()));
+            audit::record("restore_recommendation_system", vec![id], Some(&before), Some(&recommendation_system));
+            Ok(recommendation_system)
+        }
+        None => Err(Error::NotFound {
+            msg: format!("deleted recommendation system with id={} not found", id),
+        }),
+    }
+}
+
 //add user to recommendation system
 #[ic_cdk::update]
 fn add_user_to_recommendation_system(recommendation_system_id: u64, user_id: u64) -> Result<RecommendationSystem,Error> {
@@ -577,6 +1084,7 @@ fn add_user_to_recommendation_system(recommendation_system_id: u64, user_id: u64
         service
             .borrow_mut()
             .get(&recommendation_system_id)
+            .filter(|record| record.deleted_at.is_none())
             .ok_or(Error::NotFound {
                 msg: format!("recommendation system with id={} not found", recommendation_system_id),
             })
@@ -586,14 +1094,22 @@ fn add_user_to_recommendation_system(recommendation_system_id: u64, user_id: u64
         service
             .borrow_mut()
             .get(&user_id)
+            .filter(|record| record.deleted_at.is_none())
             .ok_or(Error::NotFound {
                 msg: format!("user with id={} not found", user_id),
             })
     })?;
 
     let mut recommendation_system = recommendation_system.clone();
+    let before = recommendation_system.clone();
     recommendation_system.users.push(user);
     RECOMMENDATION_SYSTEM_STORAGE.with(|m| m.borrow_mut().insert(recommendation_system_id, recommendation_system.clone()));
+    audit::record(
+        "add_user_to_recommendation_system",
+        vec![recommendation_system_id, user_id],
+        Some(&before),
+        Some(&recommendation_system),
+    );
     Ok(recommendation_system)
     
 }
@@ -605,6 +1121,7 @@ fn add_item_to_recommendation_system(recommendation_system_id: u64, item_id: u64
         service
             .borrow_mut()
             .get(&recommendation_system_id)
+            .filter(|record| record.deleted_at.is_none())
             .ok_or(Error::NotFound {
                 msg: format!("recommendation system with id={} not found", recommendation_system_id),
             })
@@ -614,14 +1131,23 @@ fn add_item_to_recommendation_system(recommendation_system_id: u64, item_id: u64
         service
             .borrow_mut()
             .get(&item_id)
+            .filter(|record| record.deleted_at.is_none())
             .ok_or(Error::NotFound {
                 msg: format!("item with id={} not found", item_id),
             })
     })?;
+    attributes::check_item(recommendation_system_id, item_id)?;
 
     let mut recommendation_system = recommendation_system.clone();
+    let before = recommendation_system.clone();
     recommendation_system.items.push(item);
     RECOMMENDATION_SYSTEM_STORAGE.with(|m| m.borrow_mut().insert(recommendation_system_id, recommendation_system.clone()));
+    audit::record(
+        "add_item_to_recommendation_system",
+        vec![recommendation_system_id, item_id],
+        Some(&before),
+        Some(&recommendation_system),
+    );
     Ok(recommendation_system)
     
 }
@@ -633,6 +1159,7 @@ fn add_user_preference_to_recommendation_system(recommendation_system_id: u64, u
         service
             .borrow_mut()
             .get(&recommendation_system_id)
+            .filter(|record| record.deleted_at.is_none())
             .ok_or(Error::NotFound {
                 msg: format!("recommendation system with id={} not found", recommendation_system_id),
             })
@@ -642,14 +1169,22 @@ fn add_user_preference_to_recommendation_system(recommendation_system_id: u64, u
         service
             .borrow_mut()
             .get(&user_preference_id)
+            .filter(|record| record.deleted_at.is_none())
             .ok_or(Error::NotFound {
                 msg: format!("user preference with id={} not found", user_preference_id),
             })
     })?;
 
     let mut recommendation_system = recommendation_system.clone();
+    let before = recommendation_system.clone();
     recommendation_system.user_preferences.push(user_preference);
     RECOMMENDATION_SYSTEM_STORAGE.with(|m| m.borrow_mut().insert(recommendation_system_id, recommendation_system.clone()));
+    audit::record(
+        "add_user_preference_to_recommendation_system",
+        vec![recommendation_system_id, user_preference_id],
+        Some(&before),
+        Some(&recommendation_system),
+    );
     Ok(recommendation_system)
     
 }
@@ -662,12 +1197,13 @@ fn get_users_in_recommendation_system(recommendation_system_id: u64) -> Result<V
         service
             .borrow_mut()
             .get(&recommendation_system_id)
+            .filter(|record| record.deleted_at.is_none())
             .ok_or(Error::NotFound {
                 msg: format!("recommendation system with id={} not found", recommendation_system_id),
             })
     })?;
 
-    let users = recommendation_system.users.clone();
+    let users: Vec<_> = recommendation_system.users.into_iter().filter(|user| user_is_active(user.id)).collect();
     if users.len() == 0 {
         return Err(Error::NotFound { msg: "No users found".to_string() });
     }
@@ -682,12 +1218,13 @@ fn get_items_in_recommendation_system(recommendation_system_id: u64) -> Result<V
         service
             .borrow_mut()
             .get(&recommendation_system_id)
+            .filter(|record| record.deleted_at.is_none())
             .ok_or(Error::NotFound {
                 msg: format!("recommendation system with id={} not found", recommendation_system_id),
             })
     })?;
 
-    let items = recommendation_system.items.clone();
+    let items: Vec<_> = recommendation_system.items.into_iter().filter(|item| item_is_active(item.id)).collect();
     if items.len() == 0 {
         return Err(Error::NotFound { msg: "No items found".to_string() });
     }
@@ -702,12 +1239,13 @@ fn get_user_preferences_in_recommendation_system(recommendation_system_id: u64)
         service
             .borrow_mut()
             .get(&recommendation_system_id)
+            .filter(|record| record.deleted_at.is_none())
             .ok_or(Error::NotFound {
                 msg: format!("recommendation system with id={} not found", recommendation_system_id),
             })
     })?;
 
-    let user_preferences = recommendation_system.user_preferences.clone();
+    let user_preferences: Vec<_> = recommendation_system.user_preferences.into_iter().filter(|user_preference| user_preference_is_active(user_preference.id)).collect();
     if user_preferences.len() == 0 {
         return Err(Error::NotFound { msg: "No user preferences found".to_string() });
     }
@@ -716,10 +1254,274 @@ fn get_user_preferences_in_recommendation_system(recommendation_system_id: u64)
 
 
 
-#[derive(candid::CandidType, Deserialize, Serialize)]
+// soft-deleted records waiting to be purged
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Trash {
+    users: Vec<User>,
+    items: Vec<Item>,
+    user_preferences: Vec<UserPreference>,
+    recommendation_systems: Vec<RecommendationSystem>,
+}
+
+// function to get all soft-deleted records
+#[ic_cdk::query]
+fn get_trash() -> Trash {
+    Trash {
+        users: USER_STORAGE.with(|m| m.borrow().iter().map(|(_, v)| v).filter(|v| v.deleted_at.is_some()).collect()),
+        items: ITEM_STORAGE.with(|m| m.borrow().iter().map(|(_, v)| v).filter(|v| v.deleted_at.is_some()).collect()),
+        user_preferences: USER_PREFERENCE_STORAGE
+            .with(|m| m.borrow().iter().map(|(_, v)| v).filter(|v| v.deleted_at.is_some()).collect()),
+        recommendation_systems: RECOMMENDATION_SYSTEM_STORAGE
+            .with(|m| m.borrow().iter().map(|(_, v)| v).filter(|v| v.deleted_at.is_some()).collect()),
+    }
+}
+
+// function to get the trash retention period in seconds
+#[ic_cdk::query]
+fn get_trash_retention() -> u64 {
+    TRASH_RETENTION.with(|cell| *cell.borrow().get())
+}
+
+// function to set the trash retention period in seconds, restricted to controllers
+#[ic_cdk::update]
+fn set_trash_retention(retention_secs: u64) -> Result<u64, Error> {
+    ensure_controller("change the trash retention")?;
+    let previous = get_trash_retention();
+    TRASH_RETENTION
+        .with(|cell| cell.borrow_mut().set(retention_secs))
+        .expect("cannot set the trash retention");
+    audit::record("set_trash_retention", vec![], Some(&previous), Some(&retention_secs));
+    Ok(retention_secs)
+}
+
+// check that the caller is a controller of the canister
+fn ensure_controller(action: &str) -> Result<(), Error> {
+    if ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized { msg: format!("only controllers can {}", action) })
+    }
+}
+
+fn start_trash_purge_timer() {
+    ic_cdk_timers::set_timer_interval(TRASH_PURGE_INTERVAL, || {
+        let purged = purge_trash(time());
+        let canister = ic_cdk::id();
+        for user in &purged.users {
+            audit::record_as(canister, "purge_trash", vec![user.id], Some(user), None);
+        }
+        for item in &purged.items {
+            audit::record_as(canister, "purge_trash", vec![item.id], Some(item), None);
+        }
+        for user_preference in &purged.user_preferences {
+            audit::record_as(canister, "purge_trash", vec![user_preference.id], Some(user_preference), None);
+        }
+        for recommendation_system in &purged.recommendation_systems {
+            audit::record_as(canister, "purge_trash", vec![recommendation_system.id], Some(recommendation_system), None);
+        }
+    });
+}
+
+// hard-delete every record that has been in the trash longer than the retention period, returns the purged records
+fn purge_trash(now: u64) -> Trash {
+    let retention_nanos = get_trash_retention().saturating_mul(1_000_000_000);
+    let expired = |deleted_at: Option<u64>| deleted_at.is_some_and(|deleted_at| deleted_at.saturating_add(retention_nanos) <= now);
+
+    let users: Vec<User> = USER_STORAGE
+        .with(|m| m.borrow().iter().map(|(_, v)| v).filter(|v| expired(v.deleted_at)).collect());
+    for user in &users {
+        USER_STORAGE.with(|m| m.borrow_mut().remove(&user.id));
+        EMAIL_INDEX.with(|m| {
+            let mut index = m.borrow_mut();
+            let key = EmailKey::of(&user.email);
+            if index.get(&key) == Some(user.id) {
+                index.remove(&key);
+            }
+        });
+        remove_user_from_recommendation_system(user.id);
+        profiles::remove_user(user.id);
+    }
+
+    let items: Vec<Item> = ITEM_STORAGE
+        .with(|m| m.borrow().iter().map(|(_, v)| v).filter(|v| expired(v.deleted_at)).collect());
+    for item in &items {
+        ITEM_STORAGE.with(|m| m.borrow_mut().remove(&item.id));
+        remove_item_from_recommendation_system(item.id);
+        attributes::remove_item(item.id);
+        embeddings::remove_item(item.id);
+    }
+
+    let user_preferences: Vec<UserPreference> = USER_PREFERENCE_STORAGE
+        .with(|m| m.borrow().iter().map(|(_, v)| v).filter(|v| expired(v.deleted_at)).collect());
+    for user_preference in &user_preferences {
+        USER_PREFERENCE_STORAGE.with(|m| m.borrow_mut().remove(&user_preference.id));
+        remove_user_preference_from_recommendation_system(user_preference.id);
+    }
+
+    let recommendation_systems: Vec<RecommendationSystem> = RECOMMENDATION_SYSTEM_STORAGE
+        .with(|m| m.borrow().iter().map(|(_, v)| v).filter(|v| expired(v.deleted_at)).collect());
+    for recommendation_system in &recommendation_systems {
+        RECOMMENDATION_SYSTEM_STORAGE.with(|m| m.borrow_mut().remove(&recommendation_system.id));
+    }
+
+    Trash { users, items, user_preferences, recommendation_systems }
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize, Clone, Debug)]
 enum  Error {
     NotFound { msg: String },
+    AlreadyExists { msg: String },
+    InvalidInput { msg: String },
+    Conflict { msg: String },
+    Unauthorized { msg: String },
+    Aborted { msg: String },
 }
 
 // Export the candid interface
-ic_cdk::export_candid!();
\ No newline at end of file
+ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_email_trims_and_lowercases() {
+        assert_eq!(normalize_email("  Jane.Doe@Example.COM \n").unwrap(), "jane.doe@example.com");
+        assert_eq!(normalize_email("a+tag@sub.example.co.uk").unwrap(), "a+tag@sub.example.co.uk");
+    }
+
+    #[test]
+    fn normalize_email_rejects_malformed_addresses() {
+        let long_local = format!("{}@example.com", "a".repeat(65));
+        let long_email = format!("a@{}.com", "b".repeat(250));
+        for email in [
+            "", "   ", "plainaddress", "@example.com", "jane@", "jane@example", "jane@@example.com",
+            "jane@exa mple.com", "jane doe@example.com", ".jane@example.com", "jane.@example.com",
+            "ja..ne@example.com", "jane@example..com", "jane@-example.com", "jane@example-.com",
+            "jane@.example.com", "jane@example.com.", "jané@example.com", &long_local, &long_email,
+        ] {
+            assert!(
+                matches!(normalize_email(email), Err(Error::InvalidInput { .. })),
+                "expected {:?} to be rejected",
+                email
+            );
+        }
+    }
+
+    #[test]
+    fn item_fields_are_bounded() {
+        let payload = |description: String| ItemPayload {
+            name: "Dune".to_string(),
+            category: "Books".to_string(),
+            category_id: None,
+            description,
+        };
+        assert!(validate_item_payload(&payload("a".repeat(MAX_ITEM_DESCRIPTION_LENGTH))).is_ok());
+        assert!(matches!(
+            validate_item_payload(&payload("a".repeat(MAX_ITEM_DESCRIPTION_LENGTH + 1))),
+            Err(Error::InvalidInput { .. })
+        ));
+
+        let largest = Item {
+            id: u64::MAX,
+            name: "a".repeat(MAX_ITEM_NAME_LENGTH),
+            category: "a".repeat(MAX_ITEM_NAME_LENGTH),
+            category_id: Some(u64::MAX),
+            description: "a".repeat(MAX_ITEM_DESCRIPTION_LENGTH),
+            created_at: u64::MAX,
+            updated_at: Some(u64::MAX),
+            deleted_at: Some(u64::MAX),
+        };
+        assert!(Encode!(&largest).unwrap().len() <= Item::MAX_SIZE as usize);
+    }
+
+    #[test]
+    fn legacy_items_move_to_the_larger_region() {
+        let item = Item { id: 3, name: "Dune".to_string(), ..Default::default() };
+        LEGACY_ITEM_STORAGE.with(|m| m.borrow_mut().insert(3, LegacyItem(item)));
+
+        migrate_item_storage();
+        assert_eq!(ITEM_STORAGE.with(|m| m.borrow().get(&3)).unwrap().name, "Dune");
+        assert!(LEGACY_ITEM_STORAGE.with(|m| m.borrow().is_empty()));
+    }
+
+    #[test]
+    fn email_index_enforces_uniqueness() {
+        EMAIL_INDEX.with(|m| m.borrow_mut().insert(EmailKey("jane@example.com".to_string()), 1));
+
+        assert!(matches!(
+            ensure_email_available("jane@example.com", None),
+            Err(Error::AlreadyExists { .. })
+        ));
+        assert!(matches!(
+            ensure_email_available("jane@example.com", Some(2)),
+            Err(Error::AlreadyExists { .. })
+        ));
+        // the owner of the email may keep it on update
+        assert!(ensure_email_available("jane@example.com", Some(1)).is_ok());
+        assert!(ensure_email_available("john@example.com", None).is_ok());
+    }
+
+    #[test]
+    fn expected_updated_at_detects_lost_updates() {
+        // never updated records are versioned by created_at
+        assert!(check_expected_updated_at(Some(10), 10, None, "item", 1).is_ok());
+        assert!(check_expected_updated_at(Some(20), 10, Some(20), "item", 1).is_ok());
+        assert!(check_expected_updated_at(None, 10, Some(20), "item", 1).is_ok());
+        assert!(matches!(
+            check_expected_updated_at(Some(10), 10, Some(20), "item", 1),
+            Err(Error::Conflict { .. })
+        ));
+    }
+
+    #[test]
+    fn purge_trash_removes_expired_records_only() {
+        let day = 24 * 60 * 60 * 1_000_000_000;
+        let now = 40 * day;
+        for (id, email, deleted_at) in [
+            (0, "active@example.com", None),
+            (1, "recent@example.com", Some(now - day)),
+            // stored before email addresses were normalized
+            (2, " Expired@Example.com", Some(now - 31 * day)),
+        ] {
+            let user = User { id, email: email.to_string(), deleted_at, ..Default::default() };
+            USER_STORAGE.with(|m| m.borrow_mut().insert(id, user));
+            EMAIL_INDEX.with(|m| m.borrow_mut().insert(EmailKey::of(email), id));
+        }
+        let system = RecommendationSystem {
+            id: 0,
+            users: (0..3).map(|id| User { id, ..Default::default() }).collect(),
+            ..Default::default()
+        };
+        RECOMMENDATION_SYSTEM_STORAGE.with(|m| m.borrow_mut().insert(0, system));
+
+        let purged = purge_trash(now);
+        assert_eq!(purged.users.iter().map(|user| user.id).collect::<Vec<_>>(), vec![2]);
+
+        let users: Vec<u64> = USER_STORAGE.with(|m| m.borrow().iter().map(|(id, _)| id).collect());
+        assert_eq!(users, vec![0, 1]);
+        assert!(ensure_email_available("expired@example.com", None).is_ok());
+        // users in the trash keep their email reserved so they can be restored
+        assert!(ensure_email_available("recent@example.com", None).is_err());
+        let system = RECOMMENDATION_SYSTEM_STORAGE.with(|m| m.borrow().get(&0)).unwrap();
+        assert_eq!(system.users.iter().map(|user| user.id).collect::<Vec<_>>(), vec![0, 1]);
+    }
+
+    #[test]
+    fn rebuild_email_index_keeps_oldest_duplicate() {
+        for (id, email) in [(0, "Jane@Example.com"), (1, "jane@example.com "), (2, "john@example.com")] {
+            let user = User { id, email: email.to_string(), ..Default::default() };
+            USER_STORAGE.with(|m| m.borrow_mut().insert(id, user));
+        }
+        EMAIL_INDEX.with(|m| m.borrow_mut().insert(EmailKey("stale@example.com".to_string()), 9));
+
+        rebuild_email_index();
+
+        let index: Vec<(String, u64)> =
+            EMAIL_INDEX.with(|m| m.borrow().iter().map(|(key, id)| (key.0, id)).collect());
+        assert_eq!(
+            index,
+            vec![("jane@example.com".to_string(), 0), ("john@example.com".to_string(), 2)]
+        );
+    }
+}
\ No newline at end of file
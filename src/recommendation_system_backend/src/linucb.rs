@@ -0,0 +1,278 @@
+use crate::recommendations::{Ratings, ScoredItem};
+use crate::search::{stem, tokenize};
+use crate::{Item, Memory, ITEM_STORAGE, MEMORY_MANAGER};
+use candid::{CandidType, Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::collections::HashMap;
+use std::{borrow::Cow, cell::RefCell};
+
+// hashed one-hot category features
+const CATEGORY_BUCKETS: usize = 8;
+
+// hashed TF-IDF features of the description
+const TERM_BUCKETS: usize = 8;
+
+// bias, category affinity, profile similarity and popularity
+const CONTEXT_FEATURES: usize = 4;
+
+const DIMENSIONS: usize = CATEGORY_BUCKETS + TERM_BUCKETS + CONTEXT_FEATURES;
+
+pub(crate) const DEFAULT_ALPHA: f64 = 1.0;
+
+// shared ridge regression of a recommendation system, A starts as the identity and b as zero;
+// the inverse of A is kept up to date with Sherman-Morrison so scoring needs no inversion
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug, PartialEq)]
+struct LinUcbModel {
+    // row-major DIMENSIONS x DIMENSIONS
+    a_inverse: Vec<f64>,
+    b: Vec<f64>,
+}
+
+impl Default for LinUcbModel {
+    fn default() -> Self {
+        let mut a_inverse = vec![0.0; DIMENSIONS * DIMENSIONS];
+        for i in 0..DIMENSIONS {
+            a_inverse[i * DIMENSIONS + i] = 1.0;
+        }
+        LinUcbModel { a_inverse, b: vec![0.0; DIMENSIONS] }
+    }
+}
+
+impl Storable for LinUcbModel {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for LinUcbModel {
+    const MAX_SIZE: u32 = 8192;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl LinUcbModel {
+    // A^-1 x
+    fn solve(&self, x: &[f64]) -> Vec<f64> {
+        (0..DIMENSIONS)
+            .map(|i| (0..DIMENSIONS).map(|j| self.a_inverse[i * DIMENSIONS + j] * x[j]).sum())
+            .collect()
+    }
+
+    // expected reward plus the upper confidence bound
+    fn score(&self, x: &[f64], alpha: f64) -> f64 {
+        let theta = self.solve(&self.b);
+        let a_inverse_x = self.solve(x);
+        let mean: f64 = theta.iter().zip(x).map(|(t, x)| t * x).sum();
+        let variance: f64 = x.iter().zip(&a_inverse_x).map(|(x, y)| x * y).sum();
+        mean + alpha * variance.max(0.0).sqrt()
+    }
+
+    fn update(&mut self, x: &[f64], reward: f64) {
+        let a_inverse_x = self.solve(x);
+        let denominator = 1.0 + x.iter().zip(&a_inverse_x).map(|(x, y)| x * y).sum::<f64>();
+        // A^-1 is symmetric so x^T A^-1 is the transpose of A^-1 x
+        for i in 0..DIMENSIONS {
+            for j in 0..DIMENSIONS {
+                self.a_inverse[i * DIMENSIONS + j] -= a_inverse_x[i] * a_inverse_x[j] / denominator;
+            }
+        }
+        for (b, x) in self.b.iter_mut().zip(x) {
+            *b += reward * x;
+        }
+    }
+}
+
+thread_local! {
+    // recommendation system id -> model
+    static MODELS: RefCell<StableBTreeMap<u64, LinUcbModel, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26))))
+    );
+}
+
+// FNV-1a, stable across upgrades unlike the std hasher
+fn bucket(value: &str, buckets: usize) -> usize {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in value.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    (hash % buckets as u64) as usize
+}
+
+fn normalize(vector: &mut HashMap<String, f64>) {
+    let norm = vector.values().map(|x| x * x).sum::<f64>().sqrt();
+    if norm > 0.0 {
+        vector.values_mut().for_each(|x| *x /= norm);
+    }
+}
+
+// what is known about the items of a recommendation system and one of its users
+pub(crate) struct FeatureContext {
+    items: HashMap<u64, Item>,
+    // stemmed term -> inverse document frequency over the descriptions of the items
+    idf: HashMap<String, f64>,
+    // category -> user's mean rating in it relative to the user's highest rating
+    affinity: HashMap<String, f64>,
+    // normalized sum of the TF-IDF vectors of the items the user liked
+    profile: HashMap<String, f64>,
+    // item id -> share of the users who rated it
+    popularity: HashMap<u64, f64>,
+}
+
+impl FeatureContext {
+    pub(crate) fn new(ratings: &Ratings, user_id: u64) -> Self {
+        let items: HashMap<u64, Item> =
+            ratings.items.iter().filter_map(|id| ITEM_STORAGE.with(|m| m.borrow().get(id))).map(|item| (item.id, item)).collect();
+
+        let mut document_frequency: HashMap<String, f64> = HashMap::new();
+        for item in items.values() {
+            let mut terms: Vec<String> = tokenize(&item.description).iter().map(|word| stem(word)).collect();
+            terms.sort();
+            terms.dedup();
+            for term in terms {
+                *document_frequency.entry(term).or_insert(0.0) += 1.0;
+            }
+        }
+        let documents = items.len() as f64;
+        let idf = document_frequency
+            .into_iter()
+            .map(|(term, frequency)| (term, ((documents + 1.0) / (frequency + 1.0)).ln() + 1.0))
+            .collect();
+
+        let users = ratings.by_user.len().max(1) as f64;
+        let popularity = ratings
+            .by_item()
+            .into_iter()
+            .map(|(item_id, raters)| (item_id, raters.len() as f64 / users))
+            .collect();
+
+        let mut context = FeatureContext { items, idf, affinity: HashMap::new(), profile: HashMap::new(), popularity };
+        let no_ratings = HashMap::new();
+        let user_ratings = ratings.by_user.get(&user_id).unwrap_or(&no_ratings);
+        let max_rating = user_ratings.values().copied().fold(0.0, f64::max);
+        let mean = user_ratings.values().sum::<f64>() / user_ratings.len().max(1) as f64;
+        let mut totals: HashMap<String, (f64, f64)> = HashMap::new();
+        for (item_id, &rating) in user_ratings {
+            let Some(item) = context.items.get(item_id) else {
+                continue;
+            };
+            let total = totals.entry(item.category.trim().to_lowercase()).or_insert((0.0, 0.0));
+            total.0 += rating;
+            total.1 += 1.0;
+            if rating >= mean {
+                for (term, weight) in context.tf_idf(item) {
+                    *context.profile.entry(term).or_insert(0.0) += weight;
+                }
+            }
+        }
+        normalize(&mut context.profile);
+        context.affinity = totals.into_iter().map(|(category, (sum, count))| (category, sum / count / max_rating)).collect();
+        context
+    }
+
+    fn tf_idf(&self, item: &Item) -> HashMap<String, f64> {
+        let mut vector: HashMap<String, f64> = HashMap::new();
+        for word in tokenize(&item.description) {
+            let term = stem(&word);
+            let idf = self.idf.get(&term).copied().unwrap_or(1.0);
+            *vector.entry(term).or_insert(0.0) += idf;
+        }
+        normalize(&mut vector);
+        vector
+    }
+
+    pub(crate) fn features(&self, item: &Item) -> Vec<f64> {
+        let mut x = vec![0.0; DIMENSIONS];
+        let category = item.category.trim().to_lowercase();
+        x[bucket(&category, CATEGORY_BUCKETS)] = 1.0;
+        let tf_idf = self.tf_idf(item);
+        for (term, weight) in &tf_idf {
+            x[CATEGORY_BUCKETS + bucket(term, TERM_BUCKETS)] += weight;
+        }
+        let context = CATEGORY_BUCKETS + TERM_BUCKETS;
+        x[context] = 1.0;
+        x[context + 1] = self.affinity.get(&category).copied().unwrap_or(0.0);
+        x[context + 2] = tf_idf.iter().map(|(term, weight)| weight * self.profile.get(term).copied().unwrap_or(0.0)).sum();
+        x[context + 3] = self.popularity.get(&item.id).copied().unwrap_or(0.0);
+        x
+    }
+}
+
+fn model_of(recommendation_system_id: u64) -> LinUcbModel {
+    MODELS.with(|m| m.borrow().get(&recommendation_system_id)).unwrap_or_default()
+}
+
+// top-N items by upper confidence bound
+pub(crate) fn recommend(
+    recommendation_system_id: u64,
+    ratings: &Ratings,
+    user_id: u64,
+    n: usize,
+    exclude_rated: bool,
+    alpha: f64,
+) -> Vec<ScoredItem> {
+    let model = model_of(recommendation_system_id);
+    let context = FeatureContext::new(ratings, user_id);
+    let rated = ratings.by_user.get(&user_id);
+    let mut scores: Vec<(u64, f64)> = context
+        .items
+        .values()
+        .filter(|item| !(exclude_rated && rated.is_some_and(|rated| rated.contains_key(&item.id))))
+        .map(|item| (item.id, model.score(&context.features(item), alpha)))
+        .collect();
+    scores.sort_by(|(a, a_score), (b, b_score)| b_score.total_cmp(a_score).then(a.cmp(b)));
+    scores.truncate(n);
+    scores.into_iter().map(|(item_id, score)| ScoredItem { item_id, score }).collect()
+}
+
+// update the model with the reward observed for items shown to a user
+pub(crate) fn reward(recommendation_system_id: u64, ratings: &Ratings, user_id: u64, item_ids: &[u64], reward: f64) {
+    let mut model = model_of(recommendation_system_id);
+    let context = FeatureContext::new(ratings, user_id);
+    for item in item_ids.iter().filter_map(|id| context.items.get(id)) {
+        model.update(&context.features(item), reward);
+    }
+    MODELS.with(|m| m.borrow_mut().insert(recommendation_system_id, model));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn sherman_morrison_matches_the_ridge_solution() {
+        let mut model = LinUcbModel::default();
+        let mut x = vec![0.0; DIMENSIONS];
+        x[0] = 1.0;
+        x[1] = 2.0;
+        model.update(&x, 1.0);
+
+        // A = I + x x^T, so A^-1 x = x / (1 + |x|^2) and theta = A^-1 b = x / 6
+        let theta = model.solve(&model.b);
+        assert!((theta[0] - 1.0 / 6.0).abs() < 1e-12);
+        assert!((theta[1] - 2.0 / 6.0).abs() < 1e-12);
+        assert!(model.score(&x, 0.0) < model.score(&x, 1.0));
+    }
+
+    #[test]
+    fn rewards_steer_the_ranking() {
+        for (id, category) in [(1, "Books"), (2, "Music"), (3, "Books"), (4, "Music")] {
+            let item = Item { id, category: category.to_string(), description: format!("item {}", id), ..Default::default() };
+            ITEM_STORAGE.with(|m| m.borrow_mut().insert(id, item));
+        }
+        let ratings = Ratings { items: BTreeSet::from([1, 2, 3, 4]), by_user: HashMap::new() };
+        for _ in 0..10 {
+            reward(1, &ratings, 1, &[1], 1.0);
+            reward(1, &ratings, 1, &[2], 0.0);
+        }
+
+        let ids: Vec<u64> = recommend(1, &ratings, 1, 4, true, 0.1).iter().map(|scored| scored.item_id).collect();
+        assert_eq!(&ids[..2], &[1, 3]);
+        assert_eq!(model_of(2), LinUcbModel::default());
+    }
+}
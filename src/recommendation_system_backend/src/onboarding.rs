@@ -0,0 +1,217 @@
+use crate::recommendations::{get_recommendations, load_ratings, Ratings, RecommendationRequest, RecommendationResponse};
+use crate::{
+    audit, insert_user_preference, item_is_active, taxonomy, user_is_active, Error, Item, RecommendationSystem,
+    UserPreferencePayload, ITEM_STORAGE, RECOMMENDATION_SYSTEM_STORAGE,
+};
+use candid::CandidType;
+use std::collections::{HashMap, HashSet};
+
+const DEFAULT_ONBOARDING_ITEMS: u64 = 10;
+const MAX_ONBOARDING_ITEMS: u64 = 20;
+
+// keeps items nobody rated yet selectable, by popularity and category coverage alone
+const ENTROPY_PRIOR: f64 = 0.1;
+
+// the score of an item is multiplied by this for every item of its category already picked
+const COVERAGE_DECAY: f64 = 0.5;
+
+// what category coverage counts: the category of the taxonomy, or the free-form category of uncategorized items
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+enum CategoryKey {
+    Taxonomy(u64),
+    FreeForm(String),
+}
+
+impl CategoryKey {
+    fn of(item: &Item) -> Self {
+        match item.category_id {
+            Some(category_id) => CategoryKey::Taxonomy(category_id),
+            None => CategoryKey::FreeForm(taxonomy::name_key(&item.category)),
+        }
+    }
+}
+
+// an item to ask a new user to rate, with why it is informative
+#[derive(CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct OnboardingItem {
+    item: Item,
+    rating_count: u64,
+    // entropy of the item's rating distribution in bits, high for polarizing items
+    entropy: f64,
+    score: f64,
+}
+
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug)]
+pub(crate) struct OnboardingRating {
+    item_id: u64,
+    rating: u64,
+}
+
+fn active_recommendation_system(id: u64) -> Result<RecommendationSystem, Error> {
+    RECOMMENDATION_SYSTEM_STORAGE
+        .with(|service| service.borrow().get(&id))
+        .filter(|record| record.deleted_at.is_none())
+        .ok_or(Error::NotFound { msg: format!("recommendation system with id={} not found", id) })
+}
+
+// Shannon entropy of the distribution of the ratings, rounded to whole stars
+fn entropy(ratings: &HashMap<u64, f64>) -> f64 {
+    let mut counts: HashMap<i64, f64> = HashMap::new();
+    for rating in ratings.values() {
+        *counts.entry(rating.round() as i64).or_insert(0.0) += 1.0;
+    }
+    let total = ratings.len() as f64;
+    counts.values().map(|count| count / total).map(|p| -p * p.log2()).sum()
+}
+
+// greedy pick of k items the user did not rate: log popularity times rating entropy, so items many users
+// rated and disagree on come first, decayed for categories already covered
+fn select(
+    ratings: &Ratings,
+    categories: &HashMap<u64, CategoryKey>,
+    user_id: u64,
+    k: usize,
+) -> Vec<(u64, u64, f64, f64)> {
+    let by_item = ratings.by_item();
+    let rated = ratings.by_user.get(&user_id);
+    let mut candidates: Vec<(u64, u64, f64, f64)> = ratings
+        .items
+        .iter()
+        .filter(|item_id| !rated.is_some_and(|rated| rated.contains_key(item_id)))
+        .map(|&item_id| {
+            let item_ratings = by_item.get(&item_id);
+            let count = item_ratings.map_or(0, |ratings| ratings.len()) as u64;
+            let entropy = item_ratings.map_or(0.0, entropy);
+            (item_id, count, entropy, (2.0 + count as f64).ln() * (entropy + ENTROPY_PRIOR))
+        })
+        .collect();
+
+    let mut covered: HashMap<Option<&CategoryKey>, i32> = HashMap::new();
+    let mut selected = vec![];
+    while selected.len() < k && !candidates.is_empty() {
+        let decayed = |(item_id, _, _, score): &(u64, u64, f64, f64)| {
+            score * COVERAGE_DECAY.powi(covered.get(&categories.get(item_id)).copied().unwrap_or(0))
+        };
+        let mut best = 0;
+        for index in 1..candidates.len() {
+            let (value, best_value) = (decayed(&candidates[index]), decayed(&candidates[best]));
+            if value > best_value || value == best_value && candidates[index].0 < candidates[best].0 {
+                best = index;
+            }
+        }
+        let (item_id, count, entropy, _) = candidates[best];
+        let score = decayed(&candidates[best]);
+        candidates.swap_remove(best);
+        *covered.entry(categories.get(&item_id)).or_insert(0) += 1;
+        selected.push((item_id, count, entropy, score));
+    }
+    selected
+}
+
+// function to pick k informative items of a recommendation system for a new user to rate
+#[ic_cdk::query]
+pub(crate) fn get_onboarding_items(recommendation_system_id: u64, user_id: u64, k: Option<u64>) -> Result<Vec<OnboardingItem>, Error> {
+    let recommendation_system = active_recommendation_system(recommendation_system_id)?;
+    if !user_is_active(user_id) {
+        return Err(Error::NotFound { msg: format!("user with id={} not found", user_id) });
+    }
+    let k = k.unwrap_or(DEFAULT_ONBOARDING_ITEMS).clamp(1, MAX_ONBOARDING_ITEMS) as usize;
+    let ratings = load_ratings(&recommendation_system);
+    let items: HashMap<u64, Item> = ITEM_STORAGE.with(|m| {
+        let m = m.borrow();
+        ratings.items.iter().filter_map(|item_id| m.get(item_id).map(|item| (*item_id, item))).collect()
+    });
+    let categories: HashMap<u64, CategoryKey> = items.iter().map(|(item_id, item)| (*item_id, CategoryKey::of(item))).collect();
+
+    Ok(select(&ratings, &categories, user_id, k)
+        .into_iter()
+        .filter_map(|(item_id, rating_count, entropy, score)| {
+            items.get(&item_id).cloned().map(|item| OnboardingItem { item, rating_count, entropy, score })
+        })
+        .collect())
+}
+
+// function to record a new user's ratings of the onboarding items and return their first personalized
+// recommendations; either all ratings are recorded or none
+#[ic_cdk::update]
+pub(crate) fn submit_onboarding_ratings(
+    recommendation_system_id: u64,
+    user_id: u64,
+    ratings: Vec<OnboardingRating>,
+) -> Result<RecommendationResponse, Error> {
+    let recommendation_system = active_recommendation_system(recommendation_system_id)?;
+    if !user_is_active(user_id) {
+        return Err(Error::NotFound { msg: format!("user with id={} not found", user_id) });
+    }
+    if ratings.is_empty() || ratings.len() as u64 > MAX_ONBOARDING_ITEMS {
+        return Err(Error::InvalidInput { msg: format!("between 1 and {} ratings are required", MAX_ONBOARDING_ITEMS) });
+    }
+    let system_items: HashSet<u64> = recommendation_system.items.iter().map(|item| item.id).collect();
+    let mut seen = HashSet::new();
+    for rating in &ratings {
+        if !system_items.contains(&rating.item_id) || !item_is_active(rating.item_id) {
+            return Err(Error::NotFound {
+                msg: format!(
+                    "item with id={} not found in recommendation system with id={}",
+                    rating.item_id, recommendation_system_id
+                ),
+            });
+        }
+        if rating.rating == 0 || !seen.insert(rating.item_id) {
+            return Err(Error::InvalidInput { msg: "ratings must be positive and rate distinct items".to_string() });
+        }
+    }
+
+    for rating in ratings {
+        let user_preference =
+            insert_user_preference(UserPreferencePayload { user_id, item_id: rating.item_id, rating: rating.rating });
+        audit::record("submit_onboarding_ratings", vec![user_preference.id], None, Some(&user_preference));
+    }
+    get_recommendations(RecommendationRequest {
+        recommendation_system_id,
+        user_id,
+        limit: None,
+        explain: None,
+        diversity: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn polarizing_items_and_new_categories_come_first() {
+        // item 1 splits three users, item 3 shares its category and splits them less, item 2 splits two users
+        let mut by_user: HashMap<u64, HashMap<u64, f64>> = HashMap::new();
+        for (user_id, item_id, rating) in
+            [(1, 1, 1.0), (2, 1, 5.0), (3, 1, 3.0), (1, 2, 1.0), (2, 2, 5.0), (1, 3, 1.0), (2, 3, 5.0), (3, 3, 5.0)]
+        {
+            by_user.entry(user_id).or_default().insert(item_id, rating);
+        }
+        by_user.entry(4).or_default().insert(4, 4.0);
+        let ratings = Ratings { items: BTreeSet::from([1, 2, 3, 4, 5]), by_user };
+        // items 1 and 3 are in the same category of the taxonomy under different free-form names
+        let categories: HashMap<u64, CategoryKey> = [
+            Item { id: 1, category: "Book".to_string(), category_id: Some(7), ..Default::default() },
+            Item { id: 2, category: "Music".to_string(), ..Default::default() },
+            Item { id: 3, category: "Books".to_string(), category_id: Some(7), ..Default::default() },
+            Item { id: 4, category: "Games".to_string(), ..Default::default() },
+            Item { id: 5, category: "Films".to_string(), ..Default::default() },
+        ]
+        .iter()
+        .map(|item| (item.id, CategoryKey::of(item)))
+        .collect();
+
+        assert!((entropy(&ratings.by_item()[&1]) - 3f64.log2()).abs() < 1e-12);
+        let picked: Vec<u64> = select(&ratings, &categories, 9, 5).iter().map(|(item_id, ..)| *item_id).collect();
+        // item 3 would come second without the coverage decay
+        assert_eq!(picked[..3], [1, 2, 3]);
+        assert_eq!(picked.len(), 5);
+
+        // items the user rated are not asked again
+        let picked: Vec<u64> = select(&ratings, &categories, 4, 5).iter().map(|(item_id, ..)| *item_id).collect();
+        assert!(!picked.contains(&4));
+    }
+}
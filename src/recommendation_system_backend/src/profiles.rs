@@ -0,0 +1,320 @@
+use crate::recommendations::{self, Ratings, ScoredItem};
+use crate::{attributes, audit, taxonomy, user_is_active, Error, Memory, ITEM_STORAGE, MEMORY_MANAGER};
+use candid::{CandidType, Decode, Encode};
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::collections::{HashMap, HashSet};
+use std::{borrow::Cow, cell::RefCell};
+
+// longest BCP 47 language tag kept
+const MAX_LOCALE_LENGTH: usize = 35;
+const MAX_FAVORITE_CATEGORIES: usize = 20;
+const MAX_FEATURES: usize = 50;
+const MAX_FEATURE_KEY_LENGTH: usize = 64;
+const MAX_FEATURE_VALUE_LENGTH: usize = 256;
+
+// prior weight of the mean rating when ranking items by popularity, as in collaborative filtering
+const POPULARITY_DAMPING: f64 = 5.0;
+
+// demographic predictions are shrunk towards zero with this much peer weight
+const PEER_SHRINKAGE: f64 = 1.0;
+
+// the algorithm's own list ranks after the items matching the declared interests of a new user
+const INTEREST_FALLBACK_WEIGHT: f64 = 0.1;
+
+#[derive(CandidType, Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub(crate) enum AgeBand {
+    Under18,
+    From18To24,
+    From25To34,
+    From35To44,
+    From45To54,
+    From55To64,
+    Over65,
+}
+
+// optional profile of a user, stored apart from the user record
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+pub(crate) struct UserProfile {
+    // BCP 47 language tag such as en-US
+    locale: Option<String>,
+    age_band: Option<AgeBand>,
+    // categories of the taxonomy the user declared as favourites, with their subcategories
+    favorite_category_ids: Vec<u64>,
+    // declared interests, matched against the tags of the items
+    interests: Vec<String>,
+    // custom features
+    features: Vec<(String, String)>,
+    updated_at: Option<u64>,
+}
+
+impl Storable for UserProfile {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for UserProfile {
+    const MAX_SIZE: u32 = 32768;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl UserProfile {
+    // language of the locale, "en" for en-US and en-GB
+    fn language(&self) -> Option<String> {
+        self.locale.as_ref().and_then(|locale| locale.split(['-', '_']).next()).map(|language| language.to_lowercase())
+    }
+
+    // how many demographic traits two users share, 0 to 2
+    fn similarity(&self, other: &UserProfile) -> f64 {
+        let age_band = self.age_band.is_some() && self.age_band == other.age_band;
+        let language = self.language().is_some() && self.language() == other.language();
+        age_band as u8 as f64 + language as u8 as f64
+    }
+}
+
+thread_local! {
+    // user id -> profile
+    static PROFILES: RefCell<StableBTreeMap<u64, UserProfile, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(41))))
+    );
+}
+
+fn profile_of(user_id: u64) -> Option<UserProfile> {
+    PROFILES.with(|m| m.borrow().get(&user_id))
+}
+
+fn validate_profile(mut profile: UserProfile) -> Result<UserProfile, Error> {
+    profile.locale = profile.locale.map(|locale| locale.trim().to_string()).filter(|locale| !locale.is_empty());
+    if profile.locale.as_ref().is_some_and(|locale| {
+        locale.len() > MAX_LOCALE_LENGTH || !locale.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    }) {
+        return Err(Error::InvalidInput { msg: "locale must be a language tag such as en-US".to_string() });
+    }
+    if profile.favorite_category_ids.len() > MAX_FAVORITE_CATEGORIES {
+        return Err(Error::InvalidInput { msg: format!("at most {} favourite categories", MAX_FAVORITE_CATEGORIES) });
+    }
+    for &category_id in &profile.favorite_category_ids {
+        taxonomy::category_by_id(category_id)?;
+    }
+    profile.interests = attributes::normalize_tags(&profile.interests)?;
+    if profile.features.len() > MAX_FEATURES {
+        return Err(Error::InvalidInput { msg: format!("at most {} features", MAX_FEATURES) });
+    }
+    let mut keys = HashSet::new();
+    for (key, value) in &profile.features {
+        if key.is_empty() || key.len() > MAX_FEATURE_KEY_LENGTH || !keys.insert(key) || value.len() > MAX_FEATURE_VALUE_LENGTH {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "feature keys must be unique with 1 to {} bytes and values at most {} bytes",
+                    MAX_FEATURE_KEY_LENGTH, MAX_FEATURE_VALUE_LENGTH
+                ),
+            });
+        }
+    }
+    Ok(profile)
+}
+
+// damped mean rating of every item of the ratings
+fn popularity(ratings: &Ratings) -> HashMap<u64, f64> {
+    let mut totals: HashMap<u64, (f64, f64)> = HashMap::new();
+    for user_ratings in ratings.by_user.values() {
+        for (&item_id, &rating) in user_ratings {
+            let total = totals.entry(item_id).or_insert((0.0, 0.0));
+            total.0 += rating;
+            total.1 += 1.0;
+        }
+    }
+    let (sum, count) = totals.values().fold((0.0, 0.0), |(sum, count), total| (sum + total.0, count + total.1));
+    let global_mean = if count > 0.0 { sum / count } else { 0.0 };
+    ratings
+        .items
+        .iter()
+        .map(|&item_id| {
+            let (sum, count) = totals.get(&item_id).copied().unwrap_or((0.0, 0.0));
+            (item_id, (sum + POPULARITY_DAMPING * global_mean) / (count + POPULARITY_DAMPING))
+        })
+        .collect()
+}
+
+fn sorted(scores: impl IntoIterator<Item = (u64, f64)>, n: usize) -> Vec<ScoredItem> {
+    let mut scores: Vec<(u64, f64)> = scores.into_iter().filter(|(_, score)| *score > 0.0).collect();
+    scores.sort_by(|(a, a_score), (b, b_score)| b_score.total_cmp(a_score).then(a.cmp(b)));
+    scores.truncate(n);
+    scores.into_iter().map(|(item_id, score)| ScoredItem { item_id, score }).collect()
+}
+
+// how well an item matches declared interests, 0 to 1: half for a favourite category, half for the share
+// of interests among its tags
+fn interest_match(
+    favorite_categories: &HashSet<u64>,
+    interests: &[String],
+    category_id: Option<u64>,
+    tags: Option<&HashSet<String>>,
+) -> f64 {
+    let category = category_id.is_some_and(|id| favorite_categories.contains(&id)) as u8 as f64;
+    let tags = match tags {
+        Some(tags) if !interests.is_empty() => {
+            interests.iter().filter(|interest| tags.contains(*interest)).count() as f64 / interests.len() as f64
+        }
+        _ => 0.0,
+    };
+    match (favorite_categories.is_empty(), interests.is_empty()) {
+        (false, false) => (category + tags) / 2.0,
+        (false, true) => category,
+        (true, false) => tags,
+        (true, true) => 0.0,
+    }
+}
+
+// for a user without ratings, put the items matching the declared interests first, ranked by interest
+// match times popularity, then the algorithm's list
+pub(crate) fn bootstrap(ratings: &Ratings, user_id: u64, candidates: Vec<ScoredItem>, n: usize) -> Vec<ScoredItem> {
+    let profile = match profile_of(user_id) {
+        Some(profile) if !ratings.by_user.contains_key(&user_id) => profile,
+        _ => return candidates,
+    };
+    if profile.favorite_category_ids.is_empty() && profile.interests.is_empty() {
+        return candidates;
+    }
+    let favorite_categories = taxonomy::subtree_of(&profile.favorite_category_ids);
+    let tags = attributes::tags_of(ratings.items.iter().copied());
+    let popularity = popularity(ratings);
+    let scores: Vec<(u64, f64)> = ratings
+        .items
+        .iter()
+        .map(|&item_id| {
+            let category_id = ITEM_STORAGE.with(|m| m.borrow().get(&item_id)).and_then(|item| item.category_id);
+            let strength = interest_match(&favorite_categories, &profile.interests, category_id, tags.get(&item_id));
+            (item_id, strength * popularity[&item_id])
+        })
+        .collect();
+
+    let mut bootstrapped = sorted(scores, n);
+    for candidate in candidates {
+        if bootstrapped.len() >= n {
+            break;
+        }
+        if !bootstrapped.iter().any(|chosen| chosen.item_id == candidate.item_id) {
+            bootstrapped.push(ScoredItem { item_id: candidate.item_id, score: candidate.score * INTEREST_FALLBACK_WEIGHT });
+        }
+    }
+    bootstrapped
+}
+
+// demographic filtering: the ratings of the users sharing the user's age band and language, weighted by how
+// many traits they share; popular items fill the list
+fn demographic_scores(ratings: &Ratings, profile: Option<&UserProfile>, user_id: u64) -> HashMap<u64, f64> {
+    let mut weighted: HashMap<u64, (f64, f64)> = HashMap::new();
+    if let Some(profile) = profile {
+        for (&peer_id, peer_ratings) in ratings.by_user.iter().filter(|(&peer_id, _)| peer_id != user_id) {
+            let similarity = profile_of(peer_id).map_or(0.0, |peer| profile.similarity(&peer));
+            if similarity == 0.0 {
+                continue;
+            }
+            for (&item_id, &rating) in peer_ratings {
+                let total = weighted.entry(item_id).or_insert((0.0, 0.0));
+                total.0 += similarity * rating;
+                total.1 += similarity;
+            }
+        }
+    }
+    weighted.into_iter().map(|(item_id, (sum, weights))| (item_id, sum / (weights + PEER_SHRINKAGE))).collect()
+}
+
+// predicted rating of an item from the ratings of the user's demographic peers
+pub(crate) fn predict_demographic(ratings: &Ratings, user_id: u64, item_id: u64) -> Option<f64> {
+    demographic_scores(ratings, profile_of(user_id).as_ref(), user_id).get(&item_id).copied()
+}
+
+// top-N items by demographic filtering
+pub(crate) fn recommend_demographic(ratings: &Ratings, user_id: u64, n: usize, exclude_rated: bool) -> Vec<ScoredItem> {
+    let rated = ratings.by_user.get(&user_id);
+    let allowed = |item_id: &u64| !(exclude_rated && rated.is_some_and(|rated| rated.contains_key(item_id)));
+    let scores = demographic_scores(ratings, profile_of(user_id).as_ref(), user_id);
+    let mut recommended = sorted(scores.into_iter().filter(|(item_id, _)| allowed(item_id)), n);
+    let mut popular = sorted(popularity(ratings).into_iter().filter(|(item_id, _)| allowed(item_id)), n);
+    popular.retain(|item| !recommended.iter().any(|chosen| chosen.item_id == item.item_id));
+    let missing = n.saturating_sub(recommended.len());
+    recommended.extend(popular.into_iter().take(missing).map(|item| ScoredItem {
+        item_id: item.item_id,
+        score: item.score * INTEREST_FALLBACK_WEIGHT,
+    }));
+    recommended
+}
+
+// drop the profile of a user purged from the trash
+pub(crate) fn remove_user(user_id: u64) {
+    PROFILES.with(|m| m.borrow_mut().remove(&user_id));
+}
+
+// function to get the profile of a user, empty when none was set
+#[ic_cdk::query]
+fn get_user_profile(user_id: u64) -> Result<UserProfile, Error> {
+    if !user_is_active(user_id) {
+        return Err(Error::NotFound { msg: format!("user with id={} not found", user_id) });
+    }
+    Ok(profile_of(user_id).unwrap_or_default())
+}
+
+// function to set the profile of a user
+#[ic_cdk::update]
+fn set_user_profile(user_id: u64, profile: UserProfile) -> Result<UserProfile, Error> {
+    if !user_is_active(user_id) {
+        return Err(Error::NotFound { msg: format!("user with id={} not found", user_id) });
+    }
+    let mut profile = validate_profile(profile)?;
+    profile.updated_at = Some(time());
+    let previous = PROFILES.with(|m| m.borrow_mut().insert(user_id, profile.clone()));
+    // the lists of new users come from their interests
+    recommendations::invalidate_user(user_id);
+    audit::record("set_user_profile", vec![user_id], previous.as_ref(), Some(&profile));
+    Ok(profile)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    fn profile(locale: &str, age_band: AgeBand) -> UserProfile {
+        UserProfile { locale: Some(locale.to_string()), age_band: Some(age_band), ..Default::default() }
+    }
+
+    #[test]
+    fn peers_share_age_band_and_language() {
+        PROFILES.with(|m| {
+            let mut m = m.borrow_mut();
+            m.insert(1, profile("en-US", AgeBand::From18To24));
+            m.insert(2, profile("en-GB", AgeBand::From18To24));
+            m.insert(3, profile("fr-FR", AgeBand::Over65));
+        });
+        let ratings = Ratings {
+            items: BTreeSet::from([1, 2, 3]),
+            by_user: HashMap::from([(2, HashMap::from([(1, 5.0)])), (3, HashMap::from([(2, 5.0), (3, 4.0)]))]),
+        };
+        let ids: Vec<u64> = recommend_demographic(&ratings, 1, 3, true).iter().map(|item| item.item_id).collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+        assert!((predict_demographic(&ratings, 1, 1).unwrap() - 10.0 / 3.0).abs() < 1e-12);
+        assert!(predict_demographic(&ratings, 1, 2).is_none());
+
+        assert!(validate_profile(UserProfile { locale: Some("en US".to_string()), ..Default::default() }).is_err());
+        let interests = validate_profile(UserProfile { interests: vec![" Jazz".to_string()], ..Default::default() });
+        assert_eq!(interests.unwrap().interests, vec!["jazz"]);
+    }
+
+    #[test]
+    fn interests_weigh_categories_and_tags() {
+        let favorites: HashSet<u64> = [7].into();
+        let interests = vec!["jazz".to_string(), "vinyl".to_string()];
+        let tags: HashSet<String> = ["jazz".to_string()].into();
+        assert_eq!(interest_match(&favorites, &interests, Some(7), Some(&tags)), 0.75);
+        assert_eq!(interest_match(&favorites, &[], Some(8), Some(&tags)), 0.0);
+        assert_eq!(interest_match(&HashSet::new(), &interests, None, Some(&tags)), 0.5);
+    }
+}
@@ -0,0 +1,777 @@
+use crate::diversity::{mmr, ItemSimilarity};
+use crate::explanations::{Explainer, RecommendationExplanation};
+use crate::impressions::{self, Impression};
+use crate::settings::{Algorithm, RecommendationSettings};
+use crate::{bandit, experiments, graph, linucb, profiles, rules, settings, slope_one};
+use crate::{
+    audit, certification, ensure_controller, user_is_active, Error, Item, Memory, RecommendationSystem, ITEM_STORAGE,
+    MEMORY_MANAGER, RECOMMENDATION_SYSTEM_STORAGE, USER_PREFERENCE_STORAGE,
+};
+use candid::{CandidType, Decode, Encode};
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use std::collections::{BTreeSet, HashMap};
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+
+// number of recommendations computed and cached for each user
+pub(crate) const TOP_N: usize = 50;
+
+// cached lists are recomputed after an hour by default
+const DEFAULT_CACHE_TTL_SECS: u64 = 60 * 60;
+
+// how often the models are retrained and the cached lists refreshed
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// cached lists recomputed per timer call, so a refresh is spread over many messages
+const LISTS_PER_CHUNK: usize = 20;
+
+// number of most similar items kept for each item of a model
+const MAX_NEIGHBORS: usize = 50;
+
+// weight of the global mean rating in the damped mean used to rank popular items
+const POPULARITY_DAMPING: f64 = 5.0;
+
+// shrinks predictions backed by little similarity towards zero
+const SIMILARITY_SHRINKAGE: f64 = 1.0;
+
+// popular items only fill the list, their damped mean is scaled down below personalized scores
+const POPULAR_FALLBACK_WEIGHT: f64 = 0.1;
+
+// key of the cache, ordered by user first so a user's lists can be invalidated with one range scan
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub(crate) struct CacheKey {
+    pub(crate) user_id: u64,
+    pub(crate) recommendation_system_id: u64,
+}
+
+impl Storable for CacheKey {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        let mut bytes = self.user_id.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&self.recommendation_system_id.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let (user_id, recommendation_system_id) = bytes.split_at(8);
+        CacheKey {
+            user_id: u64::from_be_bytes(user_id.try_into().unwrap()),
+            recommendation_system_id: u64::from_be_bytes(recommendation_system_id.try_into().unwrap()),
+        }
+    }
+}
+
+impl BoundedStorable for CacheKey {
+    const MAX_SIZE: u32 = 16;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub(crate) struct ScoredItem {
+    pub(crate) item_id: u64,
+    pub(crate) score: f64,
+}
+
+// a user's precomputed top-N list in one recommendation system
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug)]
+pub(crate) struct CachedRecommendations {
+    pub(crate) recommendations: Vec<ScoredItem>,
+    pub(crate) computed_at: u64,
+}
+
+impl Storable for CachedRecommendations {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for CachedRecommendations {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct RecommendationRequest {
+    pub(crate) recommendation_system_id: u64,
+    pub(crate) user_id: u64,
+    // number of recommendations to return, at most 50
+    pub(crate) limit: Option<u64>,
+    // attach a RecommendationExplanation to every result
+    pub(crate) explain: Option<bool>,
+    // relevance/diversity trade-off of the MMR re-ranking from 0 to 1, the system setting by default
+    pub(crate) diversity: Option<f64>,
+}
+
+#[derive(CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Recommendation {
+    pub(crate) item: Item,
+    pub(crate) score: f64,
+    explanation: Option<RecommendationExplanation>,
+}
+
+impl Recommendation {
+    pub(crate) fn new(item: Item, score: f64) -> Self {
+        Recommendation { item, score, explanation: None }
+    }
+}
+
+#[derive(CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct RecommendationResponse {
+    // pass it to record_click, record_conversion and record_reward
+    impression_id: u64,
+    recommendations: Vec<Recommendation>,
+}
+
+#[derive(CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct RecommendationCacheStats {
+    hits: u64,
+    misses: u64,
+    entries: u64,
+    ttl_secs: u64,
+}
+
+// item-item similarities of one recommendation system, kept on the heap and retrained by a timer
+#[derive(Default)]
+pub(crate) struct ItemModel {
+    // item id -> most similar items, best first
+    neighbors: HashMap<u64, Vec<(u64, f64)>>,
+    // items by damped mean rating, best first
+    popular: Vec<(u64, f64)>,
+}
+
+thread_local! {
+    static CACHE: RefCell<StableBTreeMap<CacheKey, CachedRecommendations, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16))))
+    );
+
+    static CACHE_TTL: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17))), DEFAULT_CACHE_TTL_SECS)
+            .expect("Cannot create the cache ttl")
+    );
+
+    static CACHE_HITS: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static CACHE_MISSES: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    // recommendation system id -> trained model
+    static MODELS: RefCell<HashMap<u64, ItemModel>> = RefCell::new(HashMap::new());
+
+    // the refresh in progress, restarted by the next interval after an upgrade
+    static REFRESH: RefCell<Option<RefreshRun>> = const { RefCell::new(None) };
+}
+
+// cached lists left to refresh, by recommendation system, and the system being refreshed
+struct RefreshRun {
+    started_at: u64,
+    pending: Vec<(u64, Vec<CacheKey>)>,
+    current: Option<SystemRefresh>,
+}
+
+struct SystemRefresh {
+    recommendation_system: RecommendationSystem,
+    settings: RecommendationSettings,
+    ratings: Ratings,
+    keys: Vec<CacheKey>,
+}
+
+// ratings of users on the active items of a recommendation system
+pub(crate) struct Ratings {
+    pub(crate) items: BTreeSet<u64>,
+    // user id -> item id -> rating, the latest preference wins
+    pub(crate) by_user: HashMap<u64, HashMap<u64, f64>>,
+}
+
+impl Ratings {
+    // item id -> user id -> rating
+    pub(crate) fn by_item(&self) -> HashMap<u64, HashMap<u64, f64>> {
+        let mut by_item: HashMap<u64, HashMap<u64, f64>> = HashMap::new();
+        for (&user, user_ratings) in &self.by_user {
+            for (&item, &rating) in user_ratings {
+                by_item.entry(item).or_default().insert(user, rating);
+            }
+        }
+        by_item
+    }
+}
+
+// cosine similarity of two sparse rating vectors
+pub(crate) fn cosine(a: Option<&HashMap<u64, f64>>, b: Option<&HashMap<u64, f64>>) -> f64 {
+    let (Some(a), Some(b)) = (a, b) else {
+        return 0.0;
+    };
+    let dot: f64 = a.iter().filter_map(|(key, x)| b.get(key).map(|y| x * y)).sum();
+    let norm = |v: &HashMap<u64, f64>| v.values().map(|x| x * x).sum::<f64>().sqrt();
+    if dot == 0.0 {
+        0.0
+    } else {
+        dot / (norm(a) * norm(b))
+    }
+}
+
+pub(crate) fn load_ratings(recommendation_system: &RecommendationSystem) -> Ratings {
+    let items: BTreeSet<u64> = recommendation_system
+        .items
+        .iter()
+        .map(|item| item.id)
+        .filter(|&id| ITEM_STORAGE.with(|m| m.borrow().get(&id)).is_some_and(|item| item.deleted_at.is_none()))
+        .collect();
+    let mut by_user: HashMap<u64, HashMap<u64, f64>> = HashMap::new();
+    USER_PREFERENCE_STORAGE.with(|m| {
+        for (_, preference) in m.borrow().iter() {
+            let active = preference.deleted_at.is_none() && user_is_active(preference.user_id);
+            if active && items.contains(&preference.item_id) {
+                by_user.entry(preference.user_id).or_default().insert(preference.item_id, preference.rating as f64);
+            }
+        }
+    });
+    // clicks and conversions rate the items the user did not rate explicitly
+    for (user_id, item_id, rating) in impressions::implicit_ratings() {
+        if items.contains(&item_id) && user_is_active(user_id) {
+            by_user.entry(user_id).or_default().entry(item_id).or_insert(rating);
+        }
+    }
+    Ratings { items, by_user }
+}
+
+// cosine similarity between the rating vectors of every pair of co-rated items
+pub(crate) fn train(ratings: &Ratings) -> ItemModel {
+    let mut dot: HashMap<(u64, u64), f64> = HashMap::new();
+    let mut norms: HashMap<u64, f64> = HashMap::new();
+    let mut totals: HashMap<u64, (f64, f64)> = HashMap::new();
+    for user_ratings in ratings.by_user.values() {
+        for (&i, &r_i) in user_ratings {
+            *norms.entry(i).or_insert(0.0) += r_i * r_i;
+            let total = totals.entry(i).or_insert((0.0, 0.0));
+            total.0 += r_i;
+            total.1 += 1.0;
+            for (&j, &r_j) in user_ratings {
+                if i != j {
+                    *dot.entry((i, j)).or_insert(0.0) += r_i * r_j;
+                }
+            }
+        }
+    }
+
+    let mut neighbors: HashMap<u64, Vec<(u64, f64)>> = HashMap::new();
+    for ((i, j), product) in dot {
+        let similarity = product / (norms[&i].sqrt() * norms[&j].sqrt());
+        neighbors.entry(i).or_default().push((j, similarity));
+    }
+    for similar in neighbors.values_mut() {
+        sort_scores(similar);
+        similar.truncate(MAX_NEIGHBORS);
+    }
+
+    let (sum, count) = totals.values().fold((0.0, 0.0), |(sum, count), total| (sum + total.0, count + total.1));
+    let global_mean = if count > 0.0 { sum / count } else { 0.0 };
+    let mut popular: Vec<(u64, f64)> = ratings
+        .items
+        .iter()
+        .map(|&id| {
+            let (sum, count) = totals.get(&id).copied().unwrap_or((0.0, 0.0));
+            (id, (sum + POPULARITY_DAMPING * global_mean) / (count + POPULARITY_DAMPING))
+        })
+        .collect();
+    sort_scores(&mut popular);
+    ItemModel { neighbors, popular }
+}
+
+// best score first, ties broken by id so results are deterministic
+fn sort_scores(scores: &mut [(u64, f64)]) {
+    scores.sort_by(|(a, a_score), (b, b_score)| b_score.total_cmp(a_score).then(a.cmp(b)));
+}
+
+// top-N items, without the ones the user rated if `exclude_rated`: shrunk predicted ratings from
+// the neighbors of the rated items first, then popular items to fill the list
+pub(crate) fn recommend(model: &ItemModel, user_ratings: &HashMap<u64, f64>, n: usize, exclude_rated: bool) -> Vec<ScoredItem> {
+    let allowed = |id: &u64| !exclude_rated || !user_ratings.contains_key(id);
+    let mut weighted: HashMap<u64, (f64, f64)> = HashMap::new();
+    for (item_id, rating) in user_ratings {
+        for &(neighbor, similarity) in model.neighbors.get(item_id).into_iter().flatten() {
+            if allowed(&neighbor) {
+                let total = weighted.entry(neighbor).or_insert((0.0, 0.0));
+                total.0 += similarity * rating;
+                total.1 += similarity;
+            }
+        }
+    }
+    let mut scores: Vec<(u64, f64)> =
+        weighted.into_iter().map(|(id, (sum, weights))| (id, sum / (weights + SIMILARITY_SHRINKAGE))).collect();
+    sort_scores(&mut scores);
+    scores.truncate(n);
+
+    for &(id, score) in &model.popular {
+        if scores.len() >= n {
+            break;
+        }
+        if allowed(&id) && !scores.iter().any(|(chosen, _)| *chosen == id) {
+            scores.push((id, score * POPULAR_FALLBACK_WEIGHT));
+        }
+    }
+    scores.into_iter().map(|(item_id, score)| ScoredItem { item_id, score }).collect()
+}
+
+fn active_recommendation_system(id: u64) -> Result<RecommendationSystem, Error> {
+    RECOMMENDATION_SYSTEM_STORAGE
+        .with(|service| service.borrow().get(&id))
+        .filter(|record| record.deleted_at.is_none())
+        .ok_or(Error::NotFound { msg: format!("recommendation system with id={} not found", id) })
+}
+
+fn is_fresh(entry: &CachedRecommendations, now: u64) -> bool {
+    let ttl_nanos = get_recommendation_cache_ttl().saturating_mul(1_000_000_000);
+    entry.computed_at.saturating_add(ttl_nanos) > now
+}
+
+fn increment(counter: &'static std::thread::LocalKey<RefCell<Cell<u64, Memory>>>) {
+    counter
+        .with(|cell| {
+            let current_value = *cell.borrow().get();
+            cell.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment counter");
+}
+
+// compute a user's top-N list with the given settings, training the model of the recommendation system if needed
+pub(crate) fn compute(
+    recommendation_system: &RecommendationSystem,
+    settings: &RecommendationSettings,
+    user_id: u64,
+    retrain: bool,
+) -> CachedRecommendations {
+    compute_with(recommendation_system, settings, &load_ratings(recommendation_system), user_id, retrain)
+}
+
+// compute a user's top-N list from ratings already loaded
+fn compute_with(
+    recommendation_system: &RecommendationSystem,
+    settings: &RecommendationSettings,
+    ratings: &Ratings,
+    user_id: u64,
+    retrain: bool,
+) -> CachedRecommendations {
+    let exclude_rated = rules::rules_of(recommendation_system.id).exclude_rated();
+    let recommendations = match settings.algorithm() {
+        Algorithm::ItemBasedCollaborativeFiltering => {
+            let user_ratings = ratings.by_user.get(&user_id).cloned().unwrap_or_default();
+            MODELS.with(|models| {
+                let mut models = models.borrow_mut();
+                if retrain || !models.contains_key(&recommendation_system.id) {
+                    models.insert(recommendation_system.id, train(ratings));
+                }
+                recommend(&models[&recommendation_system.id], &user_ratings, TOP_N, exclude_rated)
+            })
+        }
+        Algorithm::LinUcb => linucb::recommend(
+            recommendation_system.id,
+            ratings,
+            user_id,
+            TOP_N,
+            exclude_rated,
+            settings.linucb_alpha(),
+        ),
+        Algorithm::SlopeOne => slope_one::recommend(ratings, user_id, TOP_N, exclude_rated),
+        Algorithm::Rp3Beta => {
+            graph::recommend_rp3beta(ratings, user_id, TOP_N, exclude_rated, &settings.graph.clone().unwrap_or_default())
+        }
+        Algorithm::PersonalizedPageRank => {
+            graph::recommend_pagerank(ratings, user_id, TOP_N, exclude_rated, &settings.graph.clone().unwrap_or_default())
+        }
+        Algorithm::Demographic => profiles::recommend_demographic(ratings, user_id, TOP_N, exclude_rated),
+    };
+    // users without ratings start from their declared interests
+    let recommendations = profiles::bootstrap(ratings, user_id, recommendations, TOP_N);
+    CachedRecommendations { recommendations, computed_at: time() }
+}
+
+fn store(key: CacheKey, entry: CachedRecommendations) {
+    certification::certify_recommendations(key, &entry);
+    CACHE.with(|m| m.borrow_mut().insert(key, entry));
+}
+
+pub(crate) fn hydrate(entry: &CachedRecommendations) -> Vec<Recommendation> {
+    entry
+        .recommendations
+        .iter()
+        .filter_map(|scored| {
+            ITEM_STORAGE
+                .with(|m| m.borrow().get(&scored.item_id))
+                .filter(|item| item.deleted_at.is_none())
+                .map(|item| Recommendation::new(item, scored.score))
+        })
+        .collect()
+}
+
+// predicted rating of an item from the item-based model, none when the user rated no similar item
+fn predict(model: &ItemModel, user_ratings: &HashMap<u64, f64>, item_id: u64) -> Option<f64> {
+    let (sum, weights) = model
+        .neighbors
+        .get(&item_id)
+        .into_iter()
+        .flatten()
+        .filter_map(|(neighbor, similarity)| user_ratings.get(neighbor).map(|rating| (similarity * rating, *similarity)))
+        .fold((0.0, 0.0), |(sum, weights), (product, similarity)| (sum + product, weights + similarity));
+    (weights > 0.0).then(|| sum / weights)
+}
+
+// function to predict the rating of an item of a recommendation system by a user, with the algorithm
+// of the system; none when the algorithm has nothing to base it on
+#[ic_cdk::query]
+fn predict_rating(recommendation_system_id: u64, user_id: u64, item_id: u64) -> Result<Option<f64>, Error> {
+    let recommendation_system = active_recommendation_system(recommendation_system_id)?;
+    if !user_is_active(user_id) {
+        return Err(Error::NotFound { msg: format!("user with id={} not found", user_id) });
+    }
+    let ratings = load_ratings(&recommendation_system);
+    if !ratings.items.contains(&item_id) {
+        return Err(Error::NotFound {
+            msg: format!("item with id={} not found in recommendation system with id={}", item_id, recommendation_system_id),
+        });
+    }
+    match settings::settings_of(recommendation_system_id).algorithm() {
+        Algorithm::ItemBasedCollaborativeFiltering => {
+            let user_ratings = ratings.by_user.get(&user_id).cloned().unwrap_or_default();
+            Ok(MODELS.with(|models| match models.borrow().get(&recommendation_system_id) {
+                Some(model) => predict(model, &user_ratings, item_id),
+                None => predict(&train(&ratings), &user_ratings, item_id),
+            }))
+        }
+        Algorithm::SlopeOne => Ok(slope_one::predict(&ratings, user_id, item_id)),
+        Algorithm::Demographic => Ok(profiles::predict_demographic(&ratings, user_id, item_id)),
+        algorithm @ (Algorithm::LinUcb | Algorithm::Rp3Beta | Algorithm::PersonalizedPageRank) => {
+            Err(Error::InvalidInput { msg: format!("{:?} ranks items and does not predict ratings", algorithm) })
+        }
+    }
+}
+
+// function to get the top recommendations of a user, served from the cache while it is fresh
+#[ic_cdk::update]
+pub(crate) fn get_recommendations(request: RecommendationRequest) -> Result<RecommendationResponse, Error> {
+    let recommendation_system = active_recommendation_system(request.recommendation_system_id)?;
+    if !user_is_active(request.user_id) {
+        return Err(Error::NotFound { msg: format!("user with id={} not found", request.user_id) });
+    }
+    settings::validate_diversity(request.diversity)?;
+    let limit = request.limit.unwrap_or(10).clamp(1, TOP_N as u64) as usize;
+    let key = CacheKey { user_id: request.user_id, recommendation_system_id: recommendation_system.id };
+
+    // users in a running experiment get the settings of their variant
+    let system_settings = settings::settings_of(recommendation_system.id);
+    let assignment = experiments::assign(recommendation_system.id, request.user_id, time());
+    let settings = assignment.as_ref().map_or(system_settings.clone(), |assignment| assignment.settings.clone());
+
+    let candidates = match CACHE.with(|m| m.borrow().get(&key)).filter(|entry| is_fresh(entry, time())) {
+        // cached lists hold the scores of the system's own settings
+        _ if !settings.scores_like(&system_settings) => {
+            hydrate(&compute(&recommendation_system, &settings, request.user_id, false))
+        }
+        Some(entry) => {
+            increment(&CACHE_HITS);
+            hydrate(&entry)
+        }
+        None => {
+            increment(&CACHE_MISSES);
+            let entry = compute(&recommendation_system, &settings, request.user_id, false);
+            let candidates = hydrate(&entry);
+            store(key, entry);
+            candidates
+        }
+    };
+    let mut recommendations = finish(&recommendation_system, &settings, &request, candidates, limit);
+
+    let explored_item_ids = settings.exploration.as_ref().map(|exploration| {
+        bandit::explore(
+            exploration,
+            &rules::rules_of(recommendation_system.id),
+            &load_ratings(&recommendation_system),
+            recommendation_system.id,
+            request.user_id,
+            &mut recommendations,
+            limit,
+        )
+    });
+
+    let impression_id = impressions::log(Impression {
+        recommendation_system_id: recommendation_system.id,
+        user_id: request.user_id,
+        item_ids: recommendations.iter().map(|recommendation| recommendation.item.id).collect(),
+        explored_item_ids: explored_item_ids.unwrap_or_default(),
+        algorithm: Some(settings.algorithm()),
+        experiment_id: assignment.as_ref().map(|assignment| assignment.experiment_id),
+        variant: assignment.map(|assignment| assignment.variant),
+        ..Default::default()
+    });
+    Ok(RecommendationResponse { impression_id, recommendations })
+}
+
+// apply the rules, the diversity re-ranking and the explanations to a user's scored candidates
+pub(crate) fn finish(
+    recommendation_system: &RecommendationSystem,
+    settings: &RecommendationSettings,
+    request: &RecommendationRequest,
+    candidates: Vec<Recommendation>,
+    limit: usize,
+) -> Vec<Recommendation> {
+    let diversity = request.diversity.or(settings.diversity).unwrap_or(0.0);
+    let explain = request.explain == Some(true);
+    let ratings = (diversity > 0.0 || explain).then(|| load_ratings(recommendation_system));
+
+    let rules = rules::rules_of(recommendation_system.id);
+    let mut recommendations = rules::apply(&rules, candidates, limit, |ranked| match &ratings {
+        Some(ratings) if diversity > 0.0 => mmr(ranked, diversity, &ItemSimilarity::new(ratings)),
+        _ => ranked,
+    });
+
+    if let Some(ratings) = ratings.filter(|_| explain) {
+        let explainer = Explainer::new(ratings, request.user_id);
+        for recommendation in recommendations.iter_mut() {
+            recommendation.explanation = Some(explainer.explain(&recommendation.item));
+        }
+    }
+    recommendations
+}
+
+// the cached list of a user, if any, for certified queries
+pub(crate) fn cached(key: CacheKey) -> Option<CachedRecommendations> {
+    CACHE.with(|m| m.borrow().get(&key))
+}
+
+pub(crate) fn cached_entries() -> Vec<(CacheKey, CachedRecommendations)> {
+    CACHE.with(|m| m.borrow().iter().collect())
+}
+
+// remove every cached list of a user, returns the removed keys
+fn remove_user_entries(user_id: u64) -> Vec<CacheKey> {
+    let start = CacheKey { user_id, recommendation_system_id: 0 };
+    let end = CacheKey { user_id, recommendation_system_id: u64::MAX };
+    CACHE.with(|m| {
+        let mut cache = m.borrow_mut();
+        let keys: Vec<CacheKey> = cache.range(start..=end).map(|(key, _)| key).collect();
+        for key in &keys {
+            cache.remove(key);
+        }
+        keys
+    })
+}
+
+// drop the cached lists of a user whose preferences changed
+pub(crate) fn invalidate_user(user_id: u64) {
+    for key in remove_user_entries(user_id) {
+        certification::uncertify_recommendations(key);
+    }
+}
+
+// drop every cached list of a recommendation system, e.g. after its rules changed
+pub(crate) fn invalidate_recommendation_system(recommendation_system_id: u64) {
+    let keys: Vec<CacheKey> = cached_entries()
+        .into_iter()
+        .map(|(key, _)| key)
+        .filter(|key| key.recommendation_system_id == recommendation_system_id)
+        .collect();
+    for key in keys {
+        CACHE.with(|m| m.borrow_mut().remove(&key));
+        certification::uncertify_recommendations(key);
+    }
+}
+
+pub(crate) fn start_refresh_timer() {
+    ic_cdk_timers::set_timer_interval(REFRESH_INTERVAL, start_refresh);
+}
+
+// start refreshing every cached list unless a refresh is in progress; lists of deleted recommendation
+// systems are dropped
+fn start_refresh() {
+    let started = REFRESH.with(|run| {
+        if run.borrow().is_some() {
+            return false;
+        }
+        let mut keys_by_system: HashMap<u64, Vec<CacheKey>> = HashMap::new();
+        for (key, _) in cached_entries() {
+            keys_by_system.entry(key.recommendation_system_id).or_default().push(key);
+        }
+        MODELS.with(|models| models.borrow_mut().retain(|id, _| keys_by_system.contains_key(id)));
+        *run.borrow_mut() =
+            Some(RefreshRun { started_at: time(), pending: keys_by_system.into_iter().collect(), current: None });
+        true
+    });
+    if started {
+        refresh_chunk();
+    }
+}
+
+// refresh the next lists of the run in progress and schedule the following chunk: a recommendation system's
+// ratings are loaded and its model retrained in one call, then LISTS_PER_CHUNK of its lists per call
+fn refresh_chunk() {
+    let scheduled = REFRESH.with(|run| {
+        let mut run = run.borrow_mut();
+        let Some(state) = run.as_mut() else {
+            return false;
+        };
+        match state.current.as_mut() {
+            Some(current) => {
+                let at = current.keys.len().saturating_sub(LISTS_PER_CHUNK);
+                for key in current.keys.split_off(at) {
+                    // lists invalidated or recomputed since the run started are left alone
+                    if cached(key).is_some_and(|entry| entry.computed_at < state.started_at) {
+                        let entry = compute_with(
+                            &current.recommendation_system,
+                            &current.settings,
+                            &current.ratings,
+                            key.user_id,
+                            false,
+                        );
+                        store(key, entry);
+                    }
+                }
+                if current.keys.is_empty() {
+                    state.current = None;
+                }
+            }
+            None => match state.pending.pop() {
+                Some((recommendation_system_id, keys)) => match active_recommendation_system(recommendation_system_id) {
+                    Ok(recommendation_system) => {
+                        let settings = settings::settings_of(recommendation_system_id);
+                        let ratings = load_ratings(&recommendation_system);
+                        if settings.algorithm() == Algorithm::ItemBasedCollaborativeFiltering {
+                            MODELS.with(|models| models.borrow_mut().insert(recommendation_system_id, train(&ratings)));
+                        }
+                        state.current = Some(SystemRefresh { recommendation_system, settings, ratings, keys });
+                    }
+                    Err(_) => {
+                        for key in keys {
+                            CACHE.with(|m| m.borrow_mut().remove(&key));
+                            certification::uncertify_recommendations(key);
+                        }
+                    }
+                },
+                None => {
+                    *run = None;
+                    return false;
+                }
+            },
+        }
+        true
+    });
+    if scheduled {
+        ic_cdk_timers::set_timer(Duration::ZERO, refresh_chunk);
+    }
+}
+
+// function to get the hit and miss counters of the recommendation cache
+#[ic_cdk::query]
+fn get_recommendation_cache_stats() -> RecommendationCacheStats {
+    RecommendationCacheStats {
+        hits: CACHE_HITS.with(|cell| *cell.borrow().get()),
+        misses: CACHE_MISSES.with(|cell| *cell.borrow().get()),
+        entries: CACHE.with(|m| m.borrow().len()),
+        ttl_secs: get_recommendation_cache_ttl(),
+    }
+}
+
+// function to get how long cached recommendations are served, in seconds
+#[ic_cdk::query]
+fn get_recommendation_cache_ttl() -> u64 {
+    CACHE_TTL.with(|cell| *cell.borrow().get())
+}
+
+// function to set how long cached recommendations are served in seconds, restricted to controllers
+#[ic_cdk::update]
+fn set_recommendation_cache_ttl(ttl_secs: u64) -> Result<u64, Error> {
+    ensure_controller("change the recommendation cache ttl")?;
+    let previous = get_recommendation_cache_ttl();
+    CACHE_TTL.with(|cell| cell.borrow_mut().set(ttl_secs)).expect("cannot set the recommendation cache ttl");
+    audit::record("set_recommendation_cache_ttl", vec![], Some(&previous), Some(&ttl_secs));
+    Ok(ttl_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ratings(preferences: &[(u64, u64, f64)]) -> Ratings {
+        let mut ratings = Ratings { items: BTreeSet::new(), by_user: HashMap::new() };
+        for &(user_id, item_id, rating) in preferences {
+            ratings.items.insert(item_id);
+            ratings.by_user.entry(user_id).or_default().insert(item_id, rating);
+        }
+        ratings
+    }
+
+    #[test]
+    fn recommend_ranks_items_rated_alongside_the_users_items() {
+        // item 2 is liked by everyone who liked item 1, item 3 by someone who disliked it
+        let ratings = ratings(&[
+            (1, 1, 5.0),
+            (1, 2, 5.0),
+            (2, 1, 4.0),
+            (2, 2, 5.0),
+            (3, 1, 1.0),
+            (3, 3, 5.0),
+            (4, 4, 3.0),
+            (5, 1, 5.0),
+        ]);
+        let model = train(&ratings);
+
+        let ids: Vec<u64> = recommend(&model, &ratings.by_user[&5], 3, true).iter().map(|scored| scored.item_id).collect();
+        assert_eq!(ids, vec![2, 3, 4]);
+        assert!(!ids.contains(&1));
+
+        let ids: Vec<u64> = recommend(&model, &ratings.by_user[&1], 4, false).iter().map(|scored| scored.item_id).collect();
+        assert!(ids.contains(&1) && ids.contains(&2));
+    }
+
+    #[test]
+    fn recommend_falls_back_to_popular_items() {
+        let ratings = ratings(&[(1, 1, 2.0), (2, 2, 5.0), (3, 2, 5.0), (4, 3, 4.0)]);
+        let model = train(&ratings);
+
+        let ids: Vec<u64> = recommend(&model, &HashMap::new(), 2, true).iter().map(|scored| scored.item_id).collect();
+        assert_eq!(ids, vec![2, 3]);
+    }
+
+    #[test]
+    fn invalidation_only_drops_the_users_lists() {
+        let entry = CachedRecommendations { recommendations: vec![ScoredItem { item_id: 1, score: 1.0 }], computed_at: 0 };
+        for (user_id, recommendation_system_id) in [(1, 1), (1, 2), (2, 1)] {
+            CACHE.with(|m| m.borrow_mut().insert(CacheKey { user_id, recommendation_system_id }, entry.clone()));
+        }
+
+        assert_eq!(remove_user_entries(1).len(), 2);
+        let keys: Vec<CacheKey> = cached_entries().into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, vec![CacheKey { user_id: 2, recommendation_system_id: 1 }]);
+    }
+
+    #[test]
+    fn cache_entries_expire_after_the_ttl() {
+        let entry = CachedRecommendations { recommendations: vec![], computed_at: 1_000_000_000 };
+        assert!(is_fresh(&entry, 1_000_000_000 + DEFAULT_CACHE_TTL_SECS * 1_000_000_000 - 1));
+        assert!(!is_fresh(&entry, 1_000_000_000 + DEFAULT_CACHE_TTL_SECS * 1_000_000_000));
+    }
+
+    #[test]
+    fn load_ratings_leaves_out_users_in_the_trash() {
+        use crate::{Item, User, UserPreference, USER_STORAGE};
+        ITEM_STORAGE.with(|m| m.borrow_mut().insert(1, Item { id: 1, ..Default::default() }));
+        for (user_id, deleted_at) in [(1, None), (2, Some(1))] {
+            USER_STORAGE.with(|m| m.borrow_mut().insert(user_id, User { id: user_id, deleted_at, ..Default::default() }));
+            let preference = UserPreference { id: user_id, user_id, item_id: 1, rating: 4, ..Default::default() };
+            USER_PREFERENCE_STORAGE.with(|m| m.borrow_mut().insert(user_id, preference));
+        }
+        let recommendation_system =
+            RecommendationSystem { items: vec![Item { id: 1, ..Default::default() }], ..Default::default() };
+
+        let ratings = load_ratings(&recommendation_system);
+        assert_eq!(ratings.by_user.keys().copied().collect::<Vec<_>>(), vec![1]);
+    }
+}
@@ -0,0 +1,274 @@
+use crate::recommendations::{self, Recommendation};
+use crate::{
+    audit, ensure_controller, item_is_active, Error, Item, Memory, RecommendationSystem, ITEM_STORAGE, MEMORY_MANAGER,
+    RECOMMENDATION_SYSTEM_STORAGE,
+};
+use candid::{CandidType, Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::collections::{HashMap, HashSet};
+use std::{borrow::Cow, cell::RefCell};
+
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub(crate) enum RuleTarget {
+    Category(String),
+    Item(u64),
+}
+
+// multiplies the score of the matching items, above 1 boosts and below 1 buries
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug)]
+pub(crate) struct Boost {
+    target: RuleTarget,
+    multiplier: f64,
+}
+
+// places an item at a fixed position of the list, 0 is the top
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug)]
+pub(crate) struct Pin {
+    item_id: u64,
+    position: u64,
+}
+
+// at most max_items items of a category in one list
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug)]
+pub(crate) struct CategoryCap {
+    category: String,
+    max_items: u64,
+}
+
+// business rules applied to the scored recommendations of a recommendation system, in this order:
+// include/exclude filters, boosts, re-sorting, category caps and finally pins;
+// pinned items bypass the filters and caps
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug, Default)]
+pub(crate) struct RecommendationRules {
+    // when not empty only these categories or items are recommended
+    include_categories: Vec<String>,
+    include_item_ids: Vec<u64>,
+    exclude_categories: Vec<String>,
+    exclude_item_ids: Vec<u64>,
+    boosts: Vec<Boost>,
+    pins: Vec<Pin>,
+    category_caps: Vec<CategoryCap>,
+    // items the user already rated are left out unless this is false
+    exclude_rated: Option<bool>,
+}
+
+impl Storable for RecommendationRules {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for RecommendationRules {
+    const MAX_SIZE: u32 = 8192;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl RecommendationRules {
+    pub(crate) fn exclude_rated(&self) -> bool {
+        self.exclude_rated.unwrap_or(true)
+    }
+}
+
+thread_local! {
+    // recommendation system id -> rules
+    static RULES: RefCell<StableBTreeMap<u64, RecommendationRules, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20))))
+    );
+}
+
+fn normalize(category: &str) -> String {
+    category.trim().to_lowercase()
+}
+
+// the rules of a recommendation system, no rules by default
+pub(crate) fn rules_of(recommendation_system_id: u64) -> RecommendationRules {
+    RULES.with(|m| m.borrow().get(&recommendation_system_id)).unwrap_or_default()
+}
+
+// whether an item passes the include and exclude filters
+pub(crate) fn allows(rules: &RecommendationRules, item: &Item) -> bool {
+    let category = normalize(&item.category);
+    (rules.include_categories.is_empty() || rules.include_categories.iter().any(|c| normalize(c) == category))
+        && (rules.include_item_ids.is_empty() || rules.include_item_ids.contains(&item.id))
+        && !rules.exclude_categories.iter().any(|c| normalize(c) == category)
+        && !rules.exclude_item_ids.contains(&item.id)
+}
+
+// apply the rules to recommendations sorted by score and keep the first `limit`,
+// `rerank` runs on the filtered and capped list before the pins are placed
+pub(crate) fn apply(
+    rules: &RecommendationRules,
+    recommendations: Vec<Recommendation>,
+    limit: usize,
+    rerank: impl FnOnce(Vec<Recommendation>) -> Vec<Recommendation>,
+) -> Vec<Recommendation> {
+    let pinned: HashSet<u64> = rules.pins.iter().map(|pin| pin.item_id).collect();
+
+    let mut pinned_recommendations: HashMap<u64, Recommendation> = HashMap::new();
+    let mut ranked: Vec<Recommendation> = vec![];
+    for recommendation in recommendations {
+        let item = &recommendation.item;
+        if pinned.contains(&item.id) {
+            pinned_recommendations.insert(item.id, recommendation);
+        } else if allows(rules, item) {
+            ranked.push(recommendation);
+        }
+    }
+
+    for recommendation in ranked.iter_mut() {
+        for boost in &rules.boosts {
+            let matches = match &boost.target {
+                RuleTarget::Category(category) => normalize(category) == normalize(&recommendation.item.category),
+                RuleTarget::Item(item_id) => *item_id == recommendation.item.id,
+            };
+            if matches {
+                recommendation.score *= boost.multiplier;
+            }
+        }
+    }
+    ranked.sort_by(|a, b| b.score.total_cmp(&a.score).then(a.item.id.cmp(&b.item.id)));
+
+    let caps: HashMap<String, u64> = rules.category_caps.iter().map(|cap| (normalize(&cap.category), cap.max_items)).collect();
+    let mut counts: HashMap<String, u64> = HashMap::new();
+    ranked.retain(|recommendation| {
+        let category = normalize(&recommendation.item.category);
+        let count = counts.entry(category.clone()).or_insert(0);
+        *count += 1;
+        caps.get(&category).is_none_or(|max_items| *count <= *max_items)
+    });
+
+    ranked = rerank(ranked);
+    let mut pins: Vec<&Pin> = rules.pins.iter().collect();
+    pins.sort_by_key(|pin| (pin.position, pin.item_id));
+    for pin in pins {
+        let recommendation = pinned_recommendations.remove(&pin.item_id).or_else(|| {
+            ITEM_STORAGE
+                .with(|m| m.borrow().get(&pin.item_id))
+                .filter(|item| item.deleted_at.is_none())
+                .map(|item: Item| Recommendation::new(item, 0.0))
+        });
+        if let Some(recommendation) = recommendation {
+            let position = (pin.position as usize).min(ranked.len());
+            ranked.insert(position, recommendation);
+        }
+    }
+    ranked.truncate(limit);
+    ranked
+}
+
+fn validate(recommendation_system: &RecommendationSystem, rules: &RecommendationRules) -> Result<(), Error> {
+    if let Some(boost) = rules.boosts.iter().find(|boost| !(boost.multiplier.is_finite() && boost.multiplier > 0.0)) {
+        return Err(Error::InvalidInput { msg: format!("boost multiplier must be positive, got {}", boost.multiplier) });
+    }
+    let items: HashSet<u64> = recommendation_system.items.iter().map(|item| item.id).collect();
+    if let Some(pin) = rules.pins.iter().find(|pin| !items.contains(&pin.item_id) || !item_is_active(pin.item_id)) {
+        return Err(Error::NotFound {
+            msg: format!(
+                "item with id={} not found in recommendation system with id={}",
+                pin.item_id, recommendation_system.id
+            ),
+        });
+    }
+    let mut pinned = HashSet::new();
+    if let Some(pin) = rules.pins.iter().find(|pin| !pinned.insert(pin.item_id)) {
+        return Err(Error::InvalidInput { msg: format!("item with id={} is pinned more than once", pin.item_id) });
+    }
+    if Encode!(rules).unwrap().len() > RecommendationRules::MAX_SIZE as usize {
+        return Err(Error::InvalidInput { msg: "too many rules".to_string() });
+    }
+    Ok(())
+}
+
+// function to get the business rules of a recommendation system
+#[ic_cdk::query]
+fn get_recommendation_rules(recommendation_system_id: u64) -> RecommendationRules {
+    rules_of(recommendation_system_id)
+}
+
+// function to replace the business rules of a recommendation system, restricted to controllers
+#[ic_cdk::update]
+fn set_recommendation_rules(recommendation_system_id: u64, rules: RecommendationRules) -> Result<RecommendationRules, Error> {
+    ensure_controller("change recommendation rules")?;
+    let recommendation_system = RECOMMENDATION_SYSTEM_STORAGE
+        .with(|service| service.borrow().get(&recommendation_system_id))
+        .filter(|record| record.deleted_at.is_none())
+        .ok_or(Error::NotFound {
+            msg: format!("recommendation system with id={} not found", recommendation_system_id),
+        })?;
+    validate(&recommendation_system, &rules)?;
+
+    let previous = rules_of(recommendation_system_id);
+    RULES.with(|m| m.borrow_mut().insert(recommendation_system_id, rules.clone()));
+    // cached lists are computed with or without the rated items
+    if previous.exclude_rated() != rules.exclude_rated() {
+        recommendations::invalidate_recommendation_system(recommendation_system_id);
+    }
+    audit::record("set_recommendation_rules", vec![recommendation_system_id], Some(&previous), Some(&rules));
+    Ok(rules)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recommendation(id: u64, category: &str, score: f64) -> Recommendation {
+        let item = Item { id, category: category.to_string(), ..Default::default() };
+        ITEM_STORAGE.with(|m| m.borrow_mut().insert(id, item.clone()));
+        Recommendation::new(item, score)
+    }
+
+    fn ids(recommendations: &[Recommendation]) -> Vec<u64> {
+        recommendations.iter().map(|recommendation| recommendation.item.id).collect()
+    }
+
+    fn candidates() -> Vec<Recommendation> {
+        vec![
+            recommendation(1, "Books", 5.0),
+            recommendation(2, "Books", 4.0),
+            recommendation(3, "Music", 3.0),
+            recommendation(4, "Books", 2.0),
+            recommendation(5, "Games", 1.0),
+        ]
+    }
+
+    #[test]
+    fn filters_and_boosts_rerank() {
+        let rules = RecommendationRules {
+            exclude_categories: vec![" games ".to_string()],
+            exclude_item_ids: vec![2],
+            boosts: vec![Boost { target: RuleTarget::Category("Music".to_string()), multiplier: 2.0 }],
+            ..Default::default()
+        };
+        assert_eq!(ids(&apply(&rules, candidates(), 10, |ranked| ranked)), vec![3, 1, 4]);
+
+        let rules = RecommendationRules { include_categories: vec!["Music".to_string()], ..Default::default() };
+        assert_eq!(ids(&apply(&rules, candidates(), 10, |ranked| ranked)), vec![3]);
+    }
+
+    #[test]
+    fn caps_and_pins_apply_last() {
+        let rules = RecommendationRules {
+            exclude_item_ids: vec![5],
+            category_caps: vec![CategoryCap { category: "Books".to_string(), max_items: 1 }],
+            pins: vec![Pin { item_id: 5, position: 0 }, Pin { item_id: 4, position: 9 }],
+            ..Default::default()
+        };
+        assert_eq!(ids(&apply(&rules, candidates(), 10, |ranked| ranked)), vec![5, 1, 3, 4]);
+        assert_eq!(ids(&apply(&rules, candidates(), 2, |ranked| ranked)), vec![5, 1]);
+    }
+
+    #[test]
+    fn pins_must_belong_to_the_recommendation_system() {
+        let items = candidates().into_iter().map(|recommendation| recommendation.item).take(2).collect();
+        let recommendation_system = RecommendationSystem { id: 1, items, ..Default::default() };
+        let pinned = |item_id| RecommendationRules { pins: vec![Pin { item_id, position: 0 }], ..Default::default() };
+        assert!(validate(&recommendation_system, &pinned(2)).is_ok());
+        // item 3 exists but belongs to another recommendation system
+        assert!(matches!(validate(&recommendation_system, &pinned(3)), Err(Error::NotFound { .. })));
+    }
+}
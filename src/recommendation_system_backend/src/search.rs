@@ -0,0 +1,654 @@
+use crate::attributes::{self, AttributeFilter};
+use crate::{Error, Item, Memory, ITEM_STORAGE, MEMORY_MANAGER, RECOMMENDATION_SYSTEM_STORAGE, USER_PREFERENCE_STORAGE};
+use candid::CandidType;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use std::collections::{HashMap, HashSet};
+use std::{borrow::Cow, cell::RefCell};
+
+// terms longer than this are not indexed
+const MAX_TERM_LENGTH: usize = 64;
+
+// BM25 parameters
+const BM25_K1: f64 = 1.2;
+const BM25_B: f64 = 0.75;
+
+// a term found in the name counts three times, in the category twice
+const NAME_WEIGHT: u32 = 3;
+const CATEGORY_WEIGHT: u32 = 2;
+const DESCRIPTION_WEIGHT: u32 = 1;
+
+// the last query word is also matched as a prefix of at most this many terms,
+// which score a bit lower than exact matches
+const MAX_PREFIX_EXPANSIONS: usize = 20;
+const PREFIX_MATCH_WEIGHT: f64 = 0.8;
+
+// how strongly the user's category affinity re-ranks the results
+const PERSONALIZATION_WEIGHT: f64 = 0.5;
+
+const DEFAULT_PAGE_SIZE: u64 = 20;
+const MAX_PAGE_SIZE: u64 = 100;
+
+const STOP_WORDS: &[&str] = &[
+    "a", "an", "and", "are", "as", "at", "be", "but", "by", "for", "from", "has", "have", "in", "into", "is", "it",
+    "its", "of", "on", "or", "that", "the", "their", "this", "to", "was", "were", "will", "with",
+];
+
+// key of the inverted index, one posting per (term, item)
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct PostingKey {
+    term: String,
+    item_id: u64,
+}
+
+impl Storable for PostingKey {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        let mut bytes = self.term.as_bytes().to_vec();
+        bytes.extend_from_slice(&self.item_id.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let (term, item_id) = bytes.split_at(bytes.len() - 8);
+        PostingKey {
+            term: String::from_utf8(term.to_vec()).unwrap(),
+            item_id: u64::from_be_bytes(item_id.try_into().unwrap()),
+        }
+    }
+}
+
+impl BoundedStorable for PostingKey {
+    const MAX_SIZE: u32 = MAX_TERM_LENGTH as u32 + 8;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // (term, item id) -> weighted term frequency
+    static POSTINGS: RefCell<StableBTreeMap<PostingKey, u32, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13))))
+    );
+
+    // item id -> number of indexed terms
+    static DOCUMENT_LENGTHS: RefCell<StableBTreeMap<u64, u32, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14))))
+    );
+
+    // sum of all document lengths, for the average document length
+    static TOTAL_DOCUMENT_LENGTH: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15))), 0)
+            .expect("Cannot create the total document length")
+    );
+}
+
+// search filters, user_id re-ranks the results with that user's category affinity;
+// tags, price, availability and attributes filter on the items' metadata
+#[derive(CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct SearchFilters {
+    pub(crate) categories: Option<Vec<String>>,
+    pub(crate) user_id: Option<u64>,
+    // items must carry every tag
+    pub(crate) tags: Option<Vec<String>>,
+    pub(crate) min_price: Option<f64>,
+    pub(crate) max_price: Option<f64>,
+    // items without availability count as available
+    pub(crate) available: Option<bool>,
+    pub(crate) attributes: Option<Vec<AttributeFilter>>,
+}
+
+#[derive(CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct Page {
+    pub(crate) offset: Option<u64>,
+    pub(crate) limit: Option<u64>,
+}
+
+#[derive(CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct SearchResult {
+    item: Item,
+    score: f64,
+}
+
+impl SearchResult {
+    pub(crate) fn new(item: Item, score: f64) -> Self {
+        SearchResult { item, score }
+    }
+}
+
+#[derive(CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct SearchPage {
+    results: Vec<SearchResult>,
+    total: u64,
+}
+
+// function to search the items of a recommendation system
+#[ic_cdk::query]
+pub(crate) fn search_items(recommendation_system_id: u64, query: String, filters: SearchFilters, page: Page) -> Result<SearchPage, Error> {
+    let recommendation_system = RECOMMENDATION_SYSTEM_STORAGE
+        .with(|service| service.borrow().get(&recommendation_system_id))
+        .filter(|record| record.deleted_at.is_none())
+        .ok_or(Error::NotFound {
+            msg: format!("recommendation system with id={} not found", recommendation_system_id),
+        })?;
+    let item_ids: HashSet<u64> = recommendation_system.items.iter().map(|item| item.id).collect();
+    Ok(search(&query, &item_ids, &filters, &page))
+}
+
+fn search(query: &str, item_ids: &HashSet<u64>, filters: &SearchFilters, page: &Page) -> SearchPage {
+    let categories: Option<HashSet<String>> = filters
+        .categories
+        .as_ref()
+        .map(|categories| categories.iter().map(|category| category.trim().to_lowercase()).collect());
+    let item_ids = attributes::narrow_by_tags(item_ids, filters);
+    let mut scored: Vec<(Item, f64)> = score(query, &item_ids)
+        .into_iter()
+        .filter(|(id, _)| attributes::matches(*id, filters))
+        .filter_map(|(id, score)| ITEM_STORAGE.with(|m| m.borrow().get(&id)).map(|item| (item, score)))
+        .filter(|(item, _)| item.deleted_at.is_none())
+        .filter(|(item, _)| categories.as_ref().is_none_or(|categories| categories.contains(&item.category.trim().to_lowercase())))
+        .collect();
+
+    if let Some(user_id) = filters.user_id {
+        let affinity = category_affinity(user_id);
+        for (item, score) in scored.iter_mut() {
+            let affinity = affinity.get(&item.category.trim().to_lowercase()).copied().unwrap_or(1.0);
+            *score *= 1.0 + PERSONALIZATION_WEIGHT * (affinity - 1.0);
+        }
+    }
+
+    // best score first, ties broken by id so paging is stable
+    scored.sort_by(|(a, a_score), (b, b_score)| b_score.total_cmp(a_score).then(a.id.cmp(&b.id)));
+    let total = scored.len() as u64;
+    let offset = page.offset.unwrap_or(0) as usize;
+    let limit = page.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE) as usize;
+    let results = scored
+        .into_iter()
+        .skip(offset)
+        .take(limit)
+        .map(|(item, score)| SearchResult { item, score })
+        .collect();
+    SearchPage { results, total }
+}
+
+// BM25 score of every item of `item_ids` matching at least one query term
+fn score(query: &str, item_ids: &HashSet<u64>) -> HashMap<u64, f64> {
+    let words = tokenize(query);
+    let document_count = DOCUMENT_LENGTHS.with(|m| m.borrow().len()).max(1) as f64;
+    let average_length = TOTAL_DOCUMENT_LENGTH.with(|cell| *cell.borrow().get()) as f64 / document_count;
+
+    // exact stems, plus the raw last word as a prefix unless the query ends with a space
+    let mut terms: HashMap<String, f64> = words.iter().map(|word| (stem(word), 1.0)).collect();
+    if let Some(last) = words.last().filter(|_| !query.ends_with(char::is_whitespace)) {
+        for term in prefix_terms(last) {
+            terms.entry(term).or_insert(PREFIX_MATCH_WEIGHT);
+        }
+    }
+
+    let mut scores = HashMap::new();
+    for (term, weight) in terms {
+        let postings = postings(&term);
+        let document_frequency = postings.len() as f64;
+        let idf = (1.0 + (document_count - document_frequency + 0.5) / (document_frequency + 0.5)).ln();
+        for (item_id, frequency) in postings.into_iter().filter(|(item_id, _)| item_ids.contains(item_id)) {
+            let length = DOCUMENT_LENGTHS.with(|m| m.borrow().get(&item_id)).unwrap_or(0) as f64;
+            let frequency = frequency as f64;
+            let normalization = BM25_K1 * (1.0 - BM25_B + BM25_B * length / average_length.max(1.0));
+            *scores.entry(item_id).or_insert(0.0) +=
+                weight * idf * frequency * (BM25_K1 + 1.0) / (frequency + normalization);
+        }
+    }
+    scores
+}
+
+fn postings(term: &str) -> Vec<(u64, u32)> {
+    let start = PostingKey { term: term.to_string(), item_id: 0 };
+    let end = PostingKey { term: term.to_string(), item_id: u64::MAX };
+    POSTINGS.with(|m| m.borrow().range(start..=end).map(|(key, frequency)| (key.item_id, frequency)).collect())
+}
+
+// distinct indexed terms starting with `prefix`
+fn prefix_terms(prefix: &str) -> Vec<String> {
+    let start = PostingKey { term: prefix.to_string(), item_id: 0 };
+    POSTINGS.with(|m| {
+        let mut terms: Vec<String> = vec![];
+        for (key, _) in m.borrow().range(start..) {
+            if !key.term.starts_with(prefix) || terms.len() == MAX_PREFIX_EXPANSIONS && terms.last() != Some(&key.term) {
+                break;
+            }
+            if terms.last() != Some(&key.term) {
+                terms.push(key.term);
+            }
+        }
+        terms
+    })
+}
+
+// mean rating the user gave to each category relative to the user's overall mean rating
+fn category_affinity(user_id: u64) -> HashMap<String, f64> {
+    let ratings: Vec<(String, f64)> = USER_PREFERENCE_STORAGE.with(|m| {
+        m.borrow()
+            .iter()
+            .map(|(_, preference)| preference)
+            .filter(|preference| preference.user_id == user_id && preference.deleted_at.is_none())
+            .filter_map(|preference| {
+                ITEM_STORAGE
+                    .with(|items| items.borrow().get(&preference.item_id))
+                    .map(|item| (item.category.trim().to_lowercase(), preference.rating as f64))
+            })
+            .collect()
+    });
+    if ratings.is_empty() {
+        return HashMap::new();
+    }
+
+    let mean = ratings.iter().map(|(_, rating)| rating).sum::<f64>() / ratings.len() as f64;
+    let mut totals: HashMap<String, (f64, f64)> = HashMap::new();
+    for (category, rating) in ratings {
+        let total = totals.entry(category).or_insert((0.0, 0.0));
+        total.0 += rating;
+        total.1 += 1.0;
+    }
+    totals
+        .into_iter()
+        .map(|(category, (sum, count))| (category, (sum / count / mean).clamp(0.5, 2.0)))
+        .collect()
+}
+
+// weighted term frequencies of an item and its number of terms
+fn item_terms(item: &Item) -> (HashMap<String, u32>, u32) {
+    let mut frequencies = HashMap::new();
+    let mut length = 0;
+    for (text, weight) in [
+        (&item.name, NAME_WEIGHT),
+        (&item.category, CATEGORY_WEIGHT),
+        (&item.description, DESCRIPTION_WEIGHT),
+    ] {
+        for word in tokenize(text) {
+            *frequencies.entry(stem(&word)).or_insert(0) += weight;
+            length += 1;
+        }
+    }
+    (frequencies, length)
+}
+
+// add an item to the index
+pub(crate) fn index_item(item: &Item) {
+    let (frequencies, length) = item_terms(item);
+    POSTINGS.with(|m| {
+        let mut postings = m.borrow_mut();
+        for (term, frequency) in frequencies {
+            postings.insert(PostingKey { term, item_id: item.id }, frequency);
+        }
+    });
+    let previous = DOCUMENT_LENGTHS.with(|m| m.borrow_mut().insert(item.id, length)).unwrap_or(0);
+    update_total_length(length as i64 - previous as i64);
+}
+
+// remove an item, as it was last indexed, from the index
+pub(crate) fn remove_item(item: &Item) {
+    let (frequencies, _) = item_terms(item);
+    POSTINGS.with(|m| {
+        let mut postings = m.borrow_mut();
+        for term in frequencies.into_keys() {
+            postings.remove(&PostingKey { term, item_id: item.id });
+        }
+    });
+    if let Some(length) = DOCUMENT_LENGTHS.with(|m| m.borrow_mut().remove(&item.id)) {
+        update_total_length(-(length as i64));
+    }
+}
+
+fn update_total_length(delta: i64) {
+    TOTAL_DOCUMENT_LENGTH
+        .with(|cell| {
+            let current_value = *cell.borrow().get();
+            cell.borrow_mut().set(current_value.saturating_add_signed(delta))
+        })
+        .expect("cannot update the total document length");
+}
+
+// index the active items when the index is empty, e.g. after upgrading from a version without search
+pub(crate) fn build_index_if_empty() {
+    if DOCUMENT_LENGTHS.with(|m| !m.borrow().is_empty()) {
+        return;
+    }
+    let items: Vec<Item> = ITEM_STORAGE.with(|m| m.borrow().iter().map(|(_, item)| item).filter(|item| item.deleted_at.is_none()).collect());
+    for item in &items {
+        index_item(item);
+    }
+}
+
+// lowercase alphanumeric words, without stop words and overlong words
+pub(crate) fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .filter(|word| word.len() <= MAX_TERM_LENGTH && !STOP_WORDS.contains(&word.as_str()))
+        .collect()
+}
+
+// Porter stemmer (M.F. Porter, 1980), non-ascii words are left as they are
+pub(crate) fn stem(word: &str) -> String {
+    if !word.is_ascii() || word.len() <= 2 {
+        return word.to_string();
+    }
+    let mut stemmer = Stemmer { b: word.as_bytes().to_vec(), k: word.len() - 1, j: 0 };
+    stemmer.step1ab();
+    if stemmer.k > 0 {
+        stemmer.step1c();
+        stemmer.step2();
+        stemmer.step3();
+        stemmer.step4();
+        stemmer.step5();
+    }
+    stemmer.b.truncate(stemmer.k + 1);
+    String::from_utf8(stemmer.b).unwrap()
+}
+
+// b[0..=k] is the word being stemmed, b[0..=j] the stem left when a suffix matched
+// (j is -1 when the suffix is the whole word)
+struct Stemmer {
+    b: Vec<u8>,
+    k: usize,
+    j: isize,
+}
+
+impl Stemmer {
+    fn cons(&self, i: usize) -> bool {
+        match self.b[i] {
+            b'a' | b'e' | b'i' | b'o' | b'u' => false,
+            b'y' => i == 0 || !self.cons(i - 1),
+            _ => true,
+        }
+    }
+
+    // number of vowel-consonant sequences in b[0..=j]
+    fn m(&self) -> usize {
+        let mut n = 0;
+        let mut i = 0;
+        let end = self.j;
+        if end < 0 {
+            return 0;
+        }
+        let end = end as usize;
+        loop {
+            if i > end {
+                return n;
+            }
+            if !self.cons(i) {
+                break;
+            }
+            i += 1;
+        }
+        i += 1;
+        loop {
+            loop {
+                if i > end {
+                    return n;
+                }
+                if self.cons(i) {
+                    break;
+                }
+                i += 1;
+            }
+            i += 1;
+            n += 1;
+            loop {
+                if i > end {
+                    return n;
+                }
+                if !self.cons(i) {
+                    break;
+                }
+                i += 1;
+            }
+            i += 1;
+        }
+    }
+
+    fn vowel_in_stem(&self) -> bool {
+        (0..self.j + 1).any(|i| !self.cons(i as usize))
+    }
+
+    fn double_consonant(&self, j: usize) -> bool {
+        j >= 1 && self.b[j] == self.b[j - 1] && self.cons(j)
+    }
+
+    // consonant-vowel-consonant ending at i, where the last consonant is not w, x or y
+    fn cvc(&self, i: usize) -> bool {
+        if i < 2 || !self.cons(i) || self.cons(i - 1) || !self.cons(i - 2) {
+            return false;
+        }
+        !matches!(self.b[i], b'w' | b'x' | b'y')
+    }
+
+    fn ends(&mut self, suffix: &str) -> bool {
+        let suffix = suffix.as_bytes();
+        if suffix.len() > self.k + 1 || &self.b[self.k + 1 - suffix.len()..=self.k] != suffix {
+            return false;
+        }
+        self.j = self.k as isize - suffix.len() as isize;
+        true
+    }
+
+    fn set_to(&mut self, replacement: &str) {
+        let stem_length = (self.j + 1) as usize;
+        self.b.truncate(stem_length);
+        self.b.extend_from_slice(replacement.as_bytes());
+        self.k = stem_length + replacement.len() - 1;
+    }
+
+    fn replace_if_measure(&mut self, replacement: &str) {
+        if self.m() > 0 {
+            self.set_to(replacement);
+        }
+    }
+
+    fn step1ab(&mut self) {
+        if self.b[self.k] == b's' {
+            if self.ends("sses") {
+                self.k -= 2;
+            } else if self.ends("ies") {
+                self.set_to("i");
+            } else if self.b[self.k - 1] != b's' {
+                self.k -= 1;
+            }
+        }
+        if self.ends("eed") {
+            if self.m() > 0 {
+                self.k -= 1;
+            }
+        } else if (self.ends("ed") || self.ends("ing")) && self.vowel_in_stem() {
+            self.k = self.j as usize;
+            if self.ends("at") {
+                self.set_to("ate");
+            } else if self.ends("bl") {
+                self.set_to("ble");
+            } else if self.ends("iz") {
+                self.set_to("ize");
+            } else if self.double_consonant(self.k) {
+                if !matches!(self.b[self.k], b'l' | b's' | b'z') {
+                    self.k -= 1;
+                }
+            } else {
+                self.j = self.k as isize;
+                if self.m() == 1 && self.cvc(self.k) {
+                    self.set_to("e");
+                }
+            }
+        }
+    }
+
+    fn step1c(&mut self) {
+        if self.ends("y") && self.vowel_in_stem() {
+            self.b[self.k] = b'i';
+        }
+    }
+
+    fn replace_first(&mut self, rules: &[(&str, &str)]) {
+        for (suffix, replacement) in rules {
+            if self.ends(suffix) {
+                self.replace_if_measure(replacement);
+                return;
+            }
+        }
+    }
+
+    fn step2(&mut self) {
+        let rules: &[(&str, &str)] = match self.b[self.k - 1] {
+            b'a' => &[("ational", "ate"), ("tional", "tion")],
+            b'c' => &[("enci", "ence"), ("anci", "ance")],
+            b'e' => &[("izer", "ize")],
+            b'l' => &[("bli", "ble"), ("alli", "al"), ("entli", "ent"), ("eli", "e"), ("ousli", "ous")],
+            b'o' => &[("ization", "ize"), ("ation", "ate"), ("ator", "ate")],
+            b's' => &[("alism", "al"), ("iveness", "ive"), ("fulness", "ful"), ("ousness", "ous")],
+            b't' => &[("aliti", "al"), ("iviti", "ive"), ("biliti", "ble")],
+            b'g' => &[("logi", "log")],
+            _ => &[],
+        };
+        self.replace_first(rules);
+    }
+
+    fn step3(&mut self) {
+        let rules: &[(&str, &str)] = match self.b[self.k] {
+            b'e' => &[("icate", "ic"), ("ative", ""), ("alize", "al")],
+            b'i' => &[("iciti", "ic")],
+            b'l' => &[("ical", "ic"), ("ful", "")],
+            b's' => &[("ness", "")],
+            _ => &[],
+        };
+        self.replace_first(rules);
+    }
+
+    fn step4(&mut self) {
+        let suffixes: &[&str] = match self.b[self.k - 1] {
+            b'a' => &["al"],
+            b'c' => &["ance", "ence"],
+            b'e' => &["er"],
+            b'i' => &["ic"],
+            b'l' => &["able", "ible"],
+            b'n' => &["ant", "ement", "ment", "ent"],
+            b'o' => &["ion", "ou"],
+            b's' => &["ism"],
+            b't' => &["ate", "iti"],
+            b'u' => &["ous"],
+            b'v' => &["ive"],
+            b'z' => &["ize"],
+            _ => &[],
+        };
+        for suffix in suffixes {
+            if self.ends(suffix) {
+                // -ion is only removed after s or t
+                if *suffix == "ion" && (self.j < 0 || !matches!(self.b[self.j as usize], b's' | b't')) {
+                    continue;
+                }
+                if self.m() > 1 {
+                    self.k = self.j as usize;
+                }
+                return;
+            }
+        }
+    }
+
+    fn step5(&mut self) {
+        self.j = self.k as isize;
+        if self.b[self.k] == b'e' {
+            let m = self.m();
+            if m > 1 || m == 1 && !self.cvc(self.k - 1) {
+                self.k -= 1;
+            }
+        }
+        if self.b[self.k] == b'l' && self.double_consonant(self.k) && self.m() > 1 {
+            self.k -= 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stem_matches_porter_reference() {
+        for (word, expected) in [
+            ("caresses", "caress"), ("ponies", "poni"), ("ties", "ti"), ("caress", "caress"), ("cats", "cat"),
+            ("feed", "feed"), ("agreed", "agre"), ("plastered", "plaster"), ("bled", "bled"), ("motoring", "motor"),
+            ("sing", "sing"), ("conflated", "conflat"), ("troubled", "troubl"), ("sized", "size"), ("hopping", "hop"),
+            ("tanned", "tan"), ("falling", "fall"), ("hissing", "hiss"), ("fizzed", "fizz"), ("failing", "fail"),
+            ("filing", "file"), ("happy", "happi"), ("sky", "sky"), ("relational", "relat"),
+            ("conditional", "condit"), ("rational", "ration"), ("digitizer", "digit"), ("vietnamization", "vietnam"),
+            ("predication", "predic"), ("operator", "oper"), ("feudalism", "feudal"), ("decisiveness", "decis"),
+            ("hopefulness", "hope"), ("callousness", "callous"), ("formaliti", "formal"), ("sensitiviti", "sensit"),
+            ("sensibiliti", "sensibl"), ("triplicate", "triplic"), ("formative", "form"), ("formalize", "formal"),
+            ("electrical", "electr"), ("hopeful", "hope"), ("goodness", "good"), ("revival", "reviv"),
+            ("allowance", "allow"), ("inference", "infer"), ("airliner", "airlin"), ("gyroscopic", "gyroscop"),
+            ("adjustable", "adjust"), ("defensible", "defens"), ("irritant", "irrit"), ("replacement", "replac"),
+            ("adjustment", "adjust"), ("dependent", "depend"), ("adoption", "adopt"), ("communism", "commun"),
+            ("activate", "activ"), ("homologous", "homolog"), ("effective", "effect"), ("bowdlerize", "bowdler"),
+            ("probate", "probat"), ("rate", "rate"), ("cease", "ceas"), ("controll", "control"), ("roll", "roll"),
+            ("generalization", "gener"), ("oscillators", "oscil"), ("ed", "ed"), ("is", "is"), ("ing", "ing"),
+        ] {
+            assert_eq!(stem(word), expected, "stem({})", word);
+        }
+    }
+
+    #[test]
+    fn tokenize_drops_stop_words_and_punctuation() {
+        assert_eq!(tokenize("The Lord of the Rings: Fellowship!"), vec!["lord", "rings", "fellowship"]);
+        assert_eq!(tokenize("  "), Vec::<String>::new());
+    }
+
+    fn add(id: u64, name: &str, category: &str, description: &str) {
+        let item = Item {
+            id,
+            name: name.to_string(),
+            category: category.to_string(),
+            description: description.to_string(),
+            ..Default::default()
+        };
+        ITEM_STORAGE.with(|m| m.borrow_mut().insert(id, item.clone()));
+        index_item(&item);
+    }
+
+    fn ids(page: &SearchPage) -> Vec<u64> {
+        page.results.iter().map(|result| result.item.id).collect()
+    }
+
+    #[test]
+    fn search_ranks_name_matches_first() {
+        add(0, "Dune", "Books", "A science fiction novel about the desert planet");
+        add(1, "Desert Plants", "Books", "Gardening in dry climates");
+        add(2, "Planet Earth", "Movies", "Documentary series");
+        let all: HashSet<u64> = [0, 1, 2].into();
+
+        let page = search("planets", &all, &SearchFilters::default(), &Page::default());
+        assert_eq!(ids(&page), vec![2, 0]);
+
+        let filters = SearchFilters { categories: Some(vec!["books".to_string()]), ..Default::default() };
+        assert_eq!(ids(&search("planet", &all, &filters, &Page::default())), vec![0]);
+
+        // items outside the recommendation system are never returned
+        assert_eq!(ids(&search("planet", &[0, 1].into(), &SearchFilters::default(), &Page::default())), vec![0]);
+    }
+
+    #[test]
+    fn search_matches_last_word_as_prefix() {
+        add(0, "Gardening Basics", "Books", "How to grow vegetables");
+        add(1, "Garage Tools", "Hardware", "Wrenches and screwdrivers");
+        let all: HashSet<u64> = [0, 1].into();
+
+        assert_eq!(search("gar", &all, &SearchFilters::default(), &Page::default()).total, 2);
+        assert_eq!(search("gar ", &all, &SearchFilters::default(), &Page::default()).total, 0);
+        let page = Page { offset: Some(1), limit: Some(1) };
+        assert_eq!(search("gar", &all, &SearchFilters::default(), &page).results.len(), 1);
+    }
+
+    #[test]
+    fn remove_item_clears_postings() {
+        add(0, "Dune", "Books", "Desert planet");
+        let item = ITEM_STORAGE.with(|m| m.borrow().get(&0)).unwrap();
+        remove_item(&item);
+
+        assert!(POSTINGS.with(|m| m.borrow().is_empty()));
+        assert_eq!(TOTAL_DOCUMENT_LENGTH.with(|cell| *cell.borrow().get()), 0);
+        assert_eq!(search("dune", &[0].into(), &SearchFilters::default(), &Page::default()).total, 0);
+    }
+}
@@ -0,0 +1,247 @@
+use crate::recommendations::{Recommendation, TOP_N};
+use crate::rules;
+use crate::{
+    audit, user_is_active, Error, Memory, RecommendationSystem, ITEM_STORAGE, MEMORY_MANAGER,
+    RECOMMENDATION_SYSTEM_STORAGE, USER_PREFERENCE_STORAGE,
+};
+use candid::{CandidType, Decode, Encode};
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use std::collections::{HashMap, HashSet};
+use std::{borrow::Cow, cell::RefCell};
+
+// longest item history used as context of the transitions
+const MARKOV_ORDER: usize = 2;
+
+// events kept per session, older ones are dropped
+const MAX_SESSION_EVENTS: usize = 200;
+
+// weight of the overall item popularity, so sessions with unseen contexts still get recommendations
+const POPULARITY_WEIGHT: f64 = 0.05;
+
+// a browsing session of an anonymous shopper in a recommendation system
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug, Default)]
+pub(crate) struct Session {
+    id: u64,
+    recommendation_system_id: u64,
+    // viewed items, oldest first
+    item_ids: Vec<u64>,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+impl Storable for Session {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Session {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static SESSION_ID_COUNTER: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(32))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static SESSIONS: RefCell<StableBTreeMap<u64, Session, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(31))))
+    );
+}
+
+// counts of the items following every context of 1 to MARKOV_ORDER items
+#[derive(Default)]
+struct TransitionModel {
+    transitions: HashMap<Vec<u64>, HashMap<u64, f64>>,
+    popularity: HashMap<u64, f64>,
+}
+
+impl TransitionModel {
+    fn train(sequences: &[Vec<u64>]) -> Self {
+        let mut model = TransitionModel::default();
+        let mut total = 0.0;
+        for sequence in sequences {
+            for (index, &item_id) in sequence.iter().enumerate() {
+                *model.popularity.entry(item_id).or_insert(0.0) += 1.0;
+                total += 1.0;
+                for order in 1..=MARKOV_ORDER.min(index) {
+                    let context = sequence[index - order..index].to_vec();
+                    *model.transitions.entry(context).or_default().entry(item_id).or_insert(0.0) += 1.0;
+                }
+            }
+        }
+        model.popularity.values_mut().for_each(|count| *count /= total);
+        model
+    }
+
+    // next-item probabilities interpolated over the orders whose context was seen, higher orders weigh more,
+    // plus a small share of popularity
+    fn next(&self, history: &[u64]) -> HashMap<u64, f64> {
+        let mut scores: HashMap<u64, f64> = HashMap::new();
+        let mut weights = 0.0;
+        for order in 1..=MARKOV_ORDER.min(history.len()) {
+            let Some(followers) = self.transitions.get(&history[history.len() - order..]) else {
+                continue;
+            };
+            let total: f64 = followers.values().sum();
+            let weight = order as f64;
+            for (&item_id, &count) in followers {
+                *scores.entry(item_id).or_insert(0.0) += weight * count / total;
+            }
+            weights += weight;
+        }
+        if weights > 0.0 {
+            scores.values_mut().for_each(|score| *score *= (1.0 - POPULARITY_WEIGHT) / weights);
+        }
+        let popularity_weight = if weights > 0.0 { POPULARITY_WEIGHT } else { 1.0 };
+        for (&item_id, &share) in &self.popularity {
+            *scores.entry(item_id).or_insert(0.0) += popularity_weight * share;
+        }
+        scores
+    }
+}
+
+// interaction sequences of a recommendation system: every active user's preferences ordered by created_at
+// and every session's events
+fn sequences(recommendation_system: &RecommendationSystem) -> Vec<Vec<u64>> {
+    let items: HashSet<u64> = recommendation_system.items.iter().map(|item| item.id).collect();
+    let mut by_user: HashMap<u64, Vec<(u64, u64, u64)>> = HashMap::new();
+    USER_PREFERENCE_STORAGE.with(|m| {
+        for (_, preference) in m.borrow().iter() {
+            let active = preference.deleted_at.is_none() && user_is_active(preference.user_id);
+            if active && items.contains(&preference.item_id) {
+                by_user.entry(preference.user_id).or_default().push((
+                    preference.created_at,
+                    preference.id,
+                    preference.item_id,
+                ));
+            }
+        }
+    });
+    let mut sequences: Vec<Vec<u64>> = by_user
+        .into_values()
+        .map(|mut events| {
+            events.sort();
+            events.into_iter().map(|(_, _, item_id)| item_id).collect()
+        })
+        .collect();
+    SESSIONS.with(|m| {
+        for (_, session) in m.borrow().iter() {
+            if session.recommendation_system_id == recommendation_system.id {
+                sequences.push(session.item_ids.iter().copied().filter(|id| items.contains(id)).collect());
+            }
+        }
+    });
+    sequences
+}
+
+fn active_recommendation_system(id: u64) -> Result<RecommendationSystem, Error> {
+    RECOMMENDATION_SYSTEM_STORAGE
+        .with(|service| service.borrow().get(&id))
+        .filter(|record| record.deleted_at.is_none())
+        .ok_or(Error::NotFound { msg: format!("recommendation system with id={} not found", id) })
+}
+
+fn session_by_id(id: u64) -> Result<Session, Error> {
+    SESSIONS.with(|m| m.borrow().get(&id)).ok_or(Error::NotFound { msg: format!("session with id={} not found", id) })
+}
+
+// function to start an anonymous session in a recommendation system
+#[ic_cdk::update]
+pub(crate) fn start_session(recommendation_system_id: u64) -> Result<Session, Error> {
+    active_recommendation_system(recommendation_system_id)?;
+    let id = SESSION_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment id counter");
+    let session = Session { id, recommendation_system_id, created_at: time(), ..Default::default() };
+    SESSIONS.with(|m| m.borrow_mut().insert(id, session.clone()));
+    audit::record("start_session", vec![id], None, Some(&session));
+    Ok(session)
+}
+
+// function to record that the shopper of a session viewed an item of the recommendation system
+#[ic_cdk::update]
+pub(crate) fn record_session_event(session_id: u64, item_id: u64) -> Result<Session, Error> {
+    let mut session = session_by_id(session_id)?;
+    let before = session.clone();
+    let recommendation_system = active_recommendation_system(session.recommendation_system_id)?;
+    let active = ITEM_STORAGE.with(|m| m.borrow().get(&item_id)).is_some_and(|item| item.deleted_at.is_none());
+    if !active || !recommendation_system.items.iter().any(|item| item.id == item_id) {
+        return Err(Error::NotFound {
+            msg: format!("item with id={} not found in recommendation system with id={}", item_id, recommendation_system.id),
+        });
+    }
+    session.item_ids.push(item_id);
+    if session.item_ids.len() > MAX_SESSION_EVENTS {
+        session.item_ids.remove(0);
+    }
+    session.updated_at = Some(time());
+    SESSIONS.with(|m| m.borrow_mut().insert(session_id, session.clone()));
+    audit::record("record_session_event", vec![session_id], Some(&before), Some(&session));
+    Ok(session)
+}
+
+// function to get a session by id
+#[ic_cdk::query]
+fn get_session(id: u64) -> Result<Session, Error> {
+    session_by_id(id)
+}
+
+// function to recommend the k items most likely to be viewed next in a session, from a Markov chain
+// over the interaction sequences of the recommendation system; items already viewed are left out
+#[ic_cdk::query]
+pub(crate) fn get_next_item_recommendations(session_id: u64, k: u64) -> Result<Vec<Recommendation>, Error> {
+    let session = session_by_id(session_id)?;
+    let recommendation_system = active_recommendation_system(session.recommendation_system_id)?;
+    let model = TransitionModel::train(&sequences(&recommendation_system));
+    let rules = rules::rules_of(recommendation_system.id);
+    let viewed: HashSet<u64> = session.item_ids.iter().copied().collect();
+
+    let mut scores: Vec<(u64, f64)> =
+        model.next(&session.item_ids).into_iter().filter(|(item_id, _)| !viewed.contains(item_id)).collect();
+    scores.sort_by(|(a, a_score), (b, b_score)| b_score.total_cmp(a_score).then(a.cmp(b)));
+    Ok(scores
+        .into_iter()
+        .filter_map(|(item_id, score)| {
+            ITEM_STORAGE
+                .with(|m| m.borrow().get(&item_id))
+                .filter(|item| item.deleted_at.is_none() && rules::allows(&rules, item))
+                .map(|item| Recommendation::new(item, score))
+        })
+        .take(k.clamp(1, TOP_N as u64) as usize)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranked(scores: HashMap<u64, f64>) -> Vec<u64> {
+        let mut scores: Vec<(u64, f64)> = scores.into_iter().collect();
+        scores.sort_by(|(a, a_score), (b, b_score)| b_score.total_cmp(a_score).then(a.cmp(b)));
+        scores.into_iter().map(|(item_id, _)| item_id).collect()
+    }
+
+    #[test]
+    fn higher_orders_refine_the_next_item() {
+        let model = TransitionModel::train(&[vec![1, 2, 3], vec![1, 2, 3], vec![4, 2, 5], vec![4, 2, 5], vec![6, 2, 5]]);
+
+        // after 2 alone, 5 follows more often; after 1 then 2, it is always 3
+        assert_eq!(ranked(model.next(&[2]))[0], 5);
+        assert_eq!(ranked(model.next(&[1, 2]))[0], 3);
+        // an unseen context falls back to popularity
+        assert_eq!(ranked(model.next(&[7]))[0], 2);
+        assert!(model.next(&[]).values().all(|&score| score > 0.0));
+    }
+}
@@ -0,0 +1,144 @@
+use crate::associations::AssociationSettings;
+use crate::bandit::ExplorationSettings;
+use crate::graph::GraphSettings;
+use crate::{audit, ensure_controller, linucb, recommendations, Error, Memory, MEMORY_MANAGER, RECOMMENDATION_SYSTEM_STORAGE};
+use candid::{CandidType, Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// how a recommendation system scores items
+#[derive(CandidType, Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq)]
+pub(crate) enum Algorithm {
+    // item-based collaborative filtering with popular items as fallback
+    #[default]
+    ItemBasedCollaborativeFiltering,
+    // contextual bandit over item and user features
+    LinUcb,
+    // weighted Slope One rating predictions
+    SlopeOne,
+    // three-step random walk over the user-item graph with a popularity penalty
+    Rp3Beta,
+    // random walk with restart over the user-item graph
+    PersonalizedPageRank,
+    // ratings of the users sharing the user's age band and language
+    Demographic,
+}
+
+// tuning of how a recommendation system ranks its recommendations, unset fields use the defaults
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug, Default)]
+pub(crate) struct RecommendationSettings {
+    // relevance/diversity trade-off of the MMR re-ranking, from 0 (relevance only) to 1
+    pub(crate) diversity: Option<f64>,
+    // bandit exploration of the system's items, off when unset
+    pub(crate) exploration: Option<ExplorationSettings>,
+    // ItemBasedCollaborativeFiltering by default
+    pub(crate) algorithm: Option<Algorithm>,
+    // weight of the confidence bound of LinUcb, 1 by default
+    pub(crate) linucb_alpha: Option<f64>,
+    // thresholds of the association rules mined for the system
+    pub(crate) associations: Option<AssociationSettings>,
+    // parameters of Rp3Beta and PersonalizedPageRank
+    pub(crate) graph: Option<GraphSettings>,
+}
+
+impl RecommendationSettings {
+    pub(crate) fn algorithm(&self) -> Algorithm {
+        self.algorithm.unwrap_or_default()
+    }
+
+    pub(crate) fn linucb_alpha(&self) -> f64 {
+        self.linucb_alpha.unwrap_or(linucb::DEFAULT_ALPHA)
+    }
+
+    // whether both settings compute the same scored lists, so they can share cached lists
+    pub(crate) fn scores_like(&self, other: &RecommendationSettings) -> bool {
+        self.algorithm() == other.algorithm()
+            && self.linucb_alpha() == other.linucb_alpha()
+            && self.graph.clone().unwrap_or_default() == other.graph.clone().unwrap_or_default()
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        validate_diversity(self.diversity)?;
+        if let Some(exploration) = &self.exploration {
+            exploration.validate()?;
+        }
+        if let Some(associations) = &self.associations {
+            associations.validate()?;
+        }
+        if let Some(graph) = &self.graph {
+            graph.validate()?;
+        }
+        if self.linucb_alpha.is_some_and(|alpha| !(alpha.is_finite() && alpha >= 0.0)) {
+            return Err(Error::InvalidInput { msg: "linucb_alpha must be zero or positive".to_string() });
+        }
+        Ok(())
+    }
+}
+
+impl Storable for RecommendationSettings {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for RecommendationSettings {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // recommendation system id -> settings
+    static SETTINGS: RefCell<StableBTreeMap<u64, RecommendationSettings, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21))))
+    );
+}
+
+// the settings of a recommendation system, defaults when never set
+pub(crate) fn settings_of(recommendation_system_id: u64) -> RecommendationSettings {
+    SETTINGS.with(|m| m.borrow().get(&recommendation_system_id)).unwrap_or_default()
+}
+
+pub(crate) fn validate_diversity(diversity: Option<f64>) -> Result<(), Error> {
+    match diversity {
+        Some(diversity) if !(0.0..=1.0).contains(&diversity) => Err(Error::InvalidInput {
+            msg: format!("diversity must be between 0 and 1, got {}", diversity),
+        }),
+        _ => Ok(()),
+    }
+}
+
+// function to get the settings of a recommendation system
+#[ic_cdk::query]
+fn get_recommendation_settings(recommendation_system_id: u64) -> RecommendationSettings {
+    settings_of(recommendation_system_id)
+}
+
+// function to replace the settings of a recommendation system, restricted to controllers
+#[ic_cdk::update]
+fn set_recommendation_settings(
+    recommendation_system_id: u64,
+    settings: RecommendationSettings,
+) -> Result<RecommendationSettings, Error> {
+    ensure_controller("change recommendation settings")?;
+    RECOMMENDATION_SYSTEM_STORAGE
+        .with(|service| service.borrow().get(&recommendation_system_id))
+        .filter(|record| record.deleted_at.is_none())
+        .ok_or(Error::NotFound {
+            msg: format!("recommendation system with id={} not found", recommendation_system_id),
+        })?;
+    settings.validate()?;
+
+    let previous = settings_of(recommendation_system_id);
+    SETTINGS.with(|m| m.borrow_mut().insert(recommendation_system_id, settings.clone()));
+    // cached lists were computed by the previous algorithm
+    if !previous.scores_like(&settings) {
+        recommendations::invalidate_recommendation_system(recommendation_system_id);
+    }
+    audit::record("set_recommendation_settings", vec![recommendation_system_id], Some(&previous), Some(&settings));
+    Ok(settings)
+}
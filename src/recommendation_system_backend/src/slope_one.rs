@@ -0,0 +1,251 @@
+use crate::recommendations::{Ratings, ScoredItem};
+use crate::{user_is_active, Memory, UserPreference, MEMORY_MANAGER, USER_PREFERENCE_STORAGE};
+use candid::{CandidType, Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::collections::HashMap;
+use std::{borrow::Cow, cell::RefCell};
+
+// key of the deviation of an item from another, stored in both directions so the deviations
+// of an item are adjacent
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+struct PairKey {
+    item_id: u64,
+    other_item_id: u64,
+}
+
+impl Storable for PairKey {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        let mut bytes = self.item_id.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&self.other_item_id.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let (item_id, other_item_id) = bytes.split_at(8);
+        PairKey {
+            item_id: u64::from_be_bytes(item_id.try_into().unwrap()),
+            other_item_id: u64::from_be_bytes(other_item_id.try_into().unwrap()),
+        }
+    }
+}
+
+impl BoundedStorable for PairKey {
+    const MAX_SIZE: u32 = 16;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+// sum of (rating of the item - rating of the other item) over the users who rated both
+#[derive(CandidType, Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq)]
+struct Deviation {
+    sum: f64,
+    count: u64,
+}
+
+impl Storable for Deviation {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Deviation {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static DEVIATIONS: RefCell<StableBTreeMap<PairKey, Deviation, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(34))))
+    );
+}
+
+// add (sign 1) or remove (sign -1) a co-rating of two items
+fn update_pair(item_id: u64, rating: f64, other_item_id: u64, other_rating: f64, sign: f64) {
+    for (key, difference) in [
+        (PairKey { item_id, other_item_id }, rating - other_rating),
+        (PairKey { item_id: other_item_id, other_item_id: item_id }, other_rating - rating),
+    ] {
+        DEVIATIONS.with(|m| {
+            let mut m = m.borrow_mut();
+            let mut deviation = m.get(&key).unwrap_or_default();
+            deviation.sum += sign * difference;
+            deviation.count = if sign > 0.0 { deviation.count + 1 } else { deviation.count.saturating_sub(1) };
+            if deviation.count == 0 {
+                m.remove(&key);
+            } else {
+                m.insert(key, deviation);
+            }
+        });
+    }
+}
+
+fn update_rating(user_preference: &UserPreference, sign: f64) {
+    // users in the trash are left out of the table until they are restored
+    if !user_is_active(user_preference.user_id) {
+        return;
+    }
+    let others: Vec<UserPreference> = USER_PREFERENCE_STORAGE.with(|m| {
+        m.borrow()
+            .iter()
+            .map(|(_, other)| other)
+            .filter(|other| {
+                other.deleted_at.is_none()
+                    && other.id != user_preference.id
+                    && other.user_id == user_preference.user_id
+                    && other.item_id != user_preference.item_id
+            })
+            .collect()
+    });
+    for other in others {
+        update_pair(user_preference.item_id, user_preference.rating as f64, other.item_id, other.rating as f64, sign);
+    }
+}
+
+// account for an active user preference in the deviation table
+pub(crate) fn add_rating(user_preference: &UserPreference) {
+    update_rating(user_preference, 1.0);
+}
+
+// remove a user preference that was active from the deviation table
+pub(crate) fn remove_rating(user_preference: &UserPreference) {
+    update_rating(user_preference, -1.0);
+}
+
+// add (sign 1) or remove (sign -1) the co-ratings of every pair of items a user rated
+fn update_pairs(ratings: &[(u64, f64)], sign: f64) {
+    for (index, &(item_id, rating)) in ratings.iter().enumerate() {
+        for &(other_item_id, other_rating) in &ratings[index + 1..] {
+            if item_id != other_item_id {
+                update_pair(item_id, rating, other_item_id, other_rating, sign);
+            }
+        }
+    }
+}
+
+fn active_ratings_of(user_id: u64) -> Vec<(u64, f64)> {
+    USER_PREFERENCE_STORAGE.with(|m| {
+        m.borrow()
+            .iter()
+            .map(|(_, preference)| preference)
+            .filter(|preference| preference.deleted_at.is_none() && preference.user_id == user_id)
+            .map(|preference| (preference.item_id, preference.rating as f64))
+            .collect()
+    })
+}
+
+// remove the preferences of a user moved to the trash from the deviation table
+pub(crate) fn remove_user(user_id: u64) {
+    update_pairs(&active_ratings_of(user_id), -1.0);
+}
+
+// account again for the preferences of a user restored from the trash
+pub(crate) fn add_user(user_id: u64) {
+    update_pairs(&active_ratings_of(user_id), 1.0);
+}
+
+// build the deviation table from every active user preference, after upgrading from a version without it
+pub(crate) fn build_if_empty() {
+    if DEVIATIONS.with(|m| !m.borrow().is_empty()) {
+        return;
+    }
+    let mut by_user: HashMap<u64, Vec<(u64, f64)>> = HashMap::new();
+    USER_PREFERENCE_STORAGE.with(|m| {
+        for (_, preference) in m.borrow().iter().filter(|(_, preference)| preference.deleted_at.is_none()) {
+            by_user.entry(preference.user_id).or_default().push((preference.item_id, preference.rating as f64));
+        }
+    });
+    for (user_id, ratings) in &by_user {
+        if user_is_active(*user_id) {
+            update_pairs(ratings, 1.0);
+        }
+    }
+}
+
+// weighted Slope One predictions of the items of a recommendation system from a user's ratings:
+// each rated item i votes rating(i) + mean deviation of the item from i, weighted by the co-rating count
+fn predictions(ratings: &Ratings, user_id: u64) -> HashMap<u64, f64> {
+    let mut totals: HashMap<u64, (f64, f64)> = HashMap::new();
+    for (&rated_item_id, &rating) in ratings.by_user.get(&user_id).into_iter().flatten() {
+        let start = PairKey { item_id: rated_item_id, other_item_id: 0 };
+        let end = PairKey { item_id: rated_item_id, other_item_id: u64::MAX };
+        DEVIATIONS.with(|m| {
+            for (key, deviation) in m.borrow().range(start..=end) {
+                if ratings.items.contains(&key.other_item_id) {
+                    // the stored deviation is rated item - other item
+                    let total = totals.entry(key.other_item_id).or_insert((0.0, 0.0));
+                    total.0 += rating * deviation.count as f64 - deviation.sum;
+                    total.1 += deviation.count as f64;
+                }
+            }
+        });
+    }
+    totals.into_iter().map(|(item_id, (sum, count))| (item_id, sum / count)).collect()
+}
+
+// predicted rating of an item, none when no user rated it together with an item the user rated
+pub(crate) fn predict(ratings: &Ratings, user_id: u64, item_id: u64) -> Option<f64> {
+    predictions(ratings, user_id).get(&item_id).copied()
+}
+
+// top-N items by predicted rating
+pub(crate) fn recommend(ratings: &Ratings, user_id: u64, n: usize, exclude_rated: bool) -> Vec<ScoredItem> {
+    let rated = ratings.by_user.get(&user_id);
+    let mut scores: Vec<(u64, f64)> = predictions(ratings, user_id)
+        .into_iter()
+        .filter(|(item_id, _)| !(exclude_rated && rated.is_some_and(|rated| rated.contains_key(item_id))))
+        .collect();
+    scores.sort_by(|(a, a_score), (b, b_score)| b_score.total_cmp(a_score).then(a.cmp(b)));
+    scores.truncate(n);
+    scores.into_iter().map(|(item_id, score)| ScoredItem { item_id, score }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{User, USER_STORAGE};
+    use std::collections::BTreeSet;
+
+    fn preference(id: u64, user_id: u64, item_id: u64, rating: u64) -> UserPreference {
+        USER_STORAGE.with(|m| m.borrow_mut().insert(user_id, User { id: user_id, ..Default::default() }));
+        let preference = UserPreference { id, user_id, item_id, rating, ..Default::default() };
+        USER_PREFERENCE_STORAGE.with(|m| m.borrow_mut().insert(id, preference.clone()));
+        add_rating(&preference);
+        preference
+    }
+
+    #[test]
+    fn incremental_deviations_give_weighted_predictions() {
+        // the classic example: A rated items 1, 2 and 3, B rated 1 and 2, C rated 2 and 3
+        preference(1, 1, 1, 5);
+        preference(2, 1, 2, 3);
+        preference(3, 1, 3, 2);
+        preference(4, 2, 1, 3);
+        let removed = preference(5, 2, 2, 4);
+        preference(6, 3, 2, 2);
+        preference(7, 3, 3, 5);
+        let ratings = Ratings {
+            items: BTreeSet::from([1, 2, 3]),
+            by_user: HashMap::from([(4, HashMap::from([(1, 2.0), (2, 3.0)]))]),
+        };
+        // item 3 deviates from item 1 by -3 (1 co-rating) and from item 2 by +1 (2 co-ratings)
+        assert!((predict(&ratings, 4, 3).unwrap() - (-1.0 + 2.0 * 4.0) / 3.0).abs() < 1e-12);
+
+        USER_PREFERENCE_STORAGE.with(|m| m.borrow_mut().remove(&5));
+        remove_rating(&removed);
+        assert_eq!(DEVIATIONS.with(|m| m.borrow().get(&PairKey { item_id: 1, other_item_id: 2 })).unwrap().count, 1);
+        let top: Vec<u64> = recommend(&ratings, 4, 10, true).iter().map(|scored| scored.item_id).collect();
+        assert_eq!(top, vec![3]);
+
+        // a user in the trash no longer counts, until restored
+        USER_STORAGE.with(|m| m.borrow_mut().insert(3, User { id: 3, deleted_at: Some(1), ..Default::default() }));
+        remove_user(3);
+        assert_eq!(DEVIATIONS.with(|m| m.borrow().get(&PairKey { item_id: 2, other_item_id: 3 })).unwrap().count, 1);
+        USER_STORAGE.with(|m| m.borrow_mut().insert(3, User { id: 3, ..Default::default() }));
+        add_user(3);
+        assert_eq!(DEVIATIONS.with(|m| m.borrow().get(&PairKey { item_id: 2, other_item_id: 3 })).unwrap().count, 2);
+    }
+}
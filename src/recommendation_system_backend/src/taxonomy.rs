@@ -0,0 +1,484 @@
+use crate::search::{self, stem, tokenize};
+use crate::{
+    audit, certification, ensure_controller, user_is_active, Error, Item, Memory, ITEM_STORAGE, MEMORY_MANAGER,
+    RECOMMENDATION_SYSTEM_STORAGE, USER_PREFERENCE_STORAGE,
+};
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use std::collections::{HashMap, HashSet};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_CATEGORY_NAME_LENGTH: usize = 100;
+
+// a node of the category tree
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug, Default)]
+pub(crate) struct Category {
+    id: u64,
+    name: String,
+    // none for top-level categories
+    parent_id: Option<u64>,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+impl Storable for Category {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Category {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// ratings of the items of a category and its descendants in a recommendation system
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug, Default)]
+pub(crate) struct CategoryPopularity {
+    category_id: u64,
+    name: String,
+    item_count: u64,
+    rating_count: u64,
+    mean_rating: f64,
+}
+
+// mean rating a user gave to the items of a category and its descendants, relative to the user's mean rating
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug, Default)]
+pub(crate) struct CategoryAffinity {
+    category_id: u64,
+    name: String,
+    rating_count: u64,
+    affinity: f64,
+}
+
+thread_local! {
+    static CATEGORY_ID_COUNTER: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(40))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static CATEGORIES: RefCell<StableBTreeMap<u64, Category, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(39))))
+    );
+
+    // when the free-form categories of the items were moved into the taxonomy, 0 before
+    static MIGRATED_AT: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(42))), 0)
+            .expect("Cannot create the taxonomy migration marker")
+    );
+}
+
+// names that only differ in case, punctuation or plural map to the same key: "Books", "books" and "Book"
+pub(crate) fn name_key(name: &str) -> String {
+    let words: Vec<String> = tokenize(name).iter().map(|word| stem(word)).collect();
+    if words.is_empty() {
+        name.trim().to_lowercase()
+    } else {
+        words.join(" ")
+    }
+}
+
+fn all_categories() -> HashMap<u64, Category> {
+    CATEGORIES.with(|m| m.borrow().iter().collect())
+}
+
+pub(crate) fn category_by_id(id: u64) -> Result<Category, Error> {
+    CATEGORIES.with(|m| m.borrow().get(&id)).ok_or(Error::NotFound { msg: format!("category with id={} not found", id) })
+}
+
+// the category and its ancestors, closest first
+fn lineage(categories: &HashMap<u64, Category>, id: u64) -> Vec<u64> {
+    let mut lineage = vec![];
+    let mut current = Some(id);
+    while let Some(id) = current.filter(|id| !lineage.contains(id)) {
+        lineage.push(id);
+        current = categories.get(&id).and_then(|category| category.parent_id);
+    }
+    lineage
+}
+
+// the category and all its descendants
+fn subtree(categories: &HashMap<u64, Category>, id: u64) -> HashSet<u64> {
+    categories.keys().copied().filter(|&other| lineage(categories, other).contains(&id)).collect()
+}
+
+// the categories and all their descendants
+pub(crate) fn subtree_of(category_ids: &[u64]) -> HashSet<u64> {
+    let categories = all_categories();
+    category_ids.iter().flat_map(|&id| subtree(&categories, id)).collect()
+}
+
+fn insert_category(name: &str, parent_id: Option<u64>, now: u64) -> Category {
+    let id = CATEGORY_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment id counter");
+    let category = Category { id, name: name.trim().to_string(), parent_id, created_at: now, updated_at: None };
+    CATEGORIES.with(|m| m.borrow_mut().insert(id, category.clone()));
+    category
+}
+
+// category of the taxonomy matching a name, the oldest one when several do
+fn find_by_name(name: &str) -> Option<Category> {
+    let key = name_key(name);
+    CATEGORIES.with(|m| m.borrow().iter().map(|(_, category)| category).find(|category| name_key(&category.name) == key))
+}
+
+// id and name of an item's category: the given category or the one matching the name; items whose category
+// is not in the taxonomy keep their free-form name, only controllers add categories
+pub(crate) fn classify(category_id: Option<u64>, name: &str) -> (Option<u64>, String) {
+    match category_id.and_then(|id| CATEGORIES.with(|m| m.borrow().get(&id))).or_else(|| find_by_name(name)) {
+        Some(category) => (Some(category.id), category.name),
+        None => (None, name.trim().to_string()),
+    }
+}
+
+// free-form category names of items can be longer than category names
+fn truncated(name: &str) -> &str {
+    let name = name.trim();
+    let mut end = name.len().min(MAX_CATEGORY_NAME_LENGTH);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    &name[..end]
+}
+
+// move the free-form categories of the items created before the taxonomy into it, once; the categories are
+// created at the top level on behalf of `caller`
+pub(crate) fn migrate_items(caller: Principal, now: u64) {
+    if MIGRATED_AT.with(|cell| *cell.borrow().get()) != 0 {
+        return;
+    }
+    MIGRATED_AT.with(|cell| cell.borrow_mut().set(now.max(1))).expect("cannot record the taxonomy migration");
+
+    let items: Vec<Item> = ITEM_STORAGE
+        .with(|m| m.borrow().iter().map(|(_, item)| item).filter(|item| item.category_id.is_none()).collect());
+    for mut item in items {
+        let before = item.clone();
+        let category = match find_by_name(&item.category) {
+            Some(category) => category,
+            None if validate_name(truncated(&item.category)).is_ok() => {
+                let category = insert_category(truncated(&item.category), None, now);
+                audit::record_at(caller, "migrate_items", vec![category.id], None, Some(&category), now);
+                category
+            }
+            // blank categories stay uncategorized
+            None => continue,
+        };
+        item.category_id = Some(category.id);
+        item.category = category.name;
+        item.updated_at = Some(now);
+        ITEM_STORAGE.with(|m| m.borrow_mut().insert(item.id, item.clone()));
+        audit::record_at(caller, "migrate_items", vec![item.id], Some(&before), Some(&item), now);
+        if item.deleted_at.is_none() {
+            search::remove_item(&before);
+            search::index_item(&item);
+        }
+    }
+}
+
+fn validate_name(name: &str) -> Result<(), Error> {
+    if name.trim().is_empty() || name.trim().len() > MAX_CATEGORY_NAME_LENGTH {
+        return Err(Error::InvalidInput {
+            msg: format!("category names must have between 1 and {} bytes", MAX_CATEGORY_NAME_LENGTH),
+        });
+    }
+    Ok(())
+}
+
+// siblings cannot share a name
+fn check_unique(name: &str, parent_id: Option<u64>, id: Option<u64>) -> Result<(), Error> {
+    let key = name_key(name);
+    let taken = CATEGORIES.with(|m| {
+        m.borrow().iter().any(|(other_id, other)| {
+            Some(other_id) != id && other.parent_id == parent_id && name_key(&other.name) == key
+        })
+    });
+    if taken {
+        return Err(Error::AlreadyExists { msg: format!("category {} already exists", name.trim()) });
+    }
+    Ok(())
+}
+
+// function to get a category by id
+#[ic_cdk::query]
+pub(crate) fn get_category(id: u64) -> Result<Category, Error> {
+    category_by_id(id)
+}
+
+// function to get the whole taxonomy, parents before their children
+#[ic_cdk::query]
+pub(crate) fn get_categories() -> Vec<Category> {
+    let categories = all_categories();
+    let mut sorted: Vec<Category> = categories.values().cloned().collect();
+    sorted.sort_by_key(|category| (lineage(&categories, category.id).len(), category.id));
+    sorted
+}
+
+// function to add a category to the taxonomy, restricted to controllers
+#[ic_cdk::update]
+fn create_category(name: String, parent_id: Option<u64>) -> Result<Category, Error> {
+    ensure_controller("change the taxonomy")?;
+    validate_name(&name)?;
+    if let Some(parent_id) = parent_id {
+        category_by_id(parent_id)?;
+    }
+    check_unique(&name, parent_id, None)?;
+    let category = insert_category(&name, parent_id, time());
+    audit::record("create_category", vec![category.id], None, Some(&category));
+    Ok(category)
+}
+
+// function to rename a category, restricted to controllers; the items of the category follow
+#[ic_cdk::update]
+fn rename_category(id: u64, name: String) -> Result<Category, Error> {
+    ensure_controller("change the taxonomy")?;
+    validate_name(&name)?;
+    let mut category = category_by_id(id)?;
+    check_unique(&name, category.parent_id, Some(id))?;
+    let before = category.clone();
+    category.name = name.trim().to_string();
+    category.updated_at = Some(time());
+    CATEGORIES.with(|m| m.borrow_mut().insert(id, category.clone()));
+    audit::record("rename_category", vec![id], Some(&before), Some(&category));
+
+    let items: Vec<Item> = ITEM_STORAGE
+        .with(|m| m.borrow().iter().map(|(_, item)| item).filter(|item| item.category_id == Some(id)).collect());
+    for mut item in items {
+        let previous = item.clone();
+        item.category = category.name.clone();
+        item.updated_at = category.updated_at;
+        ITEM_STORAGE.with(|m| m.borrow_mut().insert(item.id, item.clone()));
+        if item.deleted_at.is_none() {
+            search::remove_item(&previous);
+            search::index_item(&item);
+            certification::certify_item(&item);
+        }
+        audit::record("rename_category", vec![item.id], Some(&previous), Some(&item));
+    }
+    Ok(category)
+}
+
+// function to move a category under another one, or to the top level, restricted to controllers
+#[ic_cdk::update]
+fn move_category(id: u64, parent_id: Option<u64>) -> Result<Category, Error> {
+    ensure_controller("change the taxonomy")?;
+    let mut category = category_by_id(id)?;
+    if let Some(parent_id) = parent_id {
+        category_by_id(parent_id)?;
+        if lineage(&all_categories(), parent_id).contains(&id) {
+            return Err(Error::InvalidInput { msg: "a category cannot move under itself or its descendants".to_string() });
+        }
+    }
+    check_unique(&category.name, parent_id, Some(id))?;
+    let before = category.clone();
+    category.parent_id = parent_id;
+    category.updated_at = Some(time());
+    CATEGORIES.with(|m| m.borrow_mut().insert(id, category.clone()));
+    audit::record("move_category", vec![id], Some(&before), Some(&category));
+    Ok(category)
+}
+
+// function to delete a category without subcategories nor items, restricted to controllers
+#[ic_cdk::update]
+fn delete_category(id: u64) -> Result<(), Error> {
+    ensure_controller("change the taxonomy")?;
+    let category = category_by_id(id)?;
+    let has_children = CATEGORIES.with(|m| m.borrow().iter().any(|(_, other)| other.parent_id == Some(id)));
+    let has_items = ITEM_STORAGE.with(|m| m.borrow().iter().any(|(_, item)| item.category_id == Some(id)));
+    if has_children || has_items {
+        return Err(Error::Conflict { msg: format!("category with id={} still has subcategories or items", id) });
+    }
+    CATEGORIES.with(|m| m.borrow_mut().remove(&id));
+    audit::record("delete_category", vec![id], Some(&category), None);
+    Ok(())
+}
+
+// function to get the active items of a category and of all its descendants
+#[ic_cdk::query]
+pub(crate) fn get_items_under_category(category_id: u64) -> Result<Vec<Item>, Error> {
+    category_by_id(category_id)?;
+    let subtree = subtree(&all_categories(), category_id);
+    Ok(ITEM_STORAGE.with(|m| {
+        m.borrow()
+            .iter()
+            .map(|(_, item)| item)
+            .filter(|item| item.deleted_at.is_none() && item.category_id.is_some_and(|id| subtree.contains(&id)))
+            .collect()
+    }))
+}
+
+// (item count, rating count, rating sum) of every category, each item counting for its category
+// and all the ancestors
+fn roll_up(
+    categories: &HashMap<u64, Category>,
+    items: &[(u64, Option<u64>)],
+    ratings: &[(u64, f64)],
+) -> HashMap<u64, (u64, u64, f64)> {
+    let item_categories: HashMap<u64, Vec<u64>> = items
+        .iter()
+        .filter_map(|&(item_id, category_id)| category_id.map(|category_id| (item_id, lineage(categories, category_id))))
+        .collect();
+    let mut totals: HashMap<u64, (u64, u64, f64)> = HashMap::new();
+    for category_id in item_categories.values().flatten() {
+        totals.entry(*category_id).or_default().0 += 1;
+    }
+    for (item_id, rating) in ratings {
+        for category_id in item_categories.get(item_id).into_iter().flatten() {
+            let total = totals.entry(*category_id).or_default();
+            total.1 += 1;
+            total.2 += rating;
+        }
+    }
+    totals
+}
+
+// function to rank the categories of a recommendation system by number of ratings, rolled up the tree
+#[ic_cdk::query]
+fn get_category_popularity(recommendation_system_id: u64) -> Result<Vec<CategoryPopularity>, Error> {
+    let recommendation_system = RECOMMENDATION_SYSTEM_STORAGE
+        .with(|service| service.borrow().get(&recommendation_system_id))
+        .filter(|record| record.deleted_at.is_none())
+        .ok_or(Error::NotFound {
+            msg: format!("recommendation system with id={} not found", recommendation_system_id),
+        })?;
+    let item_ids: HashSet<u64> = recommendation_system.items.iter().map(|item| item.id).collect();
+    let items: Vec<(u64, Option<u64>)> = ITEM_STORAGE.with(|m| {
+        let m = m.borrow();
+        item_ids
+            .iter()
+            .filter_map(|item_id| m.get(item_id))
+            .filter(|item| item.deleted_at.is_none())
+            .map(|item| (item.id, item.category_id))
+            .collect()
+    });
+    let ratings: Vec<(u64, f64)> = USER_PREFERENCE_STORAGE.with(|m| {
+        m.borrow()
+            .iter()
+            .map(|(_, preference)| preference)
+            .filter(|preference| preference.deleted_at.is_none() && item_ids.contains(&preference.item_id))
+            .map(|preference| (preference.item_id, preference.rating as f64))
+            .collect()
+    });
+
+    let categories = all_categories();
+    let mut popularity: Vec<CategoryPopularity> = roll_up(&categories, &items, &ratings)
+        .into_iter()
+        .filter_map(|(category_id, (item_count, rating_count, sum))| {
+            categories.get(&category_id).map(|category| CategoryPopularity {
+                category_id,
+                name: category.name.clone(),
+                item_count,
+                rating_count,
+                mean_rating: if rating_count > 0 { sum / rating_count as f64 } else { 0.0 },
+            })
+        })
+        .collect();
+    popularity.sort_by(|a, b| b.rating_count.cmp(&a.rating_count).then(a.category_id.cmp(&b.category_id)));
+    Ok(popularity)
+}
+
+// function to get a user's affinity to the categories they rated, rolled up the tree, strongest first
+#[ic_cdk::query]
+fn get_category_affinity(user_id: u64) -> Result<Vec<CategoryAffinity>, Error> {
+    if !user_is_active(user_id) {
+        return Err(Error::NotFound { msg: format!("user with id={} not found", user_id) });
+    }
+    let ratings: Vec<(u64, f64)> = USER_PREFERENCE_STORAGE.with(|m| {
+        m.borrow()
+            .iter()
+            .map(|(_, preference)| preference)
+            .filter(|preference| preference.deleted_at.is_none() && preference.user_id == user_id)
+            .map(|preference| (preference.item_id, preference.rating as f64))
+            .collect()
+    });
+    if ratings.is_empty() {
+        return Ok(vec![]);
+    }
+    let mean = ratings.iter().map(|(_, rating)| rating).sum::<f64>() / ratings.len() as f64;
+    let items: Vec<(u64, Option<u64>)> = ITEM_STORAGE.with(|m| {
+        let m = m.borrow();
+        ratings.iter().filter_map(|(item_id, _)| m.get(item_id)).map(|item| (item.id, item.category_id)).collect()
+    });
+
+    let categories = all_categories();
+    let mut affinities: Vec<CategoryAffinity> = roll_up(&categories, &items, &ratings)
+        .into_iter()
+        .filter(|(_, (_, rating_count, _))| *rating_count > 0)
+        .filter_map(|(category_id, (_, rating_count, sum))| {
+            categories.get(&category_id).map(|category| CategoryAffinity {
+                category_id,
+                name: category.name.clone(),
+                rating_count,
+                affinity: sum / rating_count as f64 / mean,
+            })
+        })
+        .collect();
+    affinities.sort_by(|a, b| b.affinity.total_cmp(&a.affinity).then(a.category_id.cmp(&b.category_id)));
+    Ok(affinities)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_matched_across_case_and_plural() {
+        let books = insert_category("Books", None, 0);
+        assert_eq!(find_by_name("books").unwrap().id, books.id);
+        assert_eq!(find_by_name(" Book ").unwrap().id, books.id);
+        assert!(find_by_name("Music").is_none());
+        assert!(check_unique("book", None, None).is_err());
+        assert!(check_unique("book", Some(books.id), None).is_ok());
+    }
+
+    #[test]
+    fn statistics_roll_up_to_ancestors() {
+        let books = insert_category("Books", None, 0);
+        let fiction = insert_category("Fiction", Some(books.id), 0);
+        let fantasy = insert_category("Fantasy", Some(fiction.id), 0);
+        let music = insert_category("Music", None, 0);
+        let categories = all_categories();
+        assert_eq!(subtree(&categories, fiction.id), [fiction.id, fantasy.id].into());
+        assert_eq!(lineage(&categories, fantasy.id), vec![fantasy.id, fiction.id, books.id]);
+
+        let items = [(1, Some(fantasy.id)), (2, Some(fiction.id)), (3, Some(music.id)), (4, None)];
+        let ratings = [(1, 5.0), (1, 3.0), (2, 4.0), (3, 2.0), (4, 1.0)];
+        let totals = roll_up(&categories, &items, &ratings);
+        assert_eq!(totals[&books.id], (2, 3, 12.0));
+        assert_eq!(totals[&fantasy.id], (1, 2, 8.0));
+        assert_eq!(totals[&music.id], (1, 1, 2.0));
+    }
+
+    #[test]
+    fn migration_moves_legacy_categories_once() {
+        for (id, category) in [(1, "Books"), (2, "book"), (3, " ")] {
+            let item = Item { id, name: "Dune".to_string(), category: category.to_string(), ..Default::default() };
+            ITEM_STORAGE.with(|m| m.borrow_mut().insert(id, item));
+        }
+
+        migrate_items(Principal::anonymous(), 1);
+        let books = find_by_name("books").unwrap();
+        assert_eq!(ITEM_STORAGE.with(|m| m.borrow().get(&1)).unwrap().category_id, Some(books.id));
+        assert_eq!(ITEM_STORAGE.with(|m| m.borrow().get(&2)).unwrap().category, "Books");
+        // blank categories stay uncategorized
+        assert_eq!(ITEM_STORAGE.with(|m| m.borrow().get(&3)).unwrap().category_id, None);
+        assert_eq!(all_categories().len(), 1);
+
+        // the migration runs once, later items only join categories created by controllers
+        let music = Item { id: 4, category: "Music".to_string(), ..Default::default() };
+        ITEM_STORAGE.with(|m| m.borrow_mut().insert(4, music));
+        migrate_items(Principal::anonymous(), 2);
+        assert_eq!(ITEM_STORAGE.with(|m| m.borrow().get(&4)).unwrap().category_id, None);
+        assert_eq!(classify(None, " music "), (None, "music".to_string()));
+        assert_eq!(classify(None, "book"), (Some(books.id), "Books".to_string()));
+    }
+}